@@ -1,6 +1,12 @@
 use byteorder::{WriteBytesExt, LittleEndian};
 use std::collections::HashMap;
+use std::rc::Rc;
+use diagnostic::Diagnostic;
+use expr::{self, EvalError, Expr};
+use image::{Image, ImageSection};
+use syntax;
 use tokenizer::*;
+use vm::MAX_MEMORY;
 
 #[derive(Debug, PartialEq)]
 pub enum CommandType {
@@ -9,27 +15,229 @@ pub enum CommandType {
     Unknown
 }
 
+/// The region of the program a command is placed in
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Section {
+    Text,
+    Data
+}
+
 #[derive(Debug)]
 pub struct Command {
     pub label: Token,
     pub cmd_type: CommandType,
     pub operand1: Token,
-    pub operand2: Token
+    pub operand2: Token,
+    pub section: Section,
+
+    /// The directive or instruction, which gives the file and line the command is on
+    pub token: Token,
+
+    /// Where the command is placed in memory
+    pub address: i32
+}
+
+/// Addresses the sections of a program are assembled for
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Origin {
+    pub text: u32,
+
+    /// Start of the data section, or right after the text section if not given
+    pub data: Option<u32>
+}
+
+/// Smallest offset which can be encoded in a register-indirect operand
+pub const MIN_INDIRECT_OFFSET: i32 = -(1 << 23);
+
+/// Largest offset which can be encoded in a register-indirect operand
+pub const MAX_INDIRECT_OFFSET: i32 = (1 << 23) - 1;
+
+/// Packs a base register and offset into a single operand
+/// The register occupies the low byte and the offset the remaining bits
+pub fn encode_indirect(register: &Register, offset: i32) -> i32 {
+    (offset << 8) | register.to_bytecode()
+}
+
+/// Unpacks an operand created by `encode_indirect` into a register code and offset
+pub fn decode_indirect(code: i32) -> (i32, i32) {
+    (code & 0xFF, code >> 8)
+}
+
+/// Most bytes a single `.space` can reserve, which is as much memory as a VM can have
+pub const MAX_SPACE: i32 = MAX_MEMORY as i32;
+
+/// Size of a single encoded instruction in bytes
+pub const INSTRUCTION_SIZE: i32 = 12;
+
+/// The values of the labels and constants in a program, used to work out integer operands
+#[derive(Default)]
+pub struct Symbols {
+    labels: HashMap<String, i32>,
+
+    /// The value of each constant with the address it is defined at, which `.` refers to
+    constants: HashMap<String, (Token, i32)>
+}
+
+impl Symbols {
+    /// Gathers the constants defined by `.equ` and `.set` alongside the address of each label
+    pub fn new(labels: HashMap<String, i32>, commands: &[Command]) -> Symbols {
+        let mut constants = HashMap::new();
+        for command in commands {
+            match (&command.cmd_type, &command.operand1.token_type) {
+                (CommandType::Directive(DirectiveType::Equ), TokenType::Label(name)) |
+                (CommandType::Directive(DirectiveType::Set), TokenType::Label(name)) => {
+                    constants.entry(name.to_string()).or_insert_with(|| (command.operand2.clone(), command.address));
+                },
+                _ => {}
+            }
+        }
+        Symbols { labels, constants }
+    }
+
+    /// Works out the value of an operand assembled at `here`
+    /// Without `labels_placed`, only values which do not depend on where anything is placed can be worked out
+    fn evaluate(&self, operand: &Token, here: i32, labels_placed: bool, stack: &mut Vec<String>) -> Result<i32, Diagnostic> {
+        let here = if labels_placed { Some(here) } else { None };
+        self.value(operand, here, labels_placed, stack).map_err(|err| self.diagnostic(err, operand))
+    }
+
+    /// Describes why the value of `operand` could not be worked out
+    fn diagnostic(&self, err: EvalError, operand: &Token) -> Diagnostic {
+        match err {
+            EvalError::Undefined(name) => {
+                let names = self.labels.keys().chain(self.constants.keys()).map(|n| expr::source_name(n));
+                syntax::undefined_label(&name, operand, names)
+            },
+            err => Diagnostic::error(err.to_string(), operand)
+        }
+    }
+
+    /// `stack` holds the constants being worked out, so one defined in terms of itself is caught
+    fn value(&self, operand: &Token, here: Option<i32>, labels_placed: bool, stack: &mut Vec<String>) -> Result<i32, EvalError> {
+        match &operand.token_type {
+            TokenType::Character(c) => Ok(*c as i32),
+            TokenType::Integer(val) => Ok(*val),
+            TokenType::Label(name) => self.lookup(name, labels_placed, stack),
+            TokenType::Expression(expr) | TokenType::Indirect(_, expr) =>
+                expr.evaluate(here, &mut |name| self.lookup(name, labels_placed, stack)),
+            _ => Ok(0)
+        }
+    }
+
+    fn lookup(&self, name: &str, labels_placed: bool, stack: &mut Vec<String>) -> Result<i32, EvalError> {
+        if let Some((value, address)) = self.constants.get(name) {
+            if stack.iter().any(|constant| constant == name) {
+                return Err(EvalError::Cycle(name.to_string()));
+            }
+            stack.push(name.to_string());
+            let here = if labels_placed { Some(*address) } else { None };
+            let result = self.value(value, here, labels_placed, stack);
+            stack.pop();
+
+            // Problems in the definition itself are reported there
+            return result.map_err(|err| match err {
+                EvalError::Cycle(_) | EvalError::Label(_) | EvalError::NoAddress => err,
+                _ => EvalError::BadConstant(name.to_string())
+            });
+        }
+        match self.labels.get(name) {
+            Some(address) if labels_placed => Ok(*address),
+            Some(_) => Err(EvalError::Label(name.to_string())),
+            None => Err(EvalError::Undefined(name.to_string()))
+        }
+    }
+}
+
+/// Renames each constant an operand uses to the version of it in `versions`, leaving other names alone
+fn rename_symbols(operand: &mut Token, versions: &HashMap<String, usize>) {
+    let rename = |name: &str| versions.get(name).map(|version| expr::versioned_name(name, *version));
+    operand.token_type = match &operand.token_type {
+        TokenType::Label(name) => match rename(name) {
+            Some(name) => TokenType::Label(name),
+            None => return
+        },
+        TokenType::Expression(value) => TokenType::Expression(value.replace_symbols(&|name| rename(name).map(Expr::Symbol))),
+        TokenType::Indirect(register, offset) =>
+            TokenType::Indirect(register.clone(), offset.replace_symbols(&|name| rename(name).map(Expr::Symbol))),
+        _ => return
+    };
+}
+
+/// Encodes a single instruction operand, working out the value of labels, constants and expressions
+fn operand_to_bytecode(cmd_type: &CommandType, operand: &Token, symbols: &Symbols, here: i32) -> Result<i32, Diagnostic> {
+    match &operand.token_type {
+        TokenType::Register(reg) => Ok(reg.to_bytecode()),
+        TokenType::Indirect(reg, _) => Ok(encode_indirect(reg, checked_value(cmd_type, operand, symbols, here, true)?)),
+        _ => symbols.evaluate(operand, here, true, &mut vec![])
+    }
+}
+
+/// The lowest and highest value an operand can hold, along with how to describe them
+/// Returns `None` if the operand can hold any integer
+fn limits(cmd_type: &CommandType, operand: &Token) -> Option<(i32, i32, String)> {
+    if let TokenType::Indirect(..) = operand.token_type {
+        return Some((
+            MIN_INDIRECT_OFFSET,
+            MAX_INDIRECT_OFFSET,
+            format!("an offset between {} and {}", MIN_INDIRECT_OFFSET, MAX_INDIRECT_OFFSET)
+        ));
+    }
+    let (min, max) = match cmd_type {
+        CommandType::Directive(DirectiveType::Byte) => (-128, 255),
+        CommandType::Directive(DirectiveType::Half) => (-32_768, 65_535),
+        CommandType::Directive(DirectiveType::Space) => (0, MAX_SPACE),
+        CommandType::Directive(DirectiveType::Align) => return Some((1, i32::MAX, "a positive integer".to_string())),
+        _ => return None
+    };
+    Some((min, max, format!("an integer between {} and {}", min, max)))
+}
+
+/// Checks that the value of an operand is one it can hold
+fn check_limits(cmd_type: &CommandType, operand: &Token, value: i32) -> Result<i32, Diagnostic> {
+    match limits(cmd_type, operand) {
+        Some((min, max, expected)) if !(min..=max).contains(&value) => {
+            // Only the offset of a register address is worked out, so it is shown without the register
+            let written = match &operand.token_type {
+                TokenType::Indirect(_, offset) => offset.to_string(),
+                other => other.to_string()
+            };
+            Err(Diagnostic::error(format!("expected {} but `{}` is {}", expected, written, value), operand))
+        },
+        _ => Ok(value)
+    }
+}
+
+/// Works out the value of an operand, checking it is one the operand can hold
+fn checked_value(cmd_type: &CommandType, operand: &Token, symbols: &Symbols, here: i32, labels_placed: bool) -> Result<i32, Diagnostic> {
+    let value = symbols.evaluate(operand, here, labels_placed, &mut vec![])?;
+    check_limits(cmd_type, operand, value)
 }
 
 pub enum ByteCodeData {
     ByteDirective(u8),
-    WordDirective(u16),
+    HalfDirective(u16),
+    WordDirective(i32),
+    BytesDirective(Vec<u8>),
+    EntryPoint(i32),
     Instruction([i32; 3])
 }
 
+impl Default for Command {
+    fn default() -> Command {
+        Command::new()
+    }
+}
+
 impl Command {
     pub fn new() -> Command {
         Command {
             label: Token::new_none(),
             cmd_type: CommandType::Unknown,
             operand1: Token::new_none(),
-            operand2: Token::new_none()
+            operand2: Token::new_none(),
+            section: Section::Text,
+            token: Token::new_none(),
+            address: 0
         }
     }
 
@@ -38,72 +246,130 @@ impl Command {
             self.operand1 = operand;
         } else {
             self.operand2 = operand;
-            let mut new_type = CommandType::Unknown;
-            match &self.cmd_type {
-                &CommandType::Instruction(InstructionType::Add) => {
-                    match &self.operand2.token_type {
-                        &TokenType::Integer(_) => {
-                            new_type = CommandType::Instruction(InstructionType::AddImmediate);
-                        },
-                        _ => {}
-                    };
-                },
-                _ => {}
+            let new_type = match (&self.cmd_type, &self.operand2.token_type) {
+                (CommandType::Instruction(InstructionType::Add), TokenType::Register(_)) => None,
+                (CommandType::Instruction(InstructionType::Add), _) =>
+                    Some(InstructionType::AddImmediate),
+                (CommandType::Instruction(InstructionType::StoreWord), TokenType::Indirect(..)) =>
+                    Some(InstructionType::StoreWordIndirect),
+                (CommandType::Instruction(InstructionType::LoadWord), TokenType::Indirect(..)) =>
+                    Some(InstructionType::LoadWordIndirect),
+                (CommandType::Instruction(InstructionType::StoreByte), TokenType::Indirect(..)) =>
+                    Some(InstructionType::StoreByteIndirect),
+                (CommandType::Instruction(InstructionType::LoadByte), TokenType::Indirect(..)) =>
+                    Some(InstructionType::LoadByteIndirect),
+                (CommandType::Instruction(InstructionType::StoreHalf), TokenType::Indirect(..)) =>
+                    Some(InstructionType::StoreHalfIndirect),
+                (CommandType::Instruction(InstructionType::LoadHalf), TokenType::Indirect(..)) =>
+                    Some(InstructionType::LoadHalfIndirect),
+                (CommandType::Instruction(InstructionType::LoadHalfSigned), TokenType::Indirect(..)) =>
+                    Some(InstructionType::LoadHalfSignedIndirect),
+                (CommandType::Instruction(InstructionType::LoadByteSigned), TokenType::Indirect(..)) =>
+                    Some(InstructionType::LoadByteSignedIndirect),
+                _ => None
             };
 
-            if new_type != CommandType::Unknown {
-                self.cmd_type = new_type;
+            if let Some(instruction) = new_type {
+                self.cmd_type = CommandType::Instruction(instruction);
             }
         }
     }
 
+    /// Number of bytes the command occupies once assembled at `offset`
+    pub fn size(&self, offset: i32) -> i32 {
+        match (&self.cmd_type, &self.operand1.token_type) {
+            (CommandType::Directive(DirectiveType::Byte), _) => 1,
+            (CommandType::Directive(DirectiveType::Half), _) => 2,
+            (CommandType::Directive(DirectiveType::Word), _) => 4,
+            (CommandType::Directive(DirectiveType::Ascii), TokenType::String(string)) =>
+                string.len() as i32,
+            (CommandType::Directive(DirectiveType::Asciz), TokenType::String(string)) =>
+                string.len() as i32 + 1,
+            (CommandType::Directive(DirectiveType::Space), TokenType::Integer(count)) if *count > 0 => *count,
+            (CommandType::Directive(DirectiveType::Align), TokenType::Integer(alignment)) if *alignment > 0 =>
+                (alignment - offset % alignment) % alignment,
+            (CommandType::Instruction(_), _) => INSTRUCTION_SIZE,
+            _ => 0
+        }
+    }
+
     pub fn is_complete(&self) -> bool {
         match &self.cmd_type {
-            &CommandType::Directive(_) => self.is_directive_complete(),
-            &CommandType::Instruction(ref instruction) =>
-                self.is_instruction_complete(&instruction),
+            CommandType::Directive(_) => self.is_directive_complete(),
+            CommandType::Instruction(instruction) =>
+                self.is_instruction_complete(instruction),
             _ => false
         }
     }
 
-    pub fn to_bytecode(&self, label_table: &HashMap<String, i32>) -> ByteCodeData {
-        match &self.cmd_type {
-            &CommandType::Directive(_) => match &self.operand1.token_type {
-                &TokenType::Character(c) => ByteCodeData::ByteDirective(c as u8),
-                &TokenType::Integer(val) => ByteCodeData::WordDirective(val as u16),
-                _ => unreachable!()
+    /// Converts the command to bytecode assuming it is placed at `offset`
+    pub fn to_bytecode(&self, symbols: &Symbols, offset: i32) -> Result<ByteCodeData, Diagnostic> {
+        let code = match &self.cmd_type {
+            CommandType::Directive(directive) => match (directive, &self.operand1.token_type) {
+                (DirectiveType::Byte, TokenType::Character(c)) => ByteCodeData::ByteDirective(*c as u8),
+                (DirectiveType::Byte, _) =>
+                    ByteCodeData::ByteDirective(checked_value(&self.cmd_type, &self.operand1, symbols, offset, true)? as u8),
+                (DirectiveType::Half, _) =>
+                    ByteCodeData::HalfDirective(checked_value(&self.cmd_type, &self.operand1, symbols, offset, true)? as u16),
+                (DirectiveType::Word, _) =>
+                    ByteCodeData::WordDirective(symbols.evaluate(&self.operand1, offset, true, &mut vec![])?),
+                (DirectiveType::Ascii, TokenType::String(string)) =>
+                    ByteCodeData::BytesDirective(string.clone()),
+                (DirectiveType::Asciz, TokenType::String(string)) => {
+                    let mut bytes = string.clone();
+                    bytes.push(0);
+                    ByteCodeData::BytesDirective(bytes)
+                },
+
+                // Sizes are needed to lay out the program, so they are worked out beforehand
+                // Anything which could not be is reported here
+                (DirectiveType::Space, _) |
+                (DirectiveType::Align, _) => {
+                    checked_value(&self.cmd_type, &self.operand1, symbols, offset, false)?;
+                    ByteCodeData::BytesDirective(vec![0; self.size(offset) as usize])
+                },
+                (DirectiveType::Entry, TokenType::Label(_)) =>
+                    ByteCodeData::EntryPoint(operand_to_bytecode(&self.cmd_type, &self.operand1, symbols, offset)?),
+
+                // A constant takes up no space, but its value is checked where it is defined
+                (DirectiveType::Equ, TokenType::Label(name)) |
+                (DirectiveType::Set, TokenType::Label(name)) => {
+                    symbols.evaluate(&self.operand2, offset, true, &mut vec![name.to_string()])?;
+                    ByteCodeData::BytesDirective(vec![])
+                },
+                _ => return Err(self.cannot_assemble())
             },
-            &CommandType::Instruction(ref instruction) => {
+            CommandType::Instruction(instruction) => {
                 let mut result = [0, 0, 0];
                 result[0] = instruction.to_bytecode();
-                result[1] = match &self.operand1.token_type {
-                    &TokenType::Character(c) => (c as u8) as i32,
-                    &TokenType::Integer(val) => val,
-                    &TokenType::Register(ref reg) => reg.to_bytecode(),
-                    &TokenType::Label(ref label) => match label_table.get(label) {
-                        Some(offset) => offset.clone(),
-
-                        // TODO: Better error handling
-                        None => panic!("Unknown label")
-                    },
-                    _ => 0,
-                };
-                result[2] = match &self.operand2.token_type {
-                    &TokenType::Character(c) => (c as u8) as i32,
-                    &TokenType::Integer(val) => val,
-                    &TokenType::Register(ref reg) => reg.to_bytecode(),
-                    &TokenType::Label(ref label) => match label_table.get(label) {
-                        Some(offset) => offset.clone(),
-
-                        // TODO: Better error handling
-                        None => panic!("Unknown label")
-                    },
-                    _ => 0,
-                };
+                result[1] = operand_to_bytecode(&self.cmd_type, &self.operand1, symbols, offset)?;
+                result[2] = operand_to_bytecode(&self.cmd_type, &self.operand2, symbols, offset)?;
                 ByteCodeData::Instruction(result)
             },
-            &CommandType::Unknown => unreachable!()
+            CommandType::Unknown => return Err(self.cannot_assemble())
+        };
+        Ok(code)
+    }
+
+    fn cannot_assemble(&self) -> Diagnostic {
+        Diagnostic::error(format!("`{}` cannot be assembled with these operands", self.token.token_type), &self.token)
+    }
+
+    /// Checks the command has the operands it needs, of the kinds it needs
+    /// Commands from source which `syntax::verify` has not checked may be missing some or have the wrong ones
+    fn verify(&self) -> Result<(), Diagnostic> {
+        let operands: Vec<Token> = [&self.operand1, &self.operand2].iter()
+            .filter(|operand| !operand.is_none())
+            .map(|operand| (*operand).clone())
+            .collect();
+        if self.cmd_type == CommandType::Unknown {
+            let stray = operands.first().unwrap_or(&self.label);
+            return Err(Diagnostic::error(
+                format!("expected a directive or instruction but saw \"{}\"", stray.token_type),
+                stray
+            ));
         }
+        syntax::verify_operands(&self.token, &operands)
     }
 
     pub fn from_bytecode(code: &[i32; 3]) -> Command {
@@ -112,7 +378,7 @@ impl Command {
             command.cmd_type = CommandType::Directive(directive);
         } else if let Some(instruction) = InstructionType::from_bytecode(code[0]) {
             use tokenizer::InstructionType::*;
-            match *&instruction {
+            match instruction {
                 Add |
                 And |
                 Divide |
@@ -123,14 +389,10 @@ impl Command {
                 Subtract => {
                     if let Some(register) = Register::from_bytecode(code[1]) {
                         command.operand1 = Token::new(TokenType::Register(register), 0);
-                    } else {
-                        unreachable!();
                     }
 
                     if let Some(register) = Register::from_bytecode(code[2]) {
                         command.operand2 = Token::new(TokenType::Register(register), 0);
-                    } else {
-                        unreachable!();
                     }
                 },
 
@@ -142,30 +404,53 @@ impl Command {
                 LessThanZeroJump |
                 LoadAddress |
                 LoadByte |
+                LoadByteSigned |
+                LoadHalf |
+                LoadHalfSigned |
                 LoadWord |
                 NonZeroJump |
                 StoreByte |
+                StoreHalf |
                 StoreWord => {
                     if let Some(register) = Register::from_bytecode(code[1]) {
                         command.operand1 = Token::new(TokenType::Register(register), 0);
-                    } else {
-                        unreachable!();
                     }
 
                     command.operand2 = Token::new(TokenType::Integer(code[2]), 0);
                 },
 
-                // Only takes an address (label)
+                // Takes a register and a base register plus an offset
+                LoadByteIndirect |
+                LoadByteSignedIndirect |
+                LoadHalfIndirect |
+                LoadHalfSignedIndirect |
+                LoadWordIndirect |
+                StoreByteIndirect |
+                StoreHalfIndirect |
+                StoreWordIndirect => {
+                    if let Some(register) = Register::from_bytecode(code[1]) {
+                        command.operand1 = Token::new(TokenType::Register(register), 0);
+                    }
+
+                    let (base, offset) = decode_indirect(code[2]);
+                    if let Some(register) = Register::from_bytecode(base) {
+                        command.operand2 = Token::new(TokenType::Indirect(register, Expr::Integer(offset)), 0);
+                    }
+                },
+
+                // Only takes an address (label) or an immediate value
+                Call |
+                Enter |
                 Jump => {
                     command.operand1 = Token::new(TokenType::Integer(code[1]), 0);
                 },
 
                 // Only takes a register
-                JumpRelative => {
+                JumpRelative |
+                Pop |
+                Push => {
                     if let Some(register) = Register::from_bytecode(code[1]) {
                         command.operand1 = Token::new(TokenType::Register(register), 0);
-                    } else {
-                        unreachable!();
                     }
                 },
 
@@ -175,8 +460,10 @@ impl Command {
                 End |
                 InputASCII |
                 InputInteger |
+                Leave |
                 OutputASCII |
-                OutputInteger => {}
+                OutputInteger |
+                Return => {}
             };
             command.cmd_type = CommandType::Instruction(instruction);
         }
@@ -184,7 +471,11 @@ impl Command {
     }
 
     fn is_directive_complete(&self) -> bool {
-        !self.operand1.is_none()
+        match self.cmd_type {
+            CommandType::Directive(DirectiveType::Equ) |
+            CommandType::Directive(DirectiveType::Set) => !self.operand2.is_none(),
+            _ => !self.operand1.is_none()
+        }
     }
 
     fn is_instruction_complete(&self, instruction: &InstructionType) -> bool {
@@ -196,10 +487,16 @@ impl Command {
             OutputASCII |
             InputASCII |
             ConvertASCIIToInteger |
-            ConvertIntegerToASCII => true,
+            ConvertIntegerToASCII |
+            Return |
+            Leave => true,
 
             Jump |
-            JumpRelative => !self.operand1.is_none(),
+            JumpRelative |
+            Push |
+            Pop |
+            Call |
+            Enter => !self.operand1.is_none(),
 
             NonZeroJump |
             GreaterThanZeroJump |
@@ -211,6 +508,18 @@ impl Command {
             LoadWord |
             StoreByte |
             LoadByte |
+            StoreWordIndirect |
+            LoadWordIndirect |
+            StoreByteIndirect |
+            LoadByteIndirect |
+            StoreHalf |
+            LoadHalf |
+            LoadHalfSigned |
+            LoadByteSigned |
+            StoreHalfIndirect |
+            LoadHalfIndirect |
+            LoadHalfSignedIndirect |
+            LoadByteSignedIndirect |
             Add |
             AddImmediate |
             Subtract |
@@ -227,75 +536,537 @@ impl Command {
 pub struct Assembler;
 
 impl Assembler {
-    pub fn to_commands(tokens: Tokenizer) -> (HashMap<String, i32>, Vec<Command>) {
-        let mut label_addresses = HashMap::new();
-        let mut offset = 0;
+    /// Parses commands for a program whose text section starts at address 0 and is followed by its data
+    pub fn to_commands<I: IntoIterator<Item = Token>>(tokens: I) -> (HashMap<String, i32>, Vec<Command>) {
+        Assembler::to_commands_at(tokens, Origin::default())
+    }
+
+    /// Parses commands, placing each section at the address given by `origin`
+    pub fn to_commands_at<I: IntoIterator<Item = Token>>(tokens: I, origin: Origin) -> (HashMap<String, i32>, Vec<Command>) {
+        // Place the whole text section before the data section
+        let (mut commands, data): (Vec<Command>, Vec<Command>) = Assembler::parse(tokens)
+            .into_iter()
+            .partition(|command| command.section == Section::Text);
+        commands.extend(data);
+
+        Assembler::resolve_sizes(&mut commands);
+        Assembler::place(&mut commands, origin);
+        let label_addresses = Assembler::layout(&commands);
+        (label_addresses, commands)
+    }
+
+    /// Parses commands in the order they are written, without placing them
+    fn parse<I: IntoIterator<Item = Token>>(tokens: I) -> Vec<Command> {
         let mut commands: Vec<Command> = Vec::new();
         let mut command = Command::new();
+        let mut section = Section::Text;
         for token in tokens {
-            if command.is_complete() {
-                offset += 12;
+            // A command missing operands, or operands with no command, is kept so `to_image` can report it
+            let starts_command = matches!(token.token_type, TokenType::Instruction(_) | TokenType::Directive(_));
+            let is_partial = command.cmd_type != CommandType::Unknown || !command.operand1.is_none();
+            if command.is_complete() || (starts_command && is_partial) {
                 commands.push(command);
                 command = Command::new();
+                command.section = section;
             }
 
             use tokenizer::TokenType::*;
             match token.token_type {
-                Instruction(instruction) => {
-                    command.cmd_type = CommandType::Instruction(instruction);
+                // Section directives only change where the following commands are placed
+                // Any label before them carries over to the next command
+                Directive(DirectiveType::Text) => {
+                    section = Section::Text;
+                    command.section = section;
                 },
-                Directive(directive) => {
-                    command.cmd_type = CommandType::Directive(directive);
+                Directive(DirectiveType::Data) => {
+                    section = Section::Data;
+                    command.section = section;
                 },
-                Label(_) => {
-                    if command.cmd_type == CommandType::Unknown {
-                        if let Label(ref label) = token.token_type {
-                            label_addresses.insert(label.to_string(), offset);
-                        }
-                        command.label = token;
-                    } else {
-                        command.add_operand(token);
-                    }
+                Instruction(ref instruction) => {
+                    command.cmd_type = CommandType::Instruction(instruction.clone());
+                    command.token = token;
+                },
+                Directive(ref directive) => {
+                    command.cmd_type = CommandType::Directive(directive.clone());
+                    command.token = token;
+                },
+                Label(_) if command.cmd_type == CommandType::Unknown => {
+                    command.label = token;
                 },
                 _ => {
                     command.add_operand(token);
                 }
             };
         }
-        if command.is_complete() {
+        if command.cmd_type != CommandType::Unknown || !command.operand1.is_none() || !command.label.is_none() {
             commands.push(command);
         }
-        (label_addresses, commands)
+        Assembler::version_constants(&mut commands);
+        commands
+    }
+
+    /// Works out every value which does not depend on where the program is placed, reporting any which are wrong,
+    /// such as a constant divided by zero or a `.byte` too large to fit
+    /// Nothing is laid out, so a program can be checked this way without assembling it
+    pub fn check_values<I: IntoIterator<Item = Token>>(tokens: I) -> Vec<Diagnostic> {
+        let commands = Assembler::parse(tokens);
+        let labels = commands.iter()
+            .filter_map(|command| match &command.label.token_type {
+                TokenType::Label(name) => Some((name.to_string(), 0)),
+                _ => None
+            })
+            .collect();
+        let symbols = Symbols::new(labels, &commands);
+
+        let mut diagnostics = vec![];
+        for command in &commands {
+            // The name a constant is given is a definition rather than a value
+            let (operands, stack) = match (&command.cmd_type, &command.operand1.token_type) {
+                (CommandType::Directive(DirectiveType::Equ), TokenType::Label(name)) |
+                (CommandType::Directive(DirectiveType::Set), TokenType::Label(name)) =>
+                    (vec![&command.operand2], vec![name.to_string()]),
+                _ => (vec![&command.operand1, &command.operand2], vec![])
+            };
+            for operand in operands.into_iter().filter(|operand| !operand.is_none()) {
+                let result = match symbols.value(operand, None, false, &mut stack.clone()) {
+                    Ok(value) => check_limits(&command.cmd_type, operand, value),
+
+                    // Left until labels have addresses, or already reported as undefined
+                    Err(EvalError::Label(_)) | Err(EvalError::NoAddress) | Err(EvalError::Undefined(_)) => continue,
+                    Err(err) => Err(symbols.diagnostic(err, operand))
+                };
+                if let Err(diagnostic) = result {
+                    diagnostics.push(diagnostic);
+                }
+            }
+        }
+        diagnostics
     }
 
-    pub fn to_bytecode(label_table: HashMap<String, i32>, commands: Vec<Command>) -> (usize, Vec<u8>) {
-        let mut bytecode = vec![];
-        let mut offset = 0;
-        let mut start: usize = 0;
-        let mut found_start = false;
+    /// Gives the value from each `.set` its own name, so that every use of a constant refers to the value it was
+    /// most recently set to before that use
+    fn version_constants(commands: &mut [Command]) {
+        let mut versions: HashMap<String, usize> = HashMap::new();
         for command in commands {
-            let code = command.to_bytecode(&label_table);
+            let is_set = command.cmd_type == CommandType::Directive(DirectiveType::Set);
+
+            // The new value may be worked out from the one before, as in `.set COUNT COUNT+1`
+            rename_symbols(&mut command.operand2, &versions);
+            if !is_set {
+                rename_symbols(&mut command.operand1, &versions);
+            } else if let TokenType::Label(name) = &command.operand1.token_type {
+                let version = versions.entry(name.to_string()).or_insert(0);
+                *version += 1;
+                command.operand1.token_type = TokenType::Label(expr::versioned_name(name, *version));
+            }
+        }
+    }
+
+    /// Works out the operands of `.space` and `.align` which are not plain integers, so commands can be placed
+    /// These may use constants but not labels, since no label has an address yet
+    fn resolve_sizes(commands: &mut [Command]) {
+        let symbols = Symbols::new(Assembler::layout(commands), commands);
+        let mut sizes = vec![];
+        for (index, command) in commands.iter().enumerate() {
+            match command.cmd_type {
+                CommandType::Directive(DirectiveType::Space) |
+                CommandType::Directive(DirectiveType::Align) => {},
+                _ => continue
+            };
+            // Operands of the wrong kind are left as they are to be reported when assembled
+            match command.operand1.token_type {
+                TokenType::Integer(_) => continue,
+                ref operand if !syntax::is_integer(operand) => continue,
+                _ => {}
+            };
+
+            // So is anything which cannot be worked out
+            if let Ok(value) = checked_value(&command.cmd_type, &command.operand1, &symbols, 0, false) {
+                sizes.push((index, value));
+            }
+        }
+        for (index, value) in sizes {
+            commands[index].operand1.token_type = TokenType::Integer(value);
+        }
+    }
+
+    /// Gives each command its address
+    /// Commands in a section are placed one after another using their assembled size
+    fn place(commands: &mut [Command], origin: Origin) {
+        let mut address = origin.text as i32;
+        let mut section = Section::Text;
+        for command in commands {
+            if command.section != section {
+                section = command.section;
+                if let Some(data) = origin.data {
+                    address = data as i32;
+                }
+            }
+            command.address = address;
+
+            // A command which would run past the end of the address space is reported when assembled
+            if let Some(next) = address.checked_add(command.size(address)) {
+                address = next;
+            }
+        }
+    }
+
+    /// Assigns each label the address of the command it is attached to
+    /// A label defined more than once keeps its first address
+    pub fn layout(commands: &[Command]) -> HashMap<String, i32> {
+        let mut label_addresses = HashMap::new();
+        for command in commands {
+            if let TokenType::Label(ref label) = command.label.token_type {
+                label_addresses.entry(label.to_string()).or_insert(command.address);
+            }
+        }
+        label_addresses
+    }
+
+    /// Maps the address of each instruction to the file and line of source it came from
+    pub fn source_lines(commands: &[Command]) -> HashMap<i32, (Rc<str>, u32)> {
+        commands.iter()
+            .filter(|command| matches!(command.cmd_type, CommandType::Instruction(_)))
+            .map(|command| (command.address, (command.token.file.clone(), command.token.line_number)))
+            .collect()
+    }
+
+    /// Assembles a list of commands into an image which can be saved and loaded later
+    /// Execution starts at the label given to `.entry` if there is one,
+    /// otherwise at the first instruction
+    ///
+    /// Every command is checked for missing or wrong operands, so source need not go through `syntax::verify` first,
+    /// though its diagnostics are more thorough
+    pub fn to_image(label_table: HashMap<String, i32>, commands: Vec<Command>) -> Result<Image, Vec<Diagnostic>> {
+        // The text section always exists, even when it is empty
+        let mut sections = vec![ImageSection {
+            kind: Section::Text,
+            address: commands.first().filter(|command| command.section == Section::Text).map_or(0, |command| command.address) as u32,
+            data: vec![]
+        }];
+        let symbols = Symbols::new(label_table, &commands);
+        let mut diagnostics = vec![];
+        let mut start = None;
+        let mut entry_point = None;
+        for command in &commands {
+            if command.section != sections.last().unwrap().kind {
+                sections.push(ImageSection { kind: command.section, address: command.address as u32, data: vec![] });
+            }
+            let bytecode = &mut sections.last_mut().unwrap().data;
+            if command.address.checked_add(command.size(command.address)).is_none() {
+                diagnostics.push(Diagnostic::error(
+                    format!("`{}` runs past the end of the address space", command.token.token_type),
+                    &command.token
+                ));
+                continue;
+            }
+
+            if let Err(diagnostic) = command.verify() {
+                diagnostics.push(diagnostic);
+                continue;
+            }
+            let code = match command.to_bytecode(&symbols, command.address) {
+                Ok(code) => code,
+                Err(diagnostic) => {
+                    diagnostics.push(diagnostic);
+                    ByteCodeData::BytesDirective(vec![0; command.size(command.address) as usize])
+                }
+            };
             match code {
                 ByteCodeData::ByteDirective(data) => {
                     bytecode.write_u8(data).unwrap();
-                    offset += 1;
                 },
-                ByteCodeData::WordDirective(data) => {
+                ByteCodeData::HalfDirective(data) => {
                     bytecode.write_u16::<LittleEndian>(data).unwrap();
-                    offset += 2;
+                },
+                ByteCodeData::WordDirective(data) => {
+                    bytecode.write_i32::<LittleEndian>(data).unwrap();
+                },
+                ByteCodeData::BytesDirective(data) => {
+                    bytecode.extend(data);
+                },
+                ByteCodeData::EntryPoint(address) => {
+                    entry_point = Some(address as u32);
                 },
                 ByteCodeData::Instruction(data) => {
-                    if !found_start {
-                        start = offset;
-                        found_start = true;
-                    }
+                    start = start.or(Some(command.address as u32));
                     bytecode.write_i32::<LittleEndian>(data[0]).unwrap();
                     bytecode.write_i32::<LittleEndian>(data[1]).unwrap();
                     bytecode.write_i32::<LittleEndian>(data[2]).unwrap();
-                    offset += 3;
                 }
             };
         }
-        (start, bytecode)
+        if !diagnostics.is_empty() {
+            return Err(diagnostics);
+        }
+        sections.retain(|section| section.kind == Section::Text || !section.data.is_empty());
+
+        let entry_point = entry_point.or(start).unwrap_or(sections[0].address);
+        Ok(Image::new(entry_point, sections, &symbols.labels))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::{ReadBytesExt, LittleEndian};
+    use std::io::Cursor;
+    use super::*;
+
+    fn assemble(path: &str) -> (HashMap<String, i32>, usize, Vec<u8>) {
+        let (label_table, commands) = Assembler::to_commands(Tokenizer::new(path));
+        let image = Assembler::to_image(label_table.clone(), commands).unwrap();
+        (label_table, image.entry_point as usize, image.to_memory())
+    }
+
+    fn read_i32(bytecode: &[u8], address: i32) -> i32 {
+        Cursor::new(&bytecode[address as usize..]).read_i32::<LittleEndian>().unwrap()
+    }
+
+    #[test]
+    fn labels_use_real_directive_sizes() {
+        let (label_table, _, _) = assemble("test_files/30-mixed-layout.asm");
+        assert_eq!(label_table["Greeting"], 0);
+        assert_eq!(label_table["Count"], 1);
+        assert_eq!(label_table["Total"], 3);
+        assert_eq!(label_table["Start"], 7);
+        assert_eq!(label_table["Gap"], 67);
+        assert_eq!(label_table["Next"], 68);
+        assert_eq!(label_table["Tail"], 128);
+    }
+
+    #[test]
+    fn start_address_follows_leading_data() {
+        let (label_table, start, bytecode) = assemble("test_files/30-mixed-layout.asm");
+        assert_eq!(start, label_table["Start"] as usize);
+        assert_eq!(bytecode.len(), 129);
+    }
+
+    #[test]
+    fn labels_point_at_emitted_bytes() {
+        let (label_table, _, bytecode) = assemble("test_files/30-mixed-layout.asm");
+        assert_eq!(bytecode[label_table["Greeting"] as usize], b'H');
+        assert_eq!(bytecode[label_table["Count"] as usize], 3);
+        assert_eq!(read_i32(&bytecode, label_table["Total"]), -5);
+        assert_eq!(bytecode[label_table["Gap"] as usize], b'x');
+        assert_eq!(bytecode[label_table["Tail"] as usize], 254);
+
+        let next = label_table["Next"];
+        assert_eq!(read_i32(&bytecode, next), InstructionType::LoadWord.to_bytecode());
+        assert_eq!(read_i32(&bytecode, next + 8), label_table["Total"]);
+    }
+
+    #[test]
+    fn jump_targets_match_instruction_addresses() {
+        let (label_table, start, bytecode) = assemble("test_files/30-mixed-layout.asm");
+        let jump = start as i32 + 4 * INSTRUCTION_SIZE;
+        assert_eq!(read_i32(&bytecode, jump), InstructionType::Jump.to_bytecode());
+        assert_eq!(read_i32(&bytecode, jump + 4), label_table["Next"]);
+    }
+
+    #[test]
+    fn data_section_follows_text_section() {
+        let (label_table, _, bytecode) = assemble("test_files/31-sections.asm");
+        assert_eq!(label_table["Helper"], 0);
+        assert_eq!(label_table["Main"], 3 * INSTRUCTION_SIZE);
+        assert_eq!(label_table["Answer"], 7 * INSTRUCTION_SIZE);
+        assert_eq!(label_table["Letter"], label_table["Answer"] + 4);
+        assert_eq!(label_table["Trailer"], label_table["Letter"] + 1);
+        assert_eq!(read_i32(&bytecode, label_table["Answer"]), 42);
+        assert_eq!(bytecode.len() as i32, label_table["Trailer"] + 2);
+    }
+
+    #[test]
+    fn string_and_space_directives_use_their_length() {
+        let (label_table, _, bytecode) = assemble("test_files/32-strings.asm");
+        let greeting = label_table["Greeting"] as usize;
+        assert_eq!(&bytecode[greeting..greeting + 10], b"Hello, #1 ");
+        assert_eq!(label_table["Name"], label_table["Greeting"] + 10);
+        assert_eq!(label_table["Buffer"], label_table["Name"] + 6);
+        assert_eq!(bytecode[label_table["Buffer"] as usize - 1], 0);
+    }
+
+    #[test]
+    fn align_pads_to_a_multiple() {
+        let (label_table, _, bytecode) = assemble("test_files/32-strings.asm");
+        let number = label_table["Number"];
+        assert_eq!(number % 4, 0);
+        assert!(number >= label_table["Buffer"] + 3 && number < label_table["Buffer"] + 7);
+        assert_eq!(read_i32(&bytecode, number), 99);
+    }
+
+    #[test]
+    fn constants_and_expressions_are_worked_out() {
+        let (tokens, _) = ::macros::expand(Tokenizer::new("test_files/38-constants.asm"));
+        let (label_table, commands) = Assembler::to_commands(tokens);
+        let bytecode = Assembler::to_image(label_table.clone(), commands).unwrap().to_memory();
+        let table = label_table["Table"];
+        assert_eq!(read_i32(&bytecode, label_table["Length"]), 12);
+
+        // `.space` takes 17 bytes, then `.align` pads to a multiple of 4
+        assert_eq!(label_table["Last"], label_table["Buffer"] + 20);
+        assert_eq!(bytecode[label_table["Last"] as usize], b'C');
+
+        // `LDW io Table+8` is the first instruction
+        assert_eq!(read_i32(&bytecode, 8), table + 8);
+    }
+
+    #[test]
+    fn reports_values_which_cannot_be_worked_out() {
+        let source = concat!(
+            ".equ A B+1\n",
+            ".equ B A\n",
+            ".equ BAD 1/0\n",
+            "+ reg_1 BAD\n",
+            "Start .byte 255+1\n",
+            ".space End-Start\n",
+            "End .word A\n",
+        );
+        let (label_table, commands) = Assembler::to_commands(Tokenizer::from_source("bad.asm", source).tokenize().unwrap());
+        let errors = Assembler::to_image(label_table, commands).err().unwrap();
+        let found: Vec<(u32, &str)> = errors.iter().map(|d| (d.line, d.message.as_str())).collect();
+        assert_eq!(found, vec![
+            (1, "constant `A` is defined in terms of itself"),
+            (2, "constant `B` is defined in terms of itself"),
+            (3, "division by zero"),
+            (4, "constant `BAD` has no value"),
+            (5, "expected an integer between -128 and 255 but `255+1` is 256"),
+            (6, "the address of `End` is not known until the program is laid out"),
+            (7, "constant `A` is defined in terms of itself"),
+        ]);
+    }
+
+    #[test]
+    fn commands_which_were_not_verified_are_checked() {
+        let source = concat!(
+            "A .space -5\n",
+            ".space reg_1\n",
+            "+ reg_1\n",
+            "END\n",
+            "MOV reg_1 reg_2 reg_3\n",
+        );
+        let (label_table, commands) = Assembler::to_commands(Tokenizer::from_source("unverified.asm", source));
+
+        // Encoding a bad size on its own is an error rather than a panic
+        let encoded = commands[0].to_bytecode(&Symbols::default(), 0).err().map(|d| d.message);
+        assert_eq!(encoded.as_deref(), Some("expected an integer between 0 and 10000000 but `-5` is -5"));
+
+        let errors = Assembler::to_image(label_table, commands).err().unwrap();
+        let found: Vec<(u32, &str)> = errors.iter().map(|d| (d.line, d.message.as_str())).collect();
+        assert_eq!(found, vec![
+            (1, "expected an integer between 0 and 10000000 but saw \"-5\""),
+            (2, "expected an integer between 0 and 10000000 but saw \"reg_1\""),
+            (3, "expected a register or an integer after \"reg_1\""),
+            (5, "expected a directive or instruction but saw \"reg_3\""),
+        ]);
+    }
+
+    #[test]
+    fn values_are_checked_without_laying_out() {
+        let source = concat!(
+            ".equ A B+1\n",
+            ".equ B A\n",
+            ".equ BAD 1/0\n",
+            "+ reg_1 BAD\n",
+            "Start .byte 255+1\n",
+            ".space End-Start\n",
+            "End .word A\n",
+            "LDW reg_1 [fp+FAR]\n",
+            ".equ FAR 1<<23\n",
+        );
+        let diagnostics = Assembler::check_values(Tokenizer::from_source("bad.asm", source).tokenize().unwrap());
+        let found: Vec<(u32, &str)> = diagnostics.iter().map(|d| (d.line, d.message.as_str())).collect();
+
+        // `.space End-Start` needs the program laid out, so it is left to be reported when assembled
+        assert_eq!(found, vec![
+            (1, "constant `A` is defined in terms of itself"),
+            (2, "constant `B` is defined in terms of itself"),
+            (3, "division by zero"),
+            (4, "constant `BAD` has no value"),
+            (5, "expected an integer between -128 and 255 but `255+1` is 256"),
+            (7, "constant `A` is defined in terms of itself"),
+            (8, "expected an offset between -8388608 and 8388607 but `FAR` is 8388608"),
+        ]);
+    }
+
+    #[test]
+    fn set_constants_take_their_latest_value() {
+        let source = concat!(
+            ".set N 1\n",
+            "A .word N\n",
+            ".set N N*10\n",
+            "B .word N\n",
+            ".equ TWICE N*2\n",
+            ".set N 5\n",
+            "C .word TWICE\n",
+            "D .word N\n",
+            ".set N 1/0\n",
+            "E .word N+1\n",
+        );
+        let (label_table, commands) = Assembler::to_commands(Tokenizer::from_source("set.asm", source).tokenize().unwrap());
+        let symbols = Symbols::new(label_table.clone(), &commands);
+        let value = |label: &str| {
+            let command = commands.iter().find(|command| command.label.token_type == TokenType::Label(label.to_string())).unwrap();
+            symbols.evaluate(&command.operand1, 0, true, &mut vec![]).map_err(|err| err.message)
+        };
+        assert_eq!(value("A"), Ok(1));
+        assert_eq!(value("B"), Ok(10));
+        assert_eq!(value("C"), Ok(20));
+        assert_eq!(value("D"), Ok(5));
+        assert_eq!(value("E"), Err("constant `N` has no value".to_string()));
+    }
+
+    #[test]
+    fn register_offsets_can_use_constants() {
+        let source = concat!(
+            ".equ FIELD 8\n",
+            ".equ FAR 1<<23\n",
+            "LDW reg_1 [reg_2+FIELD]\n",
+            "STW reg_1 [fp-FIELD*2]\n",
+            "LDW reg_1 [sp+FAR]\n",
+        );
+        let (label_table, commands) = Assembler::to_commands(Tokenizer::from_source("offsets.asm", source).tokenize().unwrap());
+        let symbols = Symbols::new(label_table.clone(), &commands);
+        let offset = |index: usize| operand_to_bytecode(&commands[index].cmd_type, &commands[index].operand2, &symbols, 0).map(decode_indirect);
+        assert_eq!(offset(2), Ok((Register::Reg2.to_bytecode(), 8)));
+        assert_eq!(offset(3), Ok((Register::FP.to_bytecode(), -16)));
+
+        let errors = Assembler::to_image(label_table, commands).err().unwrap();
+        let found: Vec<(u32, &str)> = errors.iter().map(|d| (d.line, d.message.as_str())).collect();
+        assert_eq!(found, vec![(5, "expected an offset between -8388608 and 8388607 but `FAR` is 8388608")]);
+    }
+
+    #[test]
+    fn sections_can_be_placed_anywhere() {
+        let path = "test_files/31-sections.asm";
+        let origin = Origin { text: 0x1000, data: Some(0x8000) };
+        let (label_table, commands) = Assembler::to_commands_at(Tokenizer::new(path), origin);
+        assert_eq!(label_table["Helper"], 0x1000);
+        assert_eq!(label_table["Main"], 0x1000 + 3 * INSTRUCTION_SIZE);
+        assert_eq!(label_table["Answer"], 0x8000);
+
+        let image = Assembler::to_image(label_table.clone(), commands).unwrap();
+        assert_eq!(image.entry_point, label_table["Main"] as u32);
+        let addresses: Vec<u32> = image.sections.iter().map(|section| section.address).collect();
+        assert_eq!(addresses, vec![0x1000, 0x8000]);
+        assert_eq!(read_i32(&image.to_memory(), label_table["Answer"]), 42);
+    }
+
+    #[test]
+    fn commands_past_the_end_of_the_address_space_are_errors() {
+        let origin = Origin { text: 0x7FFF_FFF0, data: None };
+        let (label_table, commands) = Assembler::to_commands_at(Tokenizer::from_source("high.asm", "END\nEND\n"), origin);
+        let errors = Assembler::to_image(label_table, commands).err().unwrap();
+        let found: Vec<(u32, &str)> = errors.iter().map(|d| (d.line, d.message.as_str())).collect();
+        assert_eq!(found, vec![(2, "`END` runs past the end of the address space")]);
+
+        // Sizes which were never checked take no space rather than failing
+        let (_, commands) = Assembler::to_commands(Tokenizer::from_source("align.asm", ".align 0\n"));
+        assert_eq!(commands[0].size(3), 0);
+    }
+
+    #[test]
+    fn entry_directive_sets_start_address() {
+        let (label_table, start, _) = assemble("test_files/31-sections.asm");
+        assert_eq!(start, label_table["Main"] as usize);
     }
 }
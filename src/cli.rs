@@ -0,0 +1,374 @@
+use std::path::Path;
+use vm::{CostTable, MemoryLayout, Origin, TraceFormat};
+
+pub const USAGE: &str = "\
+usage: vm <command> [options]
+
+commands:
+    assemble <file.asm> [options]        Assemble a program into an image
+    run <file.bin|file.asm> [options]    Run an image or assembly source
+    check <file.asm>                     Report problems without assembling
+    disasm <file.bin|file.asm>           Print the instructions in a program
+    debug <file.bin|file.asm> [options]  Step through a program interactively
+
+assemble options:
+    -o, --output <file>         Where to write the image, instead of the input with `.bin`
+
+assemble, run and debug options:
+    --code-base <address>       Address the text section starts at
+    --data-base <address>       Address the data section starts at, instead of after the text
+
+run and debug options:
+    --memory <bytes>            Amount of memory available to the program
+    --stack-top <address>       Address the stack grows down from, instead of the top of memory
+    --stack-size <bytes>        Most memory the stack may use
+    --heap <start..end>         Memory to set aside which nothing is loaded into
+    --max-steps <count>         Stop after executing this many instructions
+    --budget <units>            Stop before the program spends more than this
+    --cost <op>=<units>         Charge this much for an instruction, such as `CALL=5`
+    --input <file>              Read program input from a file instead of stdin
+    --output <file>             Write program output to a file instead of stdout
+
+run options:
+    --stats                     Report the instructions executed when the program stops
+    --trace <file>              Log each instruction executed, or use `-` for stderr
+    --trace-format <text|json>  Write the log as text or as JSON lines
+    --trace-filter <range>      Only log instructions under a label or in `start..end`
+
+Several source files may be given to assemble, run, check and debug,
+which are assembled together in order as one program.
+
+An image is loaded at the addresses it was assembled for, so `--code-base`
+and `--data-base` only apply when running or debugging source.
+
+`vm <file>` is short for `vm run <file>`.";
+
+/// Options which control how a program is run
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RunOptions {
+    pub memory_size: Option<usize>,
+
+    /// Where the program and stack go in memory
+    pub layout: MemoryLayout,
+    pub max_steps: Option<u64>,
+    pub input: Option<String>,
+    pub output: Option<String>,
+
+    /// Most the program may spend, pricing each instruction with `costs`
+    pub budget: Option<u64>,
+    pub costs: CostTable,
+
+    /// Whether to report how many instructions ran
+    pub stats: bool,
+
+    /// Where to write the trace, if tracing
+    pub trace: Option<String>,
+    pub trace_format: TraceFormat,
+
+    /// Labels or address ranges to limit the trace to
+    pub trace_filters: Vec<String>
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Subcommand {
+    Assemble { inputs: Vec<String>, output: String, origin: Origin },
+    Run { inputs: Vec<String>, options: RunOptions },
+    Check { inputs: Vec<String> },
+    Disassemble { input: String },
+    Debug { inputs: Vec<String>, options: RunOptions },
+    Help
+}
+
+/// Parses the command line arguments, not including the program name
+pub fn parse_args(args: &[String]) -> Result<Subcommand, String> {
+    let (name, rest) = match args.split_first() {
+        Some((name, rest)) => (name.as_str(), rest),
+        None => return Err("missing a command".to_string())
+    };
+
+    match name {
+        "-h" | "--help" | "help" => Ok(Subcommand::Help),
+        "assemble" => {
+            let mut inputs = vec![];
+            let mut output = None;
+            let mut origin = Origin::default();
+            let mut args = rest.iter();
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "-o" | "--output" => output = Some(value(arg, args.next())?),
+                    "--code-base" => origin.text = address(arg, args.next())?,
+                    "--data-base" => origin.data = Some(address(arg, args.next())?),
+                    _ => add_input(&mut inputs, arg)?
+                }
+            }
+            let first = inputs.first().ok_or("missing an input file")?;
+            let output = output.unwrap_or_else(|| default_output(first));
+            Ok(Subcommand::Assemble { inputs, output, origin })
+        },
+        "run" | "debug" => {
+            let mut inputs = vec![];
+            let mut options = RunOptions::default();
+            let mut args = rest.iter();
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--memory" => options.memory_size = Some(number(arg, args.next())?),
+                    "--code-base" => options.layout.code_base = address(arg, args.next())?,
+                    "--data-base" => options.layout.data_base = Some(address(arg, args.next())?),
+                    "--stack-top" => options.layout.stack_top = Some(address(arg, args.next())?),
+                    "--stack-size" => options.layout.stack_size = number(arg, args.next())?,
+                    "--heap" => {
+                        let heap = value(arg, args.next())?;
+                        let range = heap.find("..").and_then(|index| {
+                            Some(parse_address(&heap[..index])?..parse_address(&heap[index + 2..])?)
+                        });
+                        match range {
+                            Some(range) => options.layout.heap = Some(range),
+                            None => return Err(format!("`{}` requires `<start>..<end>` but saw `{}`", arg, heap))
+                        }
+                    },
+                    "--max-steps" => options.max_steps = Some(number(arg, args.next())?),
+                    "--input" => options.input = Some(value(arg, args.next())?),
+                    "--output" => options.output = Some(value(arg, args.next())?),
+                    "--budget" => options.budget = Some(number(arg, args.next())?),
+                    "--cost" => {
+                        let cost = value(arg, args.next())?;
+                        let (mnemonic, units) = match cost.rfind('=') {
+                            Some(index) => (&cost[..index], cost[index + 1..].to_string()),
+                            None => return Err(format!("`{}` requires `<instruction>=<units>` but saw `{}`", arg, cost))
+                        };
+                        let units = number(arg, Some(&units))?;
+                        if !options.costs.set_cost_by_name(mnemonic, units) {
+                            return Err(format!("unknown instruction `{}`", mnemonic));
+                        }
+                    },
+                    "--stats" if name == "run" => options.stats = true,
+                    "--trace" if name == "run" => options.trace = Some(value(arg, args.next())?),
+                    "--trace-format" if name == "run" => options.trace_format = match value(arg, args.next())?.as_str() {
+                        "text" => TraceFormat::Text,
+                        "json" => TraceFormat::Json,
+                        format => return Err(format!("unknown trace format `{}`", format))
+                    },
+                    "--trace-filter" if name == "run" => options.trace_filters.push(value(arg, args.next())?),
+                    _ => add_input(&mut inputs, arg)?
+                }
+            }
+            if inputs.is_empty() {
+                return Err("missing an input file".to_string());
+            }
+            if options.trace.is_none() && (options.trace_format != TraceFormat::default() || !options.trace_filters.is_empty()) {
+                return Err("`--trace-format` and `--trace-filter` require `--trace`".to_string());
+            }
+            if name == "run" {
+                Ok(Subcommand::Run { inputs, options })
+            } else {
+                Ok(Subcommand::Debug { inputs, options })
+            }
+        },
+        "check" => {
+            let mut inputs = vec![];
+            for arg in rest {
+                add_input(&mut inputs, arg)?;
+            }
+            if inputs.is_empty() {
+                return Err("missing an input file".to_string());
+            }
+            Ok(Subcommand::Check { inputs })
+        },
+        "disasm" => {
+            let mut input = None;
+            for arg in rest {
+                set_input(&mut input, arg)?;
+            }
+            let input = input.ok_or("missing an input file")?;
+            Ok(Subcommand::Disassemble { input })
+        },
+        _ if !name.starts_with('-') && rest.is_empty() => Ok(Subcommand::Run {
+            inputs: vec![name.to_string()],
+            options: RunOptions::default()
+        }),
+        _ => Err(format!("unknown command `{}`", name))
+    }
+}
+
+/// Records a positional input file, rejecting unknown options
+fn add_input(inputs: &mut Vec<String>, arg: &str) -> Result<(), String> {
+    if arg.starts_with('-') {
+        return Err(format!("unknown option `{}`", arg));
+    }
+    inputs.push(arg.to_string());
+    Ok(())
+}
+
+/// Records the only positional input file, rejecting unknown options and extra files
+fn set_input(input: &mut Option<String>, arg: &str) -> Result<(), String> {
+    if arg.starts_with('-') {
+        return Err(format!("unknown option `{}`", arg));
+    }
+    if input.is_some() {
+        return Err(format!("unexpected argument `{}`", arg));
+    }
+    *input = Some(arg.to_string());
+    Ok(())
+}
+
+fn value(option: &str, value: Option<&String>) -> Result<String, String> {
+    value.cloned().ok_or_else(|| format!("`{}` requires a value", option))
+}
+
+fn number<T: ::std::str::FromStr>(option: &str, arg: Option<&String>) -> Result<T, String> {
+    let arg = value(option, arg)?;
+    arg.replace('_', "").parse()
+        .map_err(|_| format!("`{}` requires a number but saw `{}`", option, arg))
+}
+
+/// Parses a decimal or `0x` hexadecimal address
+fn parse_address(text: &str) -> Option<u32> {
+    let digits = text.replace('_', "");
+    let address = match digits.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+        None => digits.parse().ok()?
+    };
+    if address <= i32::MAX as u32 {
+        Some(address)
+    } else {
+        None
+    }
+}
+
+fn address(option: &str, arg: Option<&String>) -> Result<u32, String> {
+    let arg = value(option, arg)?;
+    parse_address(&arg).ok_or_else(|| format!("`{}` requires an address but saw `{}`", option, arg))
+}
+
+/// Replaces the extension of the input file with `.bin`
+fn default_output(input: &str) -> String {
+    Path::new(input).with_extension("bin").to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Subcommand, String> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        parse_args(&args)
+    }
+
+    #[test]
+    fn assemble_defaults_to_a_bin_file() {
+        assert_eq!(parse(&["assemble", "dir/foo.asm"]), Ok(Subcommand::Assemble {
+            inputs: vec!["dir/foo.asm".to_string()],
+            output: "dir/foo.bin".to_string(),
+            origin: Origin::default()
+        }));
+        assert_eq!(parse(&["assemble", "foo.asm", "-o", "out.img", "--code-base", "0x1000"]), Ok(Subcommand::Assemble {
+            inputs: vec!["foo.asm".to_string()],
+            output: "out.img".to_string(),
+            origin: Origin { text: 0x1000, data: None }
+        }));
+    }
+
+    #[test]
+    fn run_options() {
+        assert_eq!(parse(&["run", "foo.bin", "--memory", "65_536", "--max-steps", "100", "--input", "in.txt"]), Ok(Subcommand::Run {
+            inputs: vec!["foo.bin".to_string()],
+            options: RunOptions {
+                memory_size: Some(65_536),
+                max_steps: Some(100),
+                input: Some("in.txt".to_string()),
+                output: None,
+                ..RunOptions::default()
+            }
+        }));
+    }
+
+    #[test]
+    fn trace_options() {
+        assert_eq!(parse(&["run", "foo.asm", "--trace", "-", "--trace-format", "json", "--trace-filter", "Loop", "--trace-filter", "0..24"]), Ok(Subcommand::Run {
+            inputs: vec!["foo.asm".to_string()],
+            options: RunOptions {
+                trace: Some("-".to_string()),
+                trace_format: TraceFormat::Json,
+                trace_filters: vec!["Loop".to_string(), "0..24".to_string()],
+                ..RunOptions::default()
+            }
+        }));
+        assert!(parse(&["run", "foo.asm", "--trace", "-", "--trace-format", "xml"]).is_err());
+        assert!(parse(&["run", "foo.asm", "--trace-filter", "Loop"]).is_err());
+        assert!(parse(&["debug", "foo.asm", "--trace", "-"]).is_err());
+    }
+
+    #[test]
+    fn budget_options() {
+        let mut costs = CostTable::new();
+        costs.set_cost_by_name("CALL", 5);
+        costs.set_cost_by_name("==", 2);
+        assert_eq!(parse(&["run", "foo.asm", "--budget", "1_000", "--cost", "CALL=5", "--cost", "===2", "--stats"]), Ok(Subcommand::Run {
+            inputs: vec!["foo.asm".to_string()],
+            options: RunOptions {
+                budget: Some(1000),
+                costs,
+                stats: true,
+                ..RunOptions::default()
+            }
+        }));
+        assert!(parse(&["run", "foo.asm", "--cost", "CALL"]).is_err());
+        assert!(parse(&["run", "foo.asm", "--cost", "CAL=5"]).is_err());
+        assert!(parse(&["run", "foo.asm", "--cost", "CALL=lots"]).is_err());
+    }
+
+    #[test]
+    fn layout_options() {
+        assert_eq!(parse(&["debug", "foo.asm", "--code-base", "4_096", "--data-base", "0x2000", "--stack-top", "0x8000", "--stack-size", "1024", "--heap", "0x8000..0x9000"]), Ok(Subcommand::Debug {
+            inputs: vec!["foo.asm".to_string()],
+            options: RunOptions {
+                layout: MemoryLayout {
+                    code_base: 0x1000,
+                    data_base: Some(0x2000),
+                    stack_top: Some(0x8000),
+                    stack_size: 1024,
+                    heap: Some(0x8000..0x9000)
+                },
+                ..RunOptions::default()
+            }
+        }));
+        assert!(parse(&["run", "foo.asm", "--code-base", "-4"]).is_err());
+        assert!(parse(&["run", "foo.asm", "--code-base", "0xFFFFFFFF"]).is_err());
+        assert!(parse(&["run", "foo.asm", "--heap", "0x8000"]).is_err());
+        assert!(parse(&["assemble", "foo.asm", "--heap", "0..4"]).is_err());
+    }
+
+    #[test]
+    fn several_source_files() {
+        let inputs = vec!["main.asm".to_string(), "lib/print.asm".to_string()];
+        assert_eq!(parse(&["run", "main.asm", "--max-steps", "10", "lib/print.asm"]), Ok(Subcommand::Run {
+            inputs: inputs.clone(),
+            options: RunOptions { max_steps: Some(10), ..RunOptions::default() }
+        }));
+        assert_eq!(parse(&["assemble", "main.asm", "lib/print.asm"]), Ok(Subcommand::Assemble {
+            inputs: inputs.clone(),
+            output: "main.bin".to_string(),
+            origin: Origin::default()
+        }));
+        assert_eq!(parse(&["check", "main.asm", "lib/print.asm"]), Ok(Subcommand::Check { inputs }));
+    }
+
+    #[test]
+    fn a_lone_file_is_run() {
+        assert_eq!(parse(&["foo.asm"]), Ok(Subcommand::Run {
+            inputs: vec!["foo.asm".to_string()],
+            options: RunOptions::default()
+        }));
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["run"]).is_err());
+        assert!(parse(&["disasm", "a.asm", "b.asm"]).is_err());
+        assert!(parse(&["run", "a.asm", "--memory"]).is_err());
+        assert!(parse(&["run", "a.asm", "--memory", "lots"]).is_err());
+        assert!(parse(&["check", "a.asm", "--verbose"]).is_err());
+        assert!(parse(&["frobnicate", "a.asm"]).is_err());
+    }
+}
@@ -0,0 +1,408 @@
+use byteorder::{ReadBytesExt, LittleEndian};
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::io::{BufRead, Cursor, Write};
+use std::rc::Rc;
+use assembler::{CommandType, INSTRUCTION_SIZE};
+use disasm;
+use image::Image;
+use source::SourceFiles;
+use tokenizer::*;
+use device::Device;
+use vm::{StepResult, VM};
+
+const HELP: &str = "\
+break <label|address>      Stop when the PC reaches an address (b)
+delete <label|address>     Remove a breakpoint (d)
+breakpoints                List every breakpoint
+step [count]               Execute one or more instructions (s)
+next                       Execute one instruction, running a CALL until it returns (n)
+continue                   Run until a breakpoint or the end of the program (c)
+where                      Show the current instruction (w)
+registers                  Show every register (r)
+print <register>           Show a single register (p)
+memory <address> [count] [bytes|words|ascii]
+                           Dump memory starting at a label, address or register (x)
+quit                       Stop debugging (q)
+
+An empty line repeats the previous command.";
+
+/// Number of values `memory` shows when no count is given
+const DEFAULT_DUMP_COUNT: usize = 16;
+
+/// Every register in the order `registers` shows them
+const REGISTERS: [Register; 13] = [
+    Register::Reg0, Register::Reg1, Register::Reg2, Register::Reg3, Register::Reg4, Register::Reg5, Register::Reg6,
+    Register::IO, Register::PC, Register::SP, Register::FP, Register::SB, Register::SL
+];
+
+/// The source a program was assembled from
+pub struct SourceMap {
+    /// Lines of each file, starting with the first file of the program
+    files: Vec<(Rc<str>, Vec<String>)>,
+
+    /// File and line each instruction address was assembled from
+    addresses: HashMap<i32, (Rc<str>, u32)>
+}
+
+impl SourceMap {
+    pub fn new(files: &SourceFiles, addresses: HashMap<i32, (Rc<str>, u32)>) -> SourceMap {
+        SourceMap {
+            files: files.names().into_iter()
+                .map(|name| (Rc::from(name), files.get(name).unwrap_or("").lines().map(|line| line.to_string()).collect()))
+                .collect(),
+            addresses
+        }
+    }
+
+    /// Describes where in the source an instruction came from, along with the text of the line
+    /// Lines outside the first file also name their file
+    fn line(&self, address: i32) -> Option<(String, &str)> {
+        let (file, line_number) = self.addresses.get(&address)?;
+        let index = self.files.iter().position(|(name, _)| name == file)?;
+        let text = self.files[index].1.get(line_number.checked_sub(1)? as usize)?;
+        let location = match index {
+            0 => format!("line {}", line_number),
+            _ => format!("line {} of {}", line_number, file)
+        };
+        Some((location, text.trim()))
+    }
+}
+
+/// Format used when dumping memory
+#[derive(Clone, Copy)]
+enum DumpFormat {
+    Bytes,
+    Words,
+    Ascii
+}
+
+/// Runs a program one command at a time, stopping at breakpoints
+pub struct Debugger<D: Device> {
+    vm: VM<D>,
+    source: Option<SourceMap>,
+    labels: HashMap<String, i32>,
+
+    /// Name shown for each address which has a label
+    names: HashMap<i32, String>,
+    breakpoints: BTreeSet<i32>,
+
+    /// Why the program stopped, once it has finished
+    finished: Option<String>
+}
+
+impl<D: Device> Debugger<D> {
+    pub fn new(mut vm: VM<D>, image: &Image, source: Option<SourceMap>) -> Debugger<D> {
+        vm.set_pc(image.entry_point as i32);
+
+        let mut labels = HashMap::new();
+        let mut names = HashMap::new();
+        for symbol in image.symbols.iter().flatten() {
+            labels.insert(symbol.name.to_string(), symbol.address as i32);
+            names.entry(symbol.address as i32).or_insert_with(|| symbol.name.to_string());
+        }
+
+        Debugger {
+            vm,
+            source,
+            labels,
+            names,
+            breakpoints: BTreeSet::new(),
+            finished: None
+        }
+    }
+
+    /// Reads commands from `input` until it ends or the user quits
+    pub fn run<R: BufRead, W: Write>(&mut self, input: &mut R, output: &mut W) -> io::Result<()> {
+        writeln!(output, "{}", self.location())?;
+        let mut previous = String::new();
+        loop {
+            write!(output, "(vdb) ")?;
+            output.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+
+            let line = if line.trim().is_empty() { previous.clone() } else { line.trim().to_string() };
+            if line == "q" || line == "quit" {
+                return Ok(());
+            }
+            match self.command(&line) {
+                Ok(response) => if !response.is_empty() { writeln!(output, "{}", response)? },
+                Err(message) => writeln!(output, "error: {}", message)?
+            };
+            previous = line;
+        }
+    }
+
+    /// Runs a single debugger command, returning what it prints
+    pub fn command(&mut self, line: &str) -> Result<String, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (name, args) = match words.split_first() {
+            Some((name, args)) => (*name, args),
+            None => return Ok(String::new())
+        };
+
+        match name {
+            "help" | "h" => Ok(HELP.to_string()),
+            "break" | "b" => {
+                let address = self.address(argument(args, 0)?)?;
+                self.breakpoints.insert(address);
+                Ok(format!("breakpoint at {}", self.describe(address)))
+            },
+            "delete" | "d" => {
+                let address = self.address(argument(args, 0)?)?;
+                if self.breakpoints.remove(&address) {
+                    Ok(format!("removed the breakpoint at {}", self.describe(address)))
+                } else {
+                    Err(format!("there is no breakpoint at {}", self.describe(address)))
+                }
+            },
+            "breakpoints" => {
+                let breakpoints: Vec<String> = self.breakpoints.iter()
+                    .map(|address| self.describe(*address))
+                    .collect();
+                if breakpoints.is_empty() {
+                    Ok("no breakpoints".to_string())
+                } else {
+                    Ok(breakpoints.join("\n"))
+                }
+            },
+            "step" | "s" => {
+                let count = match args.first() {
+                    Some(count) => count.parse().map_err(|_| format!("expected a count but saw `{}`", count))?,
+                    None => 1
+                };
+                self.check_running()?;
+                let result = self.vm.run_for(count);
+                self.record(result);
+                Ok(self.location())
+            },
+            "next" | "n" => {
+                self.next()?;
+                Ok(self.location())
+            },
+            "continue" | "c" => {
+                self.check_running()?;
+                let breakpoints = &self.breakpoints;
+                let result = self.vm.run_until(|vm| breakpoints.contains(&vm.pc()));
+                self.record(result);
+                Ok(self.location())
+            },
+            "where" | "w" => Ok(self.location()),
+            "registers" | "r" => {
+                let registers: Vec<String> = REGISTERS.iter()
+                    .map(|register| format!("{:<5} {}", register.to_string(), self.vm.get_register(register.clone())))
+                    .collect();
+                Ok(registers.join("\n"))
+            },
+            "print" | "p" => {
+                let name = argument(args, 0)?;
+                let register = register_from_name(&name.to_lowercase())
+                    .ok_or_else(|| format!("unknown register `{}`", name))?;
+                Ok(format!("{} = {}", register, self.vm.get_register(register.clone())))
+            },
+            "memory" | "x" => {
+                let address = self.address(argument(args, 0)?)?;
+                let mut count = DEFAULT_DUMP_COUNT;
+                let mut format = DumpFormat::Bytes;
+                for arg in &args[1..] {
+                    match *arg {
+                        "bytes" => format = DumpFormat::Bytes,
+                        "words" => format = DumpFormat::Words,
+                        "ascii" => format = DumpFormat::Ascii,
+                        _ => count = arg.parse().map_err(|_| format!("expected a count or format but saw `{}`", arg))?
+                    }
+                }
+                self.dump(address, count, format)
+            },
+            _ => Err(format!("unknown command `{}`, try `help`", name))
+        }
+    }
+
+    fn check_running(&self) -> Result<(), String> {
+        match &self.finished {
+            Some(reason) => Err(format!("the program has finished: {}", reason)),
+            None => Ok(())
+        }
+    }
+
+    /// Remembers why the program stopped if it has finished
+    /// Returns whether the program can keep running
+    fn record(&mut self, result: StepResult) -> bool {
+        match result {
+            StepResult::Continue => return true,
+            StepResult::Exited(status) => self.finished = Some(status.to_string()),
+            StepResult::Trapped(trap) => self.finished = Some(trap.to_string())
+        };
+        false
+    }
+
+    /// Steps over a `CALL`, stopping early at breakpoints
+    fn next(&mut self) -> Result<(), String> {
+        self.check_running()?;
+        let pc = self.vm.pc();
+        let is_call = match disasm::decode(self.vm.read_memory(pc, INSTRUCTION_SIZE as usize).unwrap_or(&[])) {
+            Some(command) => command.cmd_type == CommandType::Instruction(InstructionType::Call),
+            None => false
+        };
+        let result = self.vm.step();
+        if !self.record(result) || !is_call {
+            return Ok(());
+        }
+
+        // Recursive calls pass through the return address with a deeper stack
+        let stack = self.vm.get_register(Register::SP) + 4;
+        let return_address = pc + INSTRUCTION_SIZE;
+        let breakpoints = &self.breakpoints;
+        let result = self.vm.run_until(|vm| {
+            (vm.pc() == return_address && vm.get_register(Register::SP) >= stack) || breakpoints.contains(&vm.pc())
+        });
+        self.record(result);
+        Ok(())
+    }
+
+    /// Resolves a label, integer or register name to an address
+    fn address(&self, text: &str) -> Result<i32, String> {
+        if let Some(address) = self.labels.get(text) {
+            return Ok(*address);
+        }
+        if let Some(address) = parse_integer(text) {
+            return Ok(address);
+        }
+        if let Some(register) = register_from_name(&text.to_lowercase()) {
+            return Ok(self.vm.get_register(register));
+        }
+        Err(format!("expected a label, address or register but saw `{}`", text))
+    }
+
+    /// Formats an address along with its label if it has one
+    fn describe(&self, address: i32) -> String {
+        match self.names.get(&address) {
+            Some(name) => format!("{} ({})", address, name),
+            None => address.to_string()
+        }
+    }
+
+    /// Shows the instruction the PC points to and the line of source it came from
+    fn location(&self) -> String {
+        let pc = self.vm.pc();
+        let mut location = match &self.finished {
+            Some(reason) => format!("the program has finished: {}\n", reason),
+            None => String::new()
+        };
+
+        let bytes = self.vm.read_memory(pc, INSTRUCTION_SIZE as usize).unwrap_or(&[]);
+        let instruction = match disasm::decode(bytes) {
+            Some(command) => disasm::format_instruction(&command, &self.names),
+            None => "<not an instruction>".to_string()
+        };
+        location.push_str(&format!("=> {}: {}", self.describe(pc), instruction));
+        if let Some((line, text)) = self.source.as_ref().and_then(|source| source.line(pc)) {
+            location.push_str(&format!("\n   {}: {}", line, text));
+        }
+        location
+    }
+
+    fn dump(&self, address: i32, count: usize, format: DumpFormat) -> Result<String, String> {
+        let unit = match format {
+            DumpFormat::Words => 4,
+            DumpFormat::Bytes | DumpFormat::Ascii => 1
+        };
+        let memory = self.vm.memory();
+        let start = address.max(0) as usize;
+        let end = start.saturating_add(count.saturating_mul(unit));
+        if address < 0 || end > memory.len() {
+            return Err(format!("{} bytes at address {} are outside of memory", end - start, address));
+        }
+
+        let per_line = match format {
+            DumpFormat::Bytes => 16,
+            DumpFormat::Words => 4,
+            DumpFormat::Ascii => 64
+        };
+        let lines: Vec<String> = memory[start..end].chunks(per_line * unit).enumerate()
+            .map(|(i, chunk)| {
+                let values = match format {
+                    DumpFormat::Bytes => chunk.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(" "),
+                    DumpFormat::Words => {
+                        let mut reader = Cursor::new(chunk);
+                        (0..chunk.len() / 4)
+                            .map(|_| reader.read_i32::<LittleEndian>().unwrap().to_string())
+                            .collect::<Vec<_>>()
+                            .join(" ")
+                    },
+                    DumpFormat::Ascii => chunk.iter()
+                        .map(|byte| if (b' '..=b'~').contains(byte) { *byte as char } else { '.' })
+                        .collect()
+                };
+                format!("{}: {}", start + i * per_line * unit, values)
+            })
+            .collect();
+        Ok(lines.join("\n"))
+    }
+}
+
+fn argument<'a>(args: &[&'a str], index: usize) -> Result<&'a str, String> {
+    args.get(index).cloned().ok_or_else(|| "missing an argument".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use device::Streams;
+    use memory::VmConfig;
+    use test_support;
+    use super::*;
+
+    fn debugger(path: &str) -> Debugger<Streams<io::Empty, io::Sink>> {
+        let assembly = test_support::assemble(path);
+        let vm = VM::from_image(&assembly.image, &VmConfig::default(), Streams::new(io::empty(), io::sink())).unwrap();
+        Debugger::new(vm, &assembly.image, Some(assembly.source_map))
+    }
+
+    #[test]
+    fn shows_the_current_instruction_and_source() {
+        let mut debugger = debugger("test_files/27-call-stack.asm");
+        assert_eq!(debugger.command("where"), Ok("=> 0: + reg_1 21\n   line 1: + reg_1 21".to_string()));
+        assert_eq!(debugger.command("step 2"), Ok("=> 24: CALL Double\n   line 3: CALL Double".to_string()));
+    }
+
+    #[test]
+    fn breakpoints_stop_continue() {
+        let mut debugger = debugger("test_files/27-call-stack.asm");
+        assert_eq!(debugger.command("break Double"), Ok("breakpoint at 72 (Double)".to_string()));
+        assert!(debugger.command("continue").unwrap().starts_with("=> 72 (Double): ENTER 4"));
+        assert_eq!(debugger.command("print SP"), Ok("sp = 9999992".to_string()));
+        assert!(debugger.command("c").unwrap().starts_with("the program has finished: halted"));
+        assert!(debugger.command("step").is_err());
+    }
+
+    #[test]
+    fn next_steps_over_calls() {
+        let mut debugger = debugger("test_files/27-call-stack.asm");
+        debugger.command("step 2").unwrap();
+        assert!(debugger.command("next").unwrap().starts_with("=> 36: POP io"));
+        assert_eq!(debugger.command("p reg_1"), Ok("reg_1 = 21".to_string()));
+        debugger.command("n").unwrap();
+        assert_eq!(debugger.command("p io"), Ok("io = 42".to_string()));
+    }
+
+    #[test]
+    fn dumps_memory() {
+        let mut debugger = debugger("test_files/32-strings.asm");
+        assert_eq!(debugger.command("x Greeting 5 ascii"), Ok("108: Hello".to_string()));
+        assert_eq!(debugger.command("x Greeting 3"), Ok("108: 48 65 6C".to_string()));
+        assert_eq!(debugger.command("x Number 1 words"), Ok("128: 99".to_string()));
+        assert!(debugger.command("x 9999999 4 words").is_err());
+    }
+
+    #[test]
+    fn reports_bad_commands() {
+        let mut debugger = debugger("test_files/27-call-stack.asm");
+        assert!(debugger.command("frobnicate").is_err());
+        assert!(debugger.command("break Nowhere").is_err());
+        assert!(debugger.command("print reg_9").is_err());
+        assert!(debugger.command("delete 0").is_err());
+    }
+}
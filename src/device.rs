@@ -0,0 +1,93 @@
+use std::io;
+use std::io::{BufRead, Write};
+
+/// Where a program's input comes from and where its output goes
+pub trait Device {
+    /// Reads a line of input into `line`, including the newline
+    /// Returns the number of bytes read, which is 0 once the input has run out
+    fn read_line(&mut self, line: &mut String) -> io::Result<usize>;
+
+    fn write(&mut self, text: &str) -> io::Result<()>;
+
+    /// Makes sure everything written so far has reached its destination
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads from stdin and writes to stdout
+pub struct Console {
+    input: io::BufReader<io::Stdin>
+}
+
+impl Console {
+    pub fn new() -> Console {
+        Console {
+            input: console_input()
+        }
+    }
+}
+
+impl Default for Console {
+    fn default() -> Console {
+        Console::new()
+    }
+}
+
+impl Device for Console {
+    fn read_line(&mut self, line: &mut String) -> io::Result<usize> {
+        self.input.read_line(line)
+    }
+
+    fn write(&mut self, text: &str) -> io::Result<()> {
+        io::stdout().write_all(text.as_bytes())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+/// Reads from stdin without taking more input than is asked for
+/// Stdin is already buffered, so a one byte buffer leaves the rest for anything else reading it
+pub fn console_input() -> io::BufReader<io::Stdin> {
+    io::BufReader::with_capacity(1, io::stdin())
+}
+
+/// Reads from and writes to any pair of streams, such as files or in-memory buffers
+///
+/// ```text
+/// let device = Streams::new(Cursor::new("5\n"), Vec::new());
+/// ```
+pub struct Streams<R: BufRead, W: Write> {
+    input: R,
+    output: W
+}
+
+impl<R: BufRead, W: Write> Streams<R, W> {
+    pub fn new(input: R, output: W) -> Streams<R, W> {
+        Streams {
+            input,
+            output
+        }
+    }
+
+    /// Everything written to the output so far
+    pub fn output(&self) -> &W {
+        &self.output
+    }
+}
+
+impl<R: BufRead, W: Write> Device for Streams<R, W> {
+    fn read_line(&mut self, line: &mut String) -> io::Result<usize> {
+        self.input.read_line(line)
+    }
+
+    fn write(&mut self, text: &str) -> io::Result<()> {
+        self.output.write_all(text.as_bytes())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
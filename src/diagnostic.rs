@@ -0,0 +1,213 @@
+use std::fmt;
+use std::ops::Range;
+use tokenizer::Token;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Note => write!(f, "note")
+        }
+    }
+}
+
+/// A problem found in a source file, pointing at the columns which caused it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub file: String,
+    pub line: u32,
+
+    /// Columns the problem spans, counting from 1
+    pub columns: Range<u32>,
+
+    /// Other places in the source related to the problem
+    pub notes: Vec<Diagnostic>,
+
+    /// A hint on how to fix the problem
+    pub help: Option<String>,
+
+    /// How many of the notes point at the macro calls which produced the offending code
+    /// They come first, innermost call first
+    expansions: u32
+}
+
+impl Diagnostic {
+    pub fn error(message: String, token: &Token) -> Diagnostic {
+        Diagnostic::new(Severity::Error, message, token).with_expansions(token)
+    }
+
+    pub fn warning(message: String, token: &Token) -> Diagnostic {
+        Diagnostic::new(Severity::Warning, message, token).with_expansions(token)
+    }
+
+    fn new(severity: Severity, message: String, token: &Token) -> Diagnostic {
+        Diagnostic {
+            severity,
+            message,
+            file: token.file.to_string(),
+            line: token.line_number,
+            columns: token.column..token.column + token.width.max(1),
+            notes: vec![],
+            help: None,
+            expansions: 0
+        }
+    }
+
+    /// Adds a note for each macro call which led to `token`, innermost first
+    fn with_expansions(mut self, token: &Token) -> Diagnostic {
+        let mut expansion = token.expansion.as_ref();
+        while let Some(current) = expansion {
+            self = self.with_note(format!("in this expansion of macro `{}`", current.name), &current.call);
+            self.expansions += 1;
+            expansion = current.call.expansion.as_ref();
+        }
+        self
+    }
+
+    /// Attaches a note pointing at another token
+    pub fn with_note(mut self, message: String, token: &Token) -> Diagnostic {
+        self.notes.push(Diagnostic::new(Severity::Note, message, token));
+        self
+    }
+
+    pub fn with_help(mut self, help: String) -> Diagnostic {
+        self.help = Some(help);
+        self
+    }
+
+    /// Creates an error pointing just past the end of a token
+    /// Used when something is missing after it
+    pub fn error_after(message: String, token: &Token) -> Diagnostic {
+        let mut diagnostic = Diagnostic::error(message, token);
+        let end = token.column + token.width;
+        diagnostic.columns = end..end + 1;
+        diagnostic
+    }
+
+    /// Sorts diagnostics by where they appear in the source
+    /// Problems in code expanded from a macro are placed at the line which called it
+    pub fn sort(diagnostics: &mut [Diagnostic]) {
+        Diagnostic::sort_by_file(diagnostics, |_| 0);
+    }
+
+    /// Sorts diagnostics like `sort`, putting files in the order given by `rank`
+    pub fn sort_by_file<F: Fn(&str) -> usize>(diagnostics: &mut [Diagnostic], rank: F) {
+        diagnostics.sort_by_key(|diagnostic| {
+            let origin = diagnostic.origin();
+            (rank(&origin.file), origin.line, origin.columns.start, diagnostic.line, diagnostic.columns.start)
+        });
+    }
+
+    /// The outermost macro call which led to the problem, or the diagnostic itself if it is not in a macro
+    fn origin(&self) -> &Diagnostic {
+        match self.expansions {
+            0 => self,
+            count => &self.notes[count as usize - 1]
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    /// Formats the diagnostic with the offending line of `source` underlined
+    ///
+    /// ```text
+    /// error: expected a register but saw "5"
+    ///  --> program.asm:3:5
+    ///   |
+    /// 3 | MOV 5 reg_1
+    ///   |     ^
+    /// ```
+    pub fn render(&self, source: &str) -> String {
+        self.render_with(&|_| Some(source))
+    }
+
+    /// Formats the diagnostic like `render`, looking up the source of each file it or its notes point at
+    pub fn render_with<'a>(&self, source: &dyn Fn(&str) -> Option<&'a str>) -> String {
+        let line_number = self.line.to_string();
+        let gutter = " ".repeat(line_number.len());
+        let mut output = format!("{}\n{}--> {}:{}:{}\n",
+            self.severity_and_message(), gutter, self.file, self.line, self.columns.start);
+
+        let line = source(&self.file).and_then(|source| source.lines().nth(self.line.saturating_sub(1) as usize));
+        if let Some(line) = line {
+            // Keep tabs from the source so the carets line up with the text above them
+            let padding: String = line.chars()
+                .chain(::std::iter::repeat(' '))
+                .take(self.columns.start.saturating_sub(1) as usize)
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            let carets = "^".repeat((self.columns.end - self.columns.start).max(1) as usize);
+            output.push_str(&format!("{} |\n", gutter));
+            output.push_str(&format!("{} | {}\n", line_number, line));
+            output.push_str(&format!("{} | {}{}\n", gutter, padding, carets));
+        }
+        if let Some(help) = &self.help {
+            output.push_str(&format!("{} = help: {}\n", gutter, help));
+        }
+        for note in &self.notes {
+            output.push_str(&note.render_with(source));
+        }
+        output
+    }
+
+    fn severity_and_message(&self) -> String {
+        format!("{}: {}", self.severity, self.message)
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({}:{}:{})", self.severity_and_message(), self.file, self.line, self.columns.start)
+    }
+}
+
+/// Finds the candidate closest to `name`, ignoring case
+/// Only names within a few edits are suggested so unrelated labels are not offered
+pub fn closest_match<'a, I>(name: &str, candidates: I) -> Option<&'a str>
+    where I: IntoIterator<Item = &'a str>
+{
+    let name = name.to_lowercase();
+    let max_distance = (name.chars().count() / 3).max(1);
+    candidates.into_iter()
+        .map(|candidate| (edit_distance(&name, &candidate.to_lowercase()), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+/// Number of insertions, deletions, substitutions and swaps of adjacent characters
+/// needed to turn one string into the other
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    distances[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            let mut distance = (distances[i - 1][j] + 1)
+                .min(distances[i][j - 1] + 1)
+                .min(distances[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(distances[i - 2][j - 2] + 1);
+            }
+            distances[i][j] = distance;
+        }
+    }
+    distances[a.len()][b.len()]
+}
@@ -0,0 +1,421 @@
+use byteorder::{ReadBytesExt, LittleEndian};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::io::Cursor;
+use assembler::{ByteCodeData, Command, CommandType, Origin, Section, Symbols, INSTRUCTION_SIZE, MAX_SPACE};
+use image::{Image, ImageSection};
+use tokenizer::*;
+
+/// Longest string emitted by a single `.ascii` directive
+const MAX_ASCII_LENGTH: usize = 32;
+
+/// Shortest run of zeros or text worth emitting as one directive instead of single bytes
+const MIN_RUN_LENGTH: usize = 4;
+
+/// Most raw bytes shown in the comment after a data directive
+const MAX_RAW_BYTES: usize = 8;
+
+/// The reason an image cannot be written out as assembly which assembles to the same bytes
+#[derive(Clone, Debug, PartialEq)]
+pub enum DisasmError {
+    /// Two sections share some memory, holding the address the second one starts at
+    Overlap(u32),
+
+    /// The gap before the section at `address` is more than one `.space` can fill
+    GapTooLarge { address: u32, gap: u64 }
+}
+
+impl fmt::Display for DisasmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DisasmError::Overlap(address) => write!(f, "the section at {:#x} overlaps the one before it", address),
+            DisasmError::GapTooLarge { address, gap } =>
+                write!(f, "the section at {:#x} is {} bytes after the one before it, which is more than {}", address, gap, MAX_SPACE)
+        }
+    }
+}
+
+/// Decodes the instruction at the start of `bytes`
+/// Only succeeds if assembling the instruction again would produce exactly the same bytes
+pub fn decode(bytes: &[u8]) -> Option<Command> {
+    if bytes.len() < INSTRUCTION_SIZE as usize {
+        return None;
+    }
+    let mut reader = Cursor::new(bytes);
+    let code = [
+        reader.read_i32::<LittleEndian>().unwrap(),
+        reader.read_i32::<LittleEndian>().unwrap(),
+        reader.read_i32::<LittleEndian>().unwrap(),
+    ];
+
+    let command = Command::from_bytecode(&code);
+    match command.cmd_type {
+        CommandType::Instruction(_) if command.is_complete() => {},
+        _ => return None
+    };
+    match command.to_bytecode(&Symbols::default(), 0) {
+        Ok(ByteCodeData::Instruction(encoded)) if encoded == code => Some(command),
+        _ => None
+    }
+}
+
+/// Which operand of an instruction holds an address, counting from 1
+fn address_operand(instruction: &InstructionType) -> Option<usize> {
+    use tokenizer::InstructionType::*;
+    match instruction {
+        Call |
+        Jump => Some(1),
+
+        CompareZeroJump |
+        GreaterThanZeroJump |
+        LessThanZeroJump |
+        LoadAddress |
+        LoadByte |
+        LoadByteSigned |
+        LoadHalf |
+        LoadHalfSigned |
+        LoadWord |
+        NonZeroJump |
+        StoreByte |
+        StoreHalf |
+        StoreWord => Some(2),
+
+        _ => None
+    }
+}
+
+/// Formats a decoded instruction as assembly, naming any address which has a label
+pub fn format_instruction(command: &Command, labels: &HashMap<i32, String>) -> String {
+    let instruction = match &command.cmd_type {
+        CommandType::Instruction(instruction) => instruction,
+        _ => return String::new()
+    };
+    let address = address_operand(instruction);
+    let mut text = TokenType::Instruction(instruction.clone()).to_string();
+    for (index, operand) in [&command.operand1, &command.operand2].iter().enumerate() {
+        match &operand.token_type {
+            TokenType::None => {},
+            TokenType::Integer(value) if address == Some(index + 1) && labels.contains_key(value) =>
+                text.push_str(&format!(" {}", labels[value])),
+            token_type => text.push_str(&format!(" {}", token_type))
+        };
+    }
+    text
+}
+
+/// A single line of the listing
+struct Line {
+    address: i32,
+    text: String,
+    raw: String
+}
+
+/// Formats the bytes of an instruction as three little endian words
+fn raw_words(bytes: &[u8]) -> String {
+    let mut reader = Cursor::new(bytes);
+    let words: Vec<String> = (0..3)
+        .map(|_| format!("{:08X}", reader.read_u32::<LittleEndian>().unwrap()))
+        .collect();
+    words.join(" ")
+}
+
+fn raw_bytes(bytes: &[u8]) -> String {
+    let mut raw: Vec<String> = bytes.iter()
+        .take(MAX_RAW_BYTES)
+        .map(|byte| format!("{:02X}", byte))
+        .collect();
+    if bytes.len() > MAX_RAW_BYTES {
+        raw.push("...".to_string());
+    }
+    raw.join(" ")
+}
+
+fn is_text(byte: u8) -> bool {
+    matches!(byte, b' '..=b'~' | b'\n' | b'\t')
+}
+
+/// Turns bytes which are not instructions into data directives
+/// Runs of zeros become `.space` and runs of text become `.ascii`
+fn data_lines(address: i32, bytes: &[u8], lines: &mut Vec<Line>) {
+    let mut i = 0;
+    while i < bytes.len() {
+        let rest = &bytes[i..];
+        let zeros = rest.iter().take_while(|byte| **byte == 0).count();
+        let text = rest.iter().take(MAX_ASCII_LENGTH).take_while(|byte| is_text(**byte)).count();
+        let (length, text) = if zeros >= MIN_RUN_LENGTH {
+            (zeros, format!(".space {}", zeros))
+        } else if text >= MIN_RUN_LENGTH {
+            (text, format!(".ascii {}", TokenType::String(rest[..text].to_vec())))
+        } else {
+            (1, format!(".byte {}", rest[0]))
+        };
+        lines.push(Line {
+            address: address + i as i32,
+            text,
+            raw: raw_bytes(&rest[..length])
+        });
+        i += length;
+    }
+}
+
+/// Splits a section into instructions and data
+/// Nothing may span one of the `boundaries`, so each of them starts a new line
+fn section_lines(address: i32, section: &[u8], boundaries: &BTreeSet<i32>, labels: &HashMap<i32, String>) -> Vec<Line> {
+    let mut lines = vec![];
+    let mut data_start = 0;
+    let mut i = 0;
+    while i < section.len() {
+        let offset = address + i as i32;
+        if boundaries.contains(&offset) && data_start < i {
+            data_lines(address + data_start as i32, &section[data_start..i], &mut lines);
+            data_start = i;
+        }
+
+        let end = offset + INSTRUCTION_SIZE;
+        let crosses_boundary = boundaries.range(offset + 1..end).next().is_some();
+        let command = if crosses_boundary { None } else { decode(&section[i..]) };
+        match command {
+            Some(command) => {
+                if data_start < i {
+                    data_lines(address + data_start as i32, &section[data_start..i], &mut lines);
+                }
+                let size = INSTRUCTION_SIZE as usize;
+                lines.push(Line {
+                    address: offset,
+                    text: format_instruction(&command, labels),
+                    raw: raw_words(&section[i..i + size])
+                });
+                i += size;
+                data_start = i;
+            },
+            None => i += 1
+        }
+    }
+    if data_start < section.len() {
+        data_lines(address + data_start as i32, &section[data_start..], &mut lines);
+    }
+    lines
+}
+
+/// Picks a label name which is not already in use
+fn unused_name(name: &str, labels: &HashMap<i32, String>) -> String {
+    let taken = |candidate: &str| labels.values().any(|label| label == candidate);
+    if !taken(name) {
+        return name.to_string();
+    }
+    (1..).map(|n| format!("{}_{}", name, n)).find(|candidate| !taken(candidate)).unwrap()
+}
+
+/// The text sections of an image in order, followed by its data sections
+/// Empty sections are left out, since they make no difference to memory
+fn sections_in_order(image: &Image) -> Vec<&ImageSection> {
+    let mut sections: Vec<_> = image.sections.iter().filter(|section| !section.data.is_empty()).collect();
+    sections.sort_by_key(|section| (section.kind == Section::Data, section.address));
+    sections
+}
+
+/// Addresses the disassembly of `image` must be assembled for to give the same bytes
+/// The data section is only given an address if it does not follow straight on from the text section
+pub fn origin(image: &Image) -> Origin {
+    let sections = sections_in_order(image);
+    let text: Vec<_> = sections.iter().filter(|section| section.kind == Section::Text).collect();
+    let text_start = text.first().map_or(0, |section| section.address);
+    let text_end = text.last().map_or(u64::from(text_start), |section| u64::from(section.address) + section.data.len() as u64);
+    let data = sections.iter()
+        .find(|section| section.kind == Section::Data)
+        .map(|section| section.address)
+        .filter(|address| u64::from(*address) != text_end);
+    Origin { text: text_start, data }
+}
+
+/// Converts an image back into assembly which assembles to the same bytes
+///
+/// Each line ends with a comment holding its address and the raw bytes it was decoded from.
+/// Label names come from the symbol table when the image has one.
+/// Bytes in the text section which do not form an instruction are written as data.
+/// An image placed anywhere but the default addresses starts with a comment giving the `--code-base` and
+/// `--data-base` to assemble it with, as returned by `origin`.
+///
+/// Fails if sections overlap or are too far apart to be separated with `.space`
+pub fn disassemble(image: &Image) -> Result<String, DisasmError> {
+    let mut by_address = sections_in_order(image);
+    by_address.sort_by_key(|section| section.address);
+    for pair in by_address.windows(2) {
+        if u64::from(pair[1].address) < u64::from(pair[0].address) + pair[0].data.len() as u64 {
+            return Err(DisasmError::Overlap(pair[1].address));
+        }
+    }
+    let end = by_address.last().map_or(0, |section| (section.address as usize + section.data.len()) as i32);
+
+    // Only one label can be attached to each command, and a label needs a command to attach to
+    let mut labels: HashMap<i32, String> = HashMap::new();
+    if let Some(symbols) = &image.symbols {
+        for symbol in symbols {
+            let address = symbol.address as i32;
+            if (0..end).contains(&address) {
+                labels.entry(address).or_insert_with(|| symbol.name.to_string());
+            }
+        }
+    }
+    let entry_point = image.entry_point as i32;
+    if (0..end).contains(&entry_point) && !labels.contains_key(&entry_point) {
+        let name = unused_name("_start", &labels);
+        labels.insert(entry_point, name);
+    }
+    let boundaries: BTreeSet<i32> = labels.keys().cloned().collect();
+
+    let mut output = String::new();
+    let origin = origin(image);
+    if origin != Origin::default() {
+        let data = origin.data.map_or(String::new(), |data| format!(" --data-base {:#x}", data));
+        output.push_str(&format!("# Assemble with --code-base {:#x}{}\n", origin.text, data));
+    }
+
+    let mut lines = vec![];
+    if let Some(entry) = labels.get(&entry_point) {
+        lines.push(Line { address: -1, text: format!(".entry {}", entry), raw: String::new() });
+    }
+
+    // Each kind of section starts at its origin, so only the gaps between sections of the same kind need filling
+    let mut offset: Option<(Section, u64)> = None;
+    for section in sections_in_order(image) {
+        let address = section.address;
+        match offset {
+            Some((kind, end)) if kind == section.kind => {
+                let gap = u64::from(address) - end;
+                if gap > MAX_SPACE as u64 {
+                    return Err(DisasmError::GapTooLarge { address, gap });
+                }
+                if gap > 0 {
+                    lines.push(Line {
+                        address: end as i32,
+                        text: format!(".space {}", gap),
+                        raw: raw_bytes(&vec![0; gap as usize])
+                    });
+                }
+            },
+            _ if section.kind == Section::Data => {
+                lines.push(Line { address: -1, text: TokenType::Directive(DirectiveType::Data).to_string(), raw: String::new() });
+            },
+            _ => {}
+        };
+        lines.extend(section_lines(address as i32, &section.data, &boundaries, &labels));
+        offset = Some((section.kind, u64::from(address) + section.data.len() as u64));
+    }
+
+    let label_width = labels.values().map(|label| label.len()).max().unwrap_or(0);
+    let text_width = lines.iter().filter(|line| !line.raw.is_empty()).map(|line| line.text.len()).max().unwrap_or(0);
+    for line in lines {
+        let label = if line.address < 0 { "" } else { labels.get(&line.address).map_or("", |label| label.as_str()) };
+        let listing = if line.raw.is_empty() {
+            format!("{:label_width$} {}", label, line.text, label_width = label_width)
+        } else {
+            format!("{:label_width$} {:text_width$}  # {}: {}",
+                label, line.text, line.address, line.raw, label_width = label_width, text_width = text_width)
+        };
+        output.push_str(listing.trim_end());
+        output.push('\n');
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+    use syntax;
+    use test_support;
+    use super::*;
+
+    fn assemble(path: &str) -> Image {
+        test_support::assemble(path).image
+    }
+
+    /// Assembles the disassembly of `image` at the addresses it asks for, which must have no errors
+    fn reassemble(image: &Image, name: &str) -> Image {
+        let listing = disassemble(image).unwrap();
+        let path = env::temp_dir().join(format!("disasm-{}-{}.asm", process::id(), name));
+        let path = path.to_str().unwrap();
+        fs::write(path, &listing).unwrap();
+        let diagnostics = syntax::verify(Tokenizer::new(path));
+        assert!(!diagnostics.iter().any(|d| d.is_error()), "{}:\n{}\n{:?}", name, listing, diagnostics);
+        let reassembled = test_support::assemble_at(path, origin(image)).image;
+        fs::remove_file(path).unwrap();
+        reassembled
+    }
+
+    #[test]
+    fn round_trips_every_program() {
+        let mut paths: Vec<_> = fs::read_dir("test_files").unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|extension| extension == "asm"))
+            .collect();
+        paths.sort();
+        let origins = [
+            Origin::default(),
+            Origin { text: 0x100, data: Some(0) },
+            Origin { text: 0x1000, data: Some(0x8000) },
+        ];
+        for path in paths {
+            let path = path.to_str().unwrap();
+            if !syntax::verify(Tokenizer::new(path)).iter().all(|d| !d.is_error()) {
+                continue;
+            }
+            for origin in &origins {
+                let name = format!("{}-{:x}", path.replace('/', "-"), origin.text);
+                let image = test_support::assemble_at(path, *origin).image;
+                let reassembled = reassemble(&image, &name);
+                assert_eq!(reassembled.to_memory(), image.to_memory(), "{} at {:?}", path, origin);
+                assert_eq!(reassembled.entry_point, image.entry_point, "{} at {:?}", path, origin);
+
+                let mut stripped = image.clone();
+                stripped.symbols = None;
+                let reassembled = reassemble(&stripped, &format!("{}-stripped", name));
+                assert_eq!(reassembled.to_memory(), image.to_memory(), "{} at {:?} without symbols", path, origin);
+                assert_eq!(reassembled.entry_point, image.entry_point, "{} at {:?} without symbols", path, origin);
+            }
+        }
+    }
+
+    #[test]
+    fn gives_the_addresses_to_assemble_at() {
+        let image = test_support::assemble_at("test_files/31-sections.asm", Origin { text: 0x100, data: Some(0) }).image;
+        assert!(disassemble(&image).unwrap().starts_with("# Assemble with --code-base 0x100 --data-base 0x0\n"));
+
+        // Data which follows straight on from the text section needs no address of its own
+        let image = test_support::assemble_at("test_files/31-sections.asm", Origin { text: 0x100, data: None }).image;
+        assert_eq!(origin(&image), Origin { text: 0x100, data: None });
+    }
+
+    #[test]
+    fn rejects_sections_which_cannot_be_written_out() {
+        let section = |address: u32, size: usize| ImageSection { kind: Section::Text, address, data: vec![0; size] };
+        let image = Image { entry_point: 0, sections: vec![section(0, 8), section(4, 8)], symbols: None };
+        assert_eq!(disassemble(&image), Err(DisasmError::Overlap(4)));
+
+        let image = Image { entry_point: 0, sections: vec![section(0, 8), section(0x7FFF_FFF0, 8)], symbols: None };
+        assert_eq!(disassemble(&image), Err(DisasmError::GapTooLarge { address: 0x7FFF_FFF0, gap: 0x7FFF_FFE8 }));
+    }
+
+    #[test]
+    fn uses_symbol_names() {
+        let listing = disassemble(&assemble("test_files/27-call-stack.asm")).unwrap();
+        assert!(listing.contains("CALL Double"), "{}", listing);
+    }
+
+    #[test]
+    fn lists_addresses_and_raw_words() {
+        let listing = disassemble(&assemble("test_files/02-end.asm")).unwrap();
+        assert_eq!(listing, "       .entry _start\n_start END  # 0: 00000002 00000000 00000000\n");
+    }
+
+    #[test]
+    fn rejects_non_canonical_instructions() {
+        // END with a stray operand would not assemble back to the same bytes
+        let mut bytes = vec![2, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0];
+        assert!(decode(&bytes).is_none());
+        bytes[4] = 0;
+        assert!(decode(&bytes).is_some());
+    }
+}
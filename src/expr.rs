@@ -0,0 +1,351 @@
+use std::fmt;
+use tokenizer::parse_integer;
+
+/// Characters which end a name or number inside an expression
+const OPERATORS: &str = "+-*/<>&|^~()";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BinaryOp {
+    Multiply,
+    Divide,
+    Add,
+    Subtract,
+    ShiftLeft,
+    ShiftRight,
+    And,
+    Xor,
+    Or
+}
+
+impl BinaryOp {
+    /// Operators with a higher precedence are applied first, as in C
+    fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Multiply | BinaryOp::Divide => 5,
+            BinaryOp::Add | BinaryOp::Subtract => 4,
+            BinaryOp::ShiftLeft | BinaryOp::ShiftRight => 3,
+            BinaryOp::And => 2,
+            BinaryOp::Xor => 1,
+            BinaryOp::Or => 0
+        }
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            BinaryOp::Multiply => "*",
+            BinaryOp::Divide => "/",
+            BinaryOp::Add => "+",
+            BinaryOp::Subtract => "-",
+            BinaryOp::ShiftLeft => "<<",
+            BinaryOp::ShiftRight => ">>",
+            BinaryOp::And => "&",
+            BinaryOp::Xor => "^",
+            BinaryOp::Or => "|"
+        }
+    }
+}
+
+/// An integer worked out by the assembler, such as `Buffer+4` or `(SIZE*2)<<8`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Integer(i32),
+
+    /// A label or constant
+    Symbol(String),
+
+    /// `.`, the address the expression is assembled at
+    Here,
+    Negate(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>)
+}
+
+/// The reason an expression has no value
+#[derive(Clone, Debug, PartialEq)]
+pub enum EvalError {
+    Undefined(String),
+
+    /// The symbol is a label, whose address is not known yet
+    Label(String),
+
+    /// A constant refers back to itself
+    Cycle(String),
+
+    /// A constant has no value because of a problem in its own definition
+    BadConstant(String),
+    DivideByZero,
+    BadShift(i32),
+
+    /// `.` was used where there is no current address
+    NoAddress
+}
+
+/// Separates the name of a constant from the number of the `.set` which gave it its value
+/// `#` starts a comment, so it can never be part of a name written in a program
+const VERSION_SEPARATOR: char = '#';
+
+/// The name for the value a constant is given by its `version`th `.set`
+pub fn versioned_name(name: &str, version: usize) -> String {
+    format!("{}{}{}", name, VERSION_SEPARATOR, version)
+}
+
+/// A name as it is written in the program, without the version given to it by `.set`
+pub fn source_name(name: &str) -> &str {
+    name.split(VERSION_SEPARATOR).next().unwrap_or(name)
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EvalError::Undefined(name) => write!(f, "undefined label `{}`", source_name(name)),
+            EvalError::Label(name) => write!(f, "the address of `{}` is not known until the program is laid out", source_name(name)),
+            EvalError::Cycle(name) => write!(f, "constant `{}` is defined in terms of itself", source_name(name)),
+            EvalError::BadConstant(name) => write!(f, "constant `{}` has no value", source_name(name)),
+            EvalError::DivideByZero => write!(f, "division by zero"),
+            EvalError::BadShift(amount) => write!(f, "cannot shift by {} bits", amount),
+            EvalError::NoAddress => write!(f, "`.` cannot be used here")
+        }
+    }
+}
+
+impl Expr {
+    /// Works out the value of the expression when assembled at `here`
+    /// `symbol` gives the value of each label or constant
+    pub fn evaluate(&self, here: Option<i32>, symbol: &mut dyn FnMut(&str) -> Result<i32, EvalError>) -> Result<i32, EvalError> {
+        match self {
+            Expr::Integer(value) => Ok(*value),
+            Expr::Symbol(name) => symbol(name),
+            Expr::Here => here.ok_or(EvalError::NoAddress),
+            Expr::Negate(operand) => Ok(operand.evaluate(here, symbol)?.wrapping_neg()),
+            Expr::Not(operand) => Ok(!operand.evaluate(here, symbol)?),
+            Expr::Binary(op, left, right) => {
+                let left = left.evaluate(here, symbol)?;
+                let right = right.evaluate(here, symbol)?;
+                match op {
+                    BinaryOp::Multiply => Ok(left.wrapping_mul(right)),
+                    BinaryOp::Divide if right == 0 => Err(EvalError::DivideByZero),
+                    BinaryOp::Divide => Ok(left.wrapping_div(right)),
+                    BinaryOp::Add => Ok(left.wrapping_add(right)),
+                    BinaryOp::Subtract => Ok(left.wrapping_sub(right)),
+                    BinaryOp::ShiftLeft | BinaryOp::ShiftRight if !(0..32).contains(&right) => Err(EvalError::BadShift(right)),
+                    BinaryOp::ShiftLeft => Ok(left << right),
+                    BinaryOp::ShiftRight => Ok(left >> right),
+                    BinaryOp::And => Ok(left & right),
+                    BinaryOp::Xor => Ok(left ^ right),
+                    BinaryOp::Or => Ok(left | right)
+                }
+            }
+        }
+    }
+
+    /// Every label or constant the expression refers to
+    pub fn symbols(&self) -> Vec<&str> {
+        match self {
+            Expr::Integer(_) | Expr::Here => vec![],
+            Expr::Symbol(name) => vec![name],
+            Expr::Negate(operand) | Expr::Not(operand) => operand.symbols(),
+            Expr::Binary(_, left, right) => {
+                let mut symbols = left.symbols();
+                symbols.extend(right.symbols());
+                symbols
+            }
+        }
+    }
+
+    /// Copies the expression, replacing each symbol `replace` gives a new expression for
+    pub fn replace_symbols(&self, replace: &dyn Fn(&str) -> Option<Expr>) -> Expr {
+        match self {
+            Expr::Symbol(name) => replace(name).unwrap_or_else(|| self.clone()),
+            Expr::Negate(operand) => Expr::Negate(Box::new(operand.replace_symbols(replace))),
+            Expr::Not(operand) => Expr::Not(Box::new(operand.replace_symbols(replace))),
+            Expr::Binary(op, left, right) =>
+                Expr::Binary(*op, Box::new(left.replace_symbols(replace)), Box::new(right.replace_symbols(replace))),
+            Expr::Integer(_) | Expr::Here => self.clone()
+        }
+    }
+
+    /// Precedence of the outermost operator, used to decide where parentheses are needed
+    fn precedence(&self) -> u8 {
+        match self {
+            Expr::Binary(op, _, _) => op.precedence(),
+            _ => u8::MAX
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Integer(value) => write!(f, "{}", value),
+            Expr::Symbol(name) => write!(f, "{}", source_name(name)),
+            Expr::Here => write!(f, "."),
+            Expr::Negate(operand) | Expr::Not(operand) => {
+                let sign = if matches!(self, Expr::Negate(_)) { "-" } else { "~" };
+                if operand.precedence() == u8::MAX {
+                    write!(f, "{}{}", sign, operand)
+                } else {
+                    write!(f, "{}({})", sign, operand)
+                }
+            },
+            Expr::Binary(op, left, right) => {
+                // Operators of the same precedence group to the left, so only the right side needs them bracketed
+                if left.precedence() < op.precedence() {
+                    write!(f, "({})", left)?;
+                } else {
+                    write!(f, "{}", left)?;
+                }
+                write!(f, "{}", op.symbol())?;
+                if right.precedence() <= op.precedence() {
+                    write!(f, "({})", right)
+                } else {
+                    write!(f, "{}", right)
+                }
+            }
+        }
+    }
+}
+
+/// Parses an expression such as `Buffer+4`, `(SIZE * 2) << 8` or `.-Start`
+/// Returns `None` if the text is not a valid expression
+pub fn parse(text: &str) -> Option<Expr> {
+    let mut parser = Parser { text, position: 0 };
+    let expr = parser.binary(0)?;
+    parser.skip_whitespace();
+    if parser.position == text.len() {
+        Some(expr)
+    } else {
+        None
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    position: usize
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.text[self.position..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.position += rest.len() - rest.trim_start().len();
+    }
+
+    /// Consumes `token` if the text continues with it
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        if self.rest().starts_with(token) {
+            self.position += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn operator(&mut self) -> Option<BinaryOp> {
+        self.skip_whitespace();
+        let rest = self.rest();
+        let (op, length) = match rest.chars().next()? {
+            '*' => (BinaryOp::Multiply, 1),
+            '/' => (BinaryOp::Divide, 1),
+            '+' => (BinaryOp::Add, 1),
+            '-' => (BinaryOp::Subtract, 1),
+            '<' if rest.starts_with("<<") => (BinaryOp::ShiftLeft, 2),
+            '>' if rest.starts_with(">>") => (BinaryOp::ShiftRight, 2),
+            '&' => (BinaryOp::And, 1),
+            '^' => (BinaryOp::Xor, 1),
+            '|' => (BinaryOp::Or, 1),
+            _ => return None
+        };
+        self.position += length;
+        Some(op)
+    }
+
+    /// Parses operators of at least `precedence`, climbing to tighter operators as it goes
+    fn binary(&mut self, precedence: u8) -> Option<Expr> {
+        let mut left = self.unary()?;
+        loop {
+            let start = self.position;
+            let op = match self.operator() {
+                Some(op) if op.precedence() >= precedence => op,
+                _ => {
+                    self.position = start;
+                    return Some(left);
+                }
+            };
+            let right = self.binary(op.precedence() + 1)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+    }
+
+    fn unary(&mut self) -> Option<Expr> {
+        if self.eat("-") {
+            return Some(Expr::Negate(Box::new(self.unary()?)));
+        }
+        if self.eat("~") {
+            return Some(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("(") {
+            let expr = self.binary(0)?;
+            return if self.eat(")") { Some(expr) } else { None };
+        }
+
+        self.skip_whitespace();
+        let rest = self.rest();
+        let length = rest.find(|c: char| c.is_whitespace() || OPERATORS.contains(c)).unwrap_or(rest.len());
+        let word = &rest[..length];
+        self.position += length;
+        if word == "." {
+            Some(Expr::Here)
+        } else if word.starts_with(|c: char| c.is_ascii_digit()) {
+            parse_integer(word).map(Expr::Integer)
+        } else if word.is_empty() {
+            None
+        } else {
+            Some(Expr::Symbol(word.to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::*;
+
+    fn evaluate(text: &str) -> Result<i32, EvalError> {
+        let symbols: HashMap<&str, i32> = [("Buffer", 100), ("SIZE", 16)].iter().cloned().collect();
+        parse(text).unwrap().evaluate(Some(40), &mut |name| {
+            symbols.get(name).cloned().ok_or_else(|| EvalError::Undefined(name.to_string()))
+        })
+    }
+
+    #[test]
+    fn follows_precedence_and_parentheses() {
+        assert_eq!(evaluate("Buffer+SIZE*2"), Ok(132));
+        assert_eq!(evaluate("(Buffer + SIZE) * 2"), Ok(232));
+        assert_eq!(evaluate("1<<4|3&~1"), Ok(18));
+        assert_eq!(evaluate("0xF0>>4^-1"), Ok(!15));
+        assert_eq!(evaluate("10-3-2"), Ok(5));
+        assert_eq!(evaluate(".-Buffer/4"), Ok(15));
+    }
+
+    #[test]
+    fn reports_bad_values() {
+        assert_eq!(evaluate("SIZE/0"), Err(EvalError::DivideByZero));
+        assert_eq!(evaluate("1<<32"), Err(EvalError::BadShift(32)));
+        assert_eq!(evaluate("Bufer+1"), Err(EvalError::Undefined("Bufer".to_string())));
+        assert_eq!(parse("(SIZE*2"), None);
+        assert_eq!(parse("SIZE+"), None);
+        assert_eq!(parse("2SIZE"), None);
+    }
+
+    #[test]
+    fn displays_only_the_parentheses_needed() {
+        for text in &["Buffer+SIZE*2", "(Buffer+SIZE)*2", "10-(3-2)", "-(SIZE+1)", ".-Start"] {
+            assert_eq!(parse(text).unwrap().to_string(), *text);
+        }
+        assert_eq!(parse("( 1 + 2 ) + 3").unwrap().to_string(), "1+2+3");
+    }
+}
@@ -0,0 +1,272 @@
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use assembler::Section;
+
+/// Bytes every image starts with
+pub const MAGIC: [u8; 4] = *b"RVM\0";
+
+/// Version of the image format written by this assembler
+pub const VERSION: u16 = 1;
+
+/// Set in the header flags when a symbol table follows the sections
+const FLAG_SYMBOLS: u16 = 1;
+
+/// A block of bytes loaded at a fixed address
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImageSection {
+    pub kind: Section,
+    pub address: u32,
+    pub data: Vec<u8>
+}
+
+/// A label name and the address it refers to
+#[derive(Clone, Debug, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub address: u32
+}
+
+/// An assembled program which can be saved and run without its source
+///
+/// All values are little endian:
+///
+/// ```text
+/// magic        4 bytes  "RVM\0"
+/// version      u16
+/// flags        u16      bit 0 set when a symbol table is present
+/// entry point  u32
+/// sections     u32      count, then for each section:
+///   kind       u8       0 = text, 1 = data
+///   address    u32
+///   length     u32
+///   data       length bytes
+/// symbols      u32      count, only present if flagged, then for each symbol:
+///   length     u16
+///   name       length bytes of UTF-8
+///   address    u32
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Image {
+    pub entry_point: u32,
+    pub sections: Vec<ImageSection>,
+    pub symbols: Option<Vec<Symbol>>
+}
+
+#[derive(Debug)]
+pub enum ImageError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    UnknownSection(u8),
+    BadSymbolName,
+
+    /// A symbol name is too long for its length to be written, holding the length in bytes
+    SymbolNameTooLong(usize)
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::Io(err) => write!(f, "{}", err),
+            ImageError::BadMagic => write!(f, "not a program image"),
+            ImageError::UnsupportedVersion(version) =>
+                write!(f, "unsupported image version {} (expected {})", version, VERSION),
+            ImageError::UnknownSection(kind) => write!(f, "unknown section kind {}", kind),
+            ImageError::BadSymbolName => write!(f, "symbol name is not valid UTF-8"),
+            ImageError::SymbolNameTooLong(length) =>
+                write!(f, "a symbol name is {} bytes long but at most {} bytes fit in an image", length, u16::MAX)
+        }
+    }
+}
+
+impl From<io::Error> for ImageError {
+    fn from(err: io::Error) -> ImageError {
+        ImageError::Io(err)
+    }
+}
+
+impl Image {
+    /// Creates an image holding `sections` with a symbol for each label
+    pub fn new(entry_point: u32, sections: Vec<ImageSection>, labels: &HashMap<String, i32>) -> Image {
+        let mut symbols: Vec<Symbol> = labels.iter()
+            .map(|(name, address)| Symbol { name: name.to_string(), address: *address as u32 })
+            .collect();
+        symbols.sort_by(|a, b| (a.address, &a.name).cmp(&(b.address, &b.name)));
+
+        Image {
+            entry_point,
+            sections,
+            symbols: Some(symbols)
+        }
+    }
+
+    /// Whether `bytes` look like the start of an image
+    pub fn is_image(bytes: &[u8]) -> bool {
+        bytes.starts_with(&MAGIC)
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<(), ImageError> {
+        writer.write_all(&MAGIC)?;
+        writer.write_u16::<LittleEndian>(VERSION)?;
+        writer.write_u16::<LittleEndian>(if self.symbols.is_some() { FLAG_SYMBOLS } else { 0 })?;
+        writer.write_u32::<LittleEndian>(self.entry_point)?;
+
+        writer.write_u32::<LittleEndian>(self.sections.len() as u32)?;
+        for section in &self.sections {
+            writer.write_u8(match section.kind {
+                Section::Text => 0,
+                Section::Data => 1
+            })?;
+            writer.write_u32::<LittleEndian>(section.address)?;
+            writer.write_u32::<LittleEndian>(section.data.len() as u32)?;
+            writer.write_all(&section.data)?;
+        }
+
+        if let Some(symbols) = &self.symbols {
+            writer.write_u32::<LittleEndian>(symbols.len() as u32)?;
+            for symbol in symbols {
+                let length = u16::try_from(symbol.name.len()).map_err(|_| ImageError::SymbolNameTooLong(symbol.name.len()))?;
+                writer.write_u16::<LittleEndian>(length)?;
+                writer.write_all(symbol.name.as_bytes())?;
+                writer.write_u32::<LittleEndian>(symbol.address)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> Result<Image, ImageError> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(ImageError::BadMagic);
+        }
+        let version = reader.read_u16::<LittleEndian>()?;
+        if version != VERSION {
+            return Err(ImageError::UnsupportedVersion(version));
+        }
+        let flags = reader.read_u16::<LittleEndian>()?;
+        let entry_point = reader.read_u32::<LittleEndian>()?;
+
+        let section_count = reader.read_u32::<LittleEndian>()?;
+        let mut sections = vec![];
+        for _ in 0..section_count {
+            let kind = match reader.read_u8()? {
+                0 => Section::Text,
+                1 => Section::Data,
+                kind => return Err(ImageError::UnknownSection(kind))
+            };
+            let address = reader.read_u32::<LittleEndian>()?;
+            let length = reader.read_u32::<LittleEndian>()?;
+            let mut data = vec![];
+            reader.take(u64::from(length)).read_to_end(&mut data)?;
+            if data.len() != length as usize {
+                return Err(ImageError::Io(io::ErrorKind::UnexpectedEof.into()));
+            }
+            sections.push(ImageSection { kind, address, data });
+        }
+
+        let symbols = if flags & FLAG_SYMBOLS != 0 {
+            let symbol_count = reader.read_u32::<LittleEndian>()?;
+            let mut symbols = vec![];
+            for _ in 0..symbol_count {
+                let mut name = vec![0; reader.read_u16::<LittleEndian>()? as usize];
+                reader.read_exact(&mut name)?;
+                let name = String::from_utf8(name).map_err(|_| ImageError::BadSymbolName)?;
+                let address = reader.read_u32::<LittleEndian>()?;
+                symbols.push(Symbol { name, address });
+            }
+            Some(symbols)
+        } else {
+            None
+        };
+
+        Ok(Image {
+            entry_point,
+            sections,
+            symbols
+        })
+    }
+
+    /// Lays every section out at its address, ready to be copied into memory
+    pub fn to_memory(&self) -> Vec<u8> {
+        let mut memory = vec![];
+        for section in &self.sections {
+            let start = section.address as usize;
+            let end = start + section.data.len();
+            if memory.len() < end {
+                memory.resize(end, 0);
+            }
+            memory[start..end].copy_from_slice(&section.data);
+        }
+        memory
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use test_support;
+    use super::*;
+
+    fn assemble(path: &str) -> Image {
+        test_support::assemble(path).image
+    }
+
+    #[test]
+    fn round_trips_through_bytes() {
+        let image = assemble("test_files/31-sections.asm");
+        let mut bytes = vec![];
+        image.write(&mut bytes).unwrap();
+        assert!(Image::is_image(&bytes));
+        assert_eq!(Image::read(&mut Cursor::new(bytes)).unwrap(), image);
+    }
+
+    #[test]
+    fn memory_holds_every_section() {
+        let image = assemble("test_files/31-sections.asm");
+        let memory = image.to_memory();
+        for section in &image.sections {
+            let start = section.address as usize;
+            assert_eq!(&memory[start..start + section.data.len()], &section.data[..]);
+        }
+        assert_eq!(memory.len(), image.sections.iter().map(|section| section.data.len()).sum::<usize>());
+    }
+
+    #[test]
+    fn data_follows_text() {
+        let image = assemble("test_files/31-sections.asm");
+        let kinds: Vec<Section> = image.sections.iter().map(|section| section.kind).collect();
+        assert_eq!(kinds, vec![Section::Text, Section::Data]);
+        assert_eq!(image.sections[1].address as usize, image.sections[0].data.len());
+    }
+
+    #[test]
+    fn rejects_symbol_names_too_long_to_write() {
+        let mut image = assemble("test_files/02-end.asm");
+        image.symbols = Some(vec![Symbol { name: "a".repeat(70_000), address: 0 }]);
+        match image.write(&mut vec![]) {
+            Err(ImageError::SymbolNameTooLong(70_000)) => {},
+            result => panic!("expected a name too long error, got {:?}", result)
+        }
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        match Image::read(&mut Cursor::new(b"MOV reg_1 reg_2".to_vec())) {
+            Err(ImageError::BadMagic) => {},
+            result => panic!("expected a bad magic error, got {:?}", result)
+        }
+
+        let mut bytes = vec![];
+        assemble("test_files/02-end.asm").write(&mut bytes).unwrap();
+        bytes[4] = 99;
+        match Image::read(&mut Cursor::new(bytes)) {
+            Err(ImageError::UnsupportedVersion(99)) => {},
+            result => panic!("expected an unsupported version error, got {:?}", result)
+        }
+    }
+}
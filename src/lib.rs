@@ -0,0 +1,130 @@
+//! A simple RISC-based virtual machine along with its assembler, disassembler and debugger
+//!
+//! Source is assembled into an `Image`, which a `VM` loads into memory and runs.
+//! The VM reads input from and writes output to a `Device`, such as the console or in-memory buffers.
+//!
+//! ```
+//! use vm::{ExitStatus, Origin, Streams, VmConfig, VM};
+//!
+//! let source = "+ reg_1 21\n+ reg_1 reg_1\nMOV io reg_1\nOUT\nEND\n";
+//! let assembly = vm::assemble("double.asm", source, Origin::default()).unwrap();
+//!
+//! let device = Streams::new(std::io::empty(), Vec::new());
+//! let mut machine = VM::from_image(&assembly.image, &VmConfig::default(), device).unwrap();
+//! assert_eq!(machine.run(assembly.image.entry_point as usize), Ok(ExitStatus::Halted));
+//! assert_eq!(machine.device().output(), b"42");
+//! ```
+
+extern crate byteorder;
+
+pub mod assembler;
+pub mod debugger;
+pub mod device;
+pub mod diagnostic;
+pub mod expr;
+pub mod disasm;
+pub mod image;
+pub mod macros;
+pub mod memory;
+pub mod syntax;
+pub mod tokenizer;
+pub mod source;
+pub mod trace;
+pub mod vm;
+
+#[cfg(test)]
+mod test_support;
+
+use std::fs::File;
+use std::io::BufReader;
+use tokenizer::Token;
+
+pub use assembler::{Assembler, Origin};
+pub use debugger::{Debugger, SourceMap};
+pub use device::{Console, Device, Streams};
+pub use diagnostic::{Diagnostic, Severity};
+pub use image::{Image, ImageError, ImageSection, Symbol};
+pub use memory::{LoadError, MemoryLayout, VmConfig};
+pub use source::SourceFiles;
+pub use tokenizer::Tokenizer;
+pub use trace::{TraceFormat, Tracer};
+pub use vm::{CostTable, ExitStatus, StepResult, TrapKind, VmTrap, MAX_MEMORY, VM};
+
+/// A program assembled from source
+pub struct Assembly {
+    pub image: Image,
+
+    /// Maps each instruction back to the line of source it came from
+    pub source_map: SourceMap,
+
+    /// Problems which did not stop the program from being assembled
+    pub warnings: Vec<Diagnostic>
+}
+
+/// Checks source code, returning every problem found
+/// `name` is used as the file name in diagnostics, and any files it includes are read relative to it
+pub fn check(name: &str, source: &str) -> Vec<Diagnostic> {
+    check_files(&mut single_file(name, source))
+}
+
+/// Checks a program made of several source files, returning every problem found
+/// Files pulled in by `.include` are added to `files`, so diagnostics pointing at them can be rendered
+pub fn check_files(files: &mut SourceFiles) -> Vec<Diagnostic> {
+    expand_and_verify(files).1
+}
+
+/// Checks and assembles source code for the addresses in `origin`
+/// `name` is used as the file name in diagnostics, and any files it includes are read relative to it
+///
+/// Fails with every diagnostic found, warnings included, if any of them is an error
+pub fn assemble(name: &str, source: &str, origin: Origin) -> Result<Assembly, Vec<Diagnostic>> {
+    assemble_files(&mut single_file(name, source), origin)
+}
+
+/// Checks and assembles a program made of several source files, which share one set of labels
+/// The files are assembled in order as if they were one, and files pulled in by `.include` are added to `files`
+pub fn assemble_files(files: &mut SourceFiles, origin: Origin) -> Result<Assembly, Vec<Diagnostic>> {
+    let (tokens, mut diagnostics) = expand_and_verify(files);
+    if diagnostics.iter().any(|diagnostic| diagnostic.is_error()) {
+        return Err(diagnostics);
+    }
+
+    let (label_table, commands) = Assembler::to_commands_at(tokens, origin);
+    let source_map = SourceMap::new(files, Assembler::source_lines(&commands));
+    match Assembler::to_image(label_table, commands) {
+        Ok(image) => Ok(Assembly { image, source_map, warnings: diagnostics }),
+        Err(errors) => {
+            diagnostics.extend(errors);
+            files.sort(&mut diagnostics);
+            Err(diagnostics)
+        }
+    }
+}
+
+fn single_file(name: &str, source: &str) -> SourceFiles {
+    let mut files = SourceFiles::new();
+    files.add(name, source);
+    files
+}
+
+/// Tokenizes source files, resolving includes and expanding macros
+/// Returns the tokens along with every problem found in them, including values which are wrong wherever the program is placed
+fn expand_and_verify(files: &mut SourceFiles) -> (Vec<Token>, Vec<Diagnostic>) {
+    let (tokens, mut diagnostics) = files.tokenize();
+    let (tokens, expansion_diagnostics) = macros::expand(tokens);
+    diagnostics.extend(expansion_diagnostics);
+    diagnostics.extend(syntax::verify(tokens.iter().cloned()));
+
+    // Values can only be worked out once every line makes sense
+    if !diagnostics.iter().any(|diagnostic| diagnostic.is_error()) {
+        diagnostics.extend(Assembler::check_values(tokens.iter().cloned()));
+    }
+    files.sort(&mut diagnostics);
+    (tokens, diagnostics)
+}
+
+/// Reads an image saved by `Image::write`
+pub fn read_image(path: &str) -> Result<Image, ImageError> {
+    let file = File::open(path)?;
+    Image::read(&mut BufReader::new(file))
+}
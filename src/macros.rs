@@ -0,0 +1,468 @@
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use diagnostic::Diagnostic;
+use expr::Expr;
+use syntax::split_lines;
+use tokenizer::*;
+
+/// Most macro calls which may be nested inside each other
+/// Deeper nesting almost always means a macro calls itself
+pub const MAX_DEPTH: u32 = 64;
+
+/// A single call of a macro
+#[derive(Clone, Debug, PartialEq)]
+pub struct Expansion {
+    /// Counts up from 1 with each call, so copies of the same macro body can be told apart
+    pub id: u32,
+    pub name: String,
+
+    /// The token naming the macro where it was called
+    pub call: Token
+}
+
+struct Macro {
+    /// The token naming the macro in its definition
+    name: Token,
+    parameters: Vec<String>,
+
+    /// Labels defined in the body, which are renamed in each expansion so they do not clash
+    locals: HashSet<String>,
+    body: Vec<Vec<Token>>
+}
+
+struct Expander {
+    macros: HashMap<String, Macro>,
+    used: HashSet<String>,
+
+    /// Macros which have gone past `MAX_DEPTH`, whose further calls are dropped rather than reported again
+    runaway: HashSet<String>,
+    expansions: u32,
+    diagnostics: Vec<Diagnostic>
+}
+
+/// Removes macro definitions from a program and replaces every call with the body of the macro
+/// Returns the expanded tokens along with any problems found in the definitions or calls
+///
+/// A macro is defined between `.macro NAME PARAMETER...` and `.endm` lines, and called by using its name
+/// in place of an instruction. Tokens copied from the body point at the call through `Token::expansion`.
+pub fn expand<I: IntoIterator<Item = Token>>(tokens: I) -> (Vec<Token>, Vec<Diagnostic>) {
+    let mut expander = Expander {
+        macros: HashMap::new(),
+        used: HashSet::new(),
+        runaway: HashSet::new(),
+        expansions: 0,
+        diagnostics: vec![]
+    };
+    let lines = expander.collect_macros(split_lines(tokens));
+    expander.find_locals();
+
+    let mut output = vec![];
+    for line in lines {
+        expander.expand_line(line, 0, &mut output);
+    }
+
+    // Macros starting with an underscore are allowed to go unused, like labels
+    for (name, definition) in &expander.macros {
+        if !expander.used.contains(name) && !name.starts_with('_') {
+            expander.diagnostics.push(Diagnostic::warning(format!("macro `{}` is never used", name), &definition.name));
+        }
+    }
+    Diagnostic::sort(&mut expander.diagnostics);
+    (output, expander.diagnostics)
+}
+
+fn is_directive(token: &Token, directive: DirectiveType) -> bool {
+    token.token_type == TokenType::Directive(directive)
+}
+
+impl Expander {
+    /// Takes out every macro definition, returning the lines left over
+    fn collect_macros(&mut self, lines: Vec<Vec<Token>>) -> Vec<Vec<Token>> {
+        let mut remaining = vec![];
+        let mut definition: Option<(Token, Macro)> = None;
+        for line in lines {
+            if is_directive(&line[0], DirectiveType::Macro) {
+                if let Some((start, _)) = &definition {
+                    self.diagnostics.push(
+                        Diagnostic::error("macro definitions cannot be nested".to_string(), &line[0])
+                            .with_note("the enclosing definition starts here".to_string(), start)
+                    );
+                    continue;
+                }
+                definition = self.parse_header(&line).map(|header| (line[0].clone(), header));
+                if definition.is_none() {
+                    // Skip the body of a definition which could not be read
+                    definition = Some((line[0].clone(), Macro {
+                        name: line[0].clone(),
+                        parameters: vec![],
+                        locals: HashSet::new(),
+                        body: vec![]
+                    }));
+                }
+            } else if is_directive(&line[0], DirectiveType::EndMacro) {
+                if let Some(extra) = line.get(1) {
+                    self.diagnostics.push(Diagnostic::error(
+                        format!("expected the end of the line but saw \"{}\"", extra.token_type),
+                        extra
+                    ));
+                }
+                match definition.take() {
+                    Some((_, definition)) => self.define(definition),
+                    None => self.diagnostics.push(Diagnostic::error(
+                        "`.endm` without a matching `.macro`".to_string(),
+                        &line[0]
+                    ))
+                };
+            } else {
+                match &mut definition {
+                    Some((_, definition)) => definition.body.push(line),
+                    None => remaining.push(line)
+                };
+            }
+        }
+
+        if let Some((start, _)) = definition {
+            self.diagnostics.push(
+                Diagnostic::error("macro definition is missing `.endm`".to_string(), &start)
+            );
+        }
+        remaining
+    }
+
+    /// Reads the name and parameters following `.macro`
+    fn parse_header(&mut self, line: &[Token]) -> Option<Macro> {
+        let name = match line.get(1) {
+            Some(token) if matches!(token.token_type, TokenType::Label(_)) => token,
+            Some(token) => {
+                self.diagnostics.push(Diagnostic::error(
+                    format!("expected a macro name but saw \"{}\"", token.token_type),
+                    token
+                ));
+                return None;
+            },
+            None => {
+                self.diagnostics.push(Diagnostic::error_after("expected a macro name after \".macro\"".to_string(), &line[0]));
+                return None;
+            }
+        };
+
+        let mut parameters: Vec<String> = vec![];
+        for token in &line[2..] {
+            match &token.token_type {
+                TokenType::Label(parameter) if parameters.contains(parameter) => {
+                    self.diagnostics.push(Diagnostic::error(format!("parameter `{}` is already defined", parameter), token));
+                    return None;
+                },
+                TokenType::Label(parameter) => parameters.push(parameter.to_string()),
+                other => {
+                    self.diagnostics.push(Diagnostic::error(
+                        format!("expected a parameter name but saw \"{}\"", other),
+                        token
+                    ));
+                    return None;
+                }
+            };
+        }
+
+        Some(Macro {
+            name: name.clone(),
+            parameters,
+            locals: HashSet::new(),
+            body: vec![]
+        })
+    }
+
+    fn define(&mut self, definition: Macro) {
+        let name = match &definition.name.token_type {
+            TokenType::Label(name) => name.to_string(),
+            // The header could not be read and has already been reported
+            _ => return
+        };
+        if let Some(first) = self.macros.get(&name) {
+            self.diagnostics.push(
+                Diagnostic::error(format!("macro `{}` is already defined", name), &definition.name)
+                    .with_note(format!("`{}` was first defined here", name), &first.name)
+            );
+            return;
+        }
+        self.macros.insert(name, definition);
+    }
+
+    /// Finds the labels defined in the body of each macro
+    /// Done once every macro is known, since a line starting with the name of a macro calls it instead
+    fn find_locals(&mut self) {
+        let names: HashSet<String> = self.macros.keys().cloned().collect();
+        for definition in self.macros.values_mut() {
+            for line in &definition.body {
+                if let TokenType::Label(label) = &line[0].token_type {
+                    if !definition.parameters.contains(label) && !names.contains(label) {
+                        definition.locals.insert(label.to_string());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Finds the token calling a macro, which replaces the instruction after an optional label
+    fn find_call(&self, line: &[Token]) -> Option<usize> {
+        let is_call = |token: &Token| match &token.token_type {
+            TokenType::Label(name) => self.macros.contains_key(name),
+            _ => false
+        };
+        if is_call(&line[0]) {
+            Some(0)
+        } else if line.len() > 1 && matches!(line[0].token_type, TokenType::Label(_)) && is_call(&line[1]) {
+            Some(1)
+        } else {
+            None
+        }
+    }
+
+    /// Writes out a line, expanding it first if it calls a macro
+    fn expand_line(&mut self, line: Vec<Token>, depth: u32, output: &mut Vec<Token>) {
+        let index = match self.find_call(&line) {
+            Some(index) => index,
+            None => {
+                output.extend(line);
+                return;
+            }
+        };
+
+        // A label before the call sits on a line of its own so it refers to the first line of the body
+        let call = &line[index];
+        if index == 1 {
+            output.push(line[0].clone());
+        }
+
+        let name = call.token_type.to_string();
+        self.used.insert(name.clone());
+
+        // A macro calling itself more than once would otherwise be expanded a number of times
+        // which doubles with every level, so it is only reported the first time it goes too deep
+        if self.runaway.contains(&name) {
+            return;
+        }
+        match self.copy_body(&name, call, &line[index + 1..], depth) {
+            Ok(body) => {
+                self.expansions += 1;
+                for copy in body {
+                    self.expand_line(copy, depth + 1, output);
+                }
+            },
+            Err(diagnostic) => {
+                if depth >= MAX_DEPTH {
+                    self.runaway.insert(name);
+                }
+                self.diagnostics.push(diagnostic);
+            }
+        };
+    }
+
+    /// Makes a copy of the body of a macro for a call with `arguments`
+    fn copy_body(&self, name: &str, call: &Token, arguments: &[Token], depth: u32) -> Result<Vec<Vec<Token>>, Diagnostic> {
+        let definition = &self.macros[name];
+        if arguments.len() != definition.parameters.len() {
+            return Err(Diagnostic::error(
+                format!("macro `{}` takes {} argument{} but {} {} given",
+                    name,
+                    definition.parameters.len(),
+                    if definition.parameters.len() == 1 { "" } else { "s" },
+                    arguments.len(),
+                    if arguments.len() == 1 { "was" } else { "were" }),
+                call
+            ).with_note(format!("`{}` is defined here", name), &definition.name));
+        }
+        if depth >= MAX_DEPTH {
+            return Err(Diagnostic::error(
+                format!("macro `{}` is nested more than {} calls deep", name, MAX_DEPTH),
+                call
+            ).with_help("check whether the macro calls itself".to_string()));
+        }
+
+        let expansion = Rc::new(Expansion {
+            id: self.expansions + 1,
+            name: name.to_string(),
+            call: call.clone()
+        });
+        Ok(definition.body.iter()
+            .map(|line| line.iter().map(|token| substitute(token, definition, arguments, &expansion)).collect())
+            .collect())
+    }
+}
+
+/// Copies a token from the body of a macro into an expansion
+/// Parameters are replaced with their argument and local labels are given a name unique to the expansion
+fn substitute(token: &Token, definition: &Macro, arguments: &[Token], expansion: &Rc<Expansion>) -> Token {
+    let mut copy = token.clone();
+    copy.expansion = Some(expansion.clone());
+    if let TokenType::Label(label) = &token.token_type {
+        if let Some(index) = definition.parameters.iter().position(|parameter| parameter == label) {
+            copy.token_type = arguments[index].token_type.clone();
+        } else if definition.locals.contains(label) {
+            copy.token_type = TokenType::Label(format!("{}@{}", label, expansion.id));
+        }
+    }
+
+    // Names inside an expression, or the offset of a register address, are replaced the same way
+    // An argument which cannot be part of an expression, such as a register, is left to be reported as undefined
+    let replace = |expr: &Expr| {
+        expr.replace_symbols(&|name| {
+            if let Some(index) = definition.parameters.iter().position(|parameter| parameter == name) {
+                match &arguments[index].token_type {
+                    TokenType::Integer(value) => Some(Expr::Integer(*value)),
+                    TokenType::Character(c) => Some(Expr::Integer(*c as i32)),
+                    TokenType::Label(label) => Some(Expr::Symbol(label.to_string())),
+                    TokenType::Expression(argument) => Some(argument.clone()),
+                    _ => None
+                }
+            } else if definition.locals.contains(name) {
+                Some(Expr::Symbol(format!("{}@{}", name, expansion.id)))
+            } else {
+                None
+            }
+        })
+    };
+    match &token.token_type {
+        TokenType::Expression(expr) => copy.token_type = TokenType::Expression(replace(expr)),
+        TokenType::Indirect(register, offset) => copy.token_type = TokenType::Indirect(register.clone(), replace(offset)),
+        _ => {}
+    }
+    copy
+}
+
+#[cfg(test)]
+mod tests {
+    use diagnostic::Severity;
+    use super::*;
+
+    fn expand_source(source: &str) -> (Vec<Token>, Vec<Diagnostic>) {
+        expand(Tokenizer::from_source("macros.asm", source).tokenize().unwrap())
+    }
+
+    fn words(tokens: &[Token]) -> Vec<String> {
+        tokens.iter().map(|token| token.token_type.to_string()).collect()
+    }
+
+    #[test]
+    fn substitutes_arguments_and_renames_local_labels() {
+        let (tokens, diagnostics) = expand_source(concat!(
+            ".macro Countdown counter\n",
+            "Loop + counter -1\n",
+            "!0 counter Loop\n",
+            ".endm\n",
+            "Countdown reg_1\n",
+            "Again Countdown reg_2\n",
+        ));
+        assert_eq!(diagnostics, vec![]);
+        assert_eq!(words(&tokens), vec![
+            "Loop@1", "+", "reg_1", "-1", "!0", "reg_1", "Loop@1",
+            "Again", "Loop@2", "+", "reg_2", "-1", "!0", "reg_2", "Loop@2"
+        ]);
+
+        // Tokens keep pointing at the body, and remember the call they came from
+        assert_eq!((tokens[2].line_number, tokens[2].column), (2, 8));
+        assert_eq!(tokens[2].expansion.as_ref().unwrap().call.line_number, 5);
+    }
+
+    #[test]
+    fn substitutes_arguments_inside_expressions() {
+        let (tokens, diagnostics) = expand_source(concat!(
+            ".macro Offset base scale\n",
+            "Here LDA reg_1 base+scale*4\n",
+            "JMP Here+12\n",
+            ".endm\n",
+            "Offset Table 1+1\n",
+        ));
+        assert_eq!(diagnostics, vec![]);
+
+        // Arguments are substituted whole, so `1+1` is multiplied as one value
+        assert_eq!(words(&tokens), vec!["Here@1", "LDA", "reg_1", "Table+(1+1)*4", "JMP", "Here@1+12"]);
+    }
+
+    #[test]
+    fn expands_nested_calls() {
+        let (tokens, diagnostics) = expand_source(concat!(
+            "Twice\n",
+            "END\n",
+            ".macro Newline\n",
+            "MOV io reg_0\n",
+            "+ io 10\n",
+            "ASCO\n",
+            ".endm\n",
+            ".macro Twice\n",
+            "Newline\n",
+            "Newline\n",
+            ".endm\n",
+        ));
+        assert_eq!(diagnostics, vec![]);
+        assert_eq!(words(&tokens).join(" "), "MOV io reg_0 + io 10 ASCO MOV io reg_0 + io 10 ASCO END");
+
+        // Each line of every expansion stays separate even though they share a line of the body
+        assert_eq!(split_lines(tokens.clone()).len(), 7);
+        let inner = tokens[0].expansion.as_ref().unwrap();
+        assert_eq!((inner.name.as_str(), inner.call.line_number), ("Newline", 9));
+        assert_eq!(inner.call.expansion.as_ref().unwrap().call.line_number, 1);
+    }
+
+    #[test]
+    fn reports_bad_definitions_and_calls() {
+        let (_, diagnostics) = expand_source(concat!(
+            ".macro Add2 a b\n",
+            "+ a b\n",
+            ".endm\n",
+            ".macro Add2\n",
+            ".endm\n",
+            "Add2 reg_1\n",
+            ".endm\n",
+            ".macro Forever\n",
+            "Forever\n",
+            ".endm\n",
+            "Forever\n",
+            ".macro 5\n",
+        ));
+        let found: Vec<(Severity, u32, &str)> = diagnostics.iter()
+            .map(|d| (d.severity, d.line, d.message.as_str()))
+            .collect();
+        assert_eq!(found, vec![
+            (Severity::Error, 4, "macro `Add2` is already defined"),
+            (Severity::Error, 6, "macro `Add2` takes 2 arguments but 1 was given"),
+            (Severity::Error, 7, "`.endm` without a matching `.macro`"),
+            (Severity::Error, 9, "macro `Forever` is nested more than 64 calls deep"),
+            (Severity::Error, 12, "macro definition is missing `.endm`"),
+            (Severity::Error, 12, "expected a macro name but saw \"5\""),
+        ]);
+    }
+
+    #[test]
+    fn macros_calling_themselves_twice_are_reported_once() {
+        let (tokens, diagnostics) = expand_source(".macro F\nF\nF\n.endm\nF\nF\nEND\n");
+        let found: Vec<(u32, &str)> = diagnostics.iter().map(|d| (d.line, d.message.as_str())).collect();
+        assert_eq!(found, vec![(2, "macro `F` is nested more than 64 calls deep")]);
+        assert_eq!(words(&tokens), vec!["END"]);
+    }
+
+    #[test]
+    fn diagnostics_point_at_the_body_and_the_call() {
+        let source = concat!(
+            ".macro Store value\n",
+            "STW value Result\n",
+            ".endm\n",
+            "Store 5\n",
+        );
+        let (tokens, mut diagnostics) = expand_source(source);
+        diagnostics.extend(::syntax::verify(tokens));
+        let error = diagnostics.iter().find(|d| d.is_error()).unwrap();
+        assert_eq!(error.render(source), concat!(
+            "error: expected a register but saw \"5\"\n",
+            " --> macros.asm:2:5\n",
+            "  |\n",
+            "2 | STW value Result\n",
+            "  |     ^^^^^\n",
+            "note: in this expansion of macro `Store`\n",
+            " --> macros.asm:4:1\n",
+            "  |\n",
+            "4 | Store 5\n",
+            "  | ^^^^^\n"
+        ));
+    }
+}
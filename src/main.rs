@@ -1,29 +1,234 @@
-extern crate byteorder;
+extern crate vm;
 
-mod assembler;
-mod syntax;
-mod tokenizer;
-mod vm;
+mod cli;
 
 use std::env;
-use assembler::Assembler;
-use tokenizer::Tokenizer;
-use vm::VM;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::process;
+use cli::{RunOptions, Subcommand};
+use vm::{device, disasm, trace};
+use vm::{Debugger, Diagnostic, ExitStatus, Image, ImageError, Origin, SourceFiles, SourceMap, Streams, Tracer, VmConfig, VM};
 
-fn main() {
-    let mut args = env::args();
-    if let Some(filename) = args.nth(1) {
-        let tokenizer = Tokenizer::new(&filename);
-        if let Some(err) = syntax::verify(tokenizer) {
-            println!("{}", err);
-            return;
+/// The program stopped because of a trap
+const EXIT_TRAP: i32 = 1;
+
+/// The command line arguments were invalid
+const EXIT_USAGE: i32 = 2;
+
+/// The program has errors and could not be assembled or loaded
+const EXIT_INVALID_PROGRAM: i32 = 3;
+
+/// A file could not be read or written
+const EXIT_IO: i32 = 4;
+
+/// The program was stopped after reaching the step limit
+const EXIT_STEP_LIMIT: i32 = 5;
+
+/// The program was stopped because it ran out of budget
+const EXIT_BUDGET: i32 = 6;
+
+/// Prints an error and exits with `code`
+fn fail(code: i32, message: String) -> ! {
+    eprintln!("error: {}", message);
+    process::exit(code);
+}
+
+fn read_source(filename: &str) -> String {
+    fs::read_to_string(filename)
+        .unwrap_or_else(|err| fail(EXIT_IO, format!("failed to read {}: {}", filename, err)))
+}
+
+fn print_diagnostics(diagnostics: &[Diagnostic], files: &SourceFiles) {
+    for diagnostic in diagnostics {
+        eprintln!("{}", files.render(diagnostic));
+    }
+}
+
+/// Checks source files, printing any diagnostics
+/// Returns whether they are free of errors
+fn check(files: &mut SourceFiles) -> bool {
+    let diagnostics = vm::check_files(files);
+    print_diagnostics(&diagnostics, files);
+    !diagnostics.iter().any(|diagnostic| diagnostic.is_error())
+}
+
+/// Checks and assembles source files for the addresses in `origin`, printing any diagnostics
+/// Exits the process if the files cannot be assembled
+fn assemble(files: &mut SourceFiles, origin: Origin) -> (Image, SourceMap) {
+    match vm::assemble_files(files, origin) {
+        Ok(assembly) => {
+            print_diagnostics(&assembly.warnings, files);
+            (assembly.image, assembly.source_map)
+        },
+        Err(diagnostics) => {
+            print_diagnostics(&diagnostics, files);
+            process::exit(EXIT_INVALID_PROGRAM);
         }
-        let tokenizer = Tokenizer::new(&filename);
-        let (label_table, commands) = Assembler::to_commands(tokenizer);
-        let (start, bytecode) = Assembler::to_bytecode(label_table, commands);
-        let mut vm = VM::new(bytecode);
-        vm.run(start);
+    }
+}
+
+/// Loads a program from either an assembled image or assembly source files
+/// Source is assembled for the addresses in `origin`, and is only available when the program was assembled from it
+fn load(filenames: &[String], origin: Origin) -> (Image, Option<SourceMap>) {
+    let mut files = SourceFiles::new();
+    for filename in filenames {
+        let bytes = fs::read(filename)
+            .unwrap_or_else(|err| fail(EXIT_IO, format!("failed to read {}: {}", filename, err)));
+        if Image::is_image(&bytes) {
+            if filenames.len() > 1 {
+                fail(EXIT_USAGE, format!("{} is a program image, which cannot be combined with other files", filename));
+            }
+            let image = Image::read(&mut &bytes[..])
+                .unwrap_or_else(|err| fail(EXIT_INVALID_PROGRAM, format!("failed to load {}: {}", filename, err)));
+            return (image, None);
+        }
+
+        match String::from_utf8(bytes) {
+            Ok(source) => files.add(filename, source),
+            Err(_) => fail(EXIT_INVALID_PROGRAM, format!("{} is neither a program image nor UTF-8 source", filename))
+        };
+    }
+
+    let (image, source_map) = assemble(&mut files, origin);
+    (image, Some(source_map))
+}
+
+/// Reads source files named on the command line
+fn read_sources(filenames: &[String]) -> SourceFiles {
+    let mut files = SourceFiles::new();
+    for filename in filenames {
+        files.add(filename, read_source(filename));
+    }
+    files
+}
+
+/// Program I/O chosen on the command line
+type CliDevice = Streams<Box<dyn BufRead>, Box<dyn Write>>;
+
+/// Creates a VM for an image, applying the memory, step limit and I/O options
+fn create_vm(image: &Image, options: &RunOptions) -> VM<CliDevice> {
+    let config = VmConfig {
+        memory_size: options.memory_size.unwrap_or(vm::MAX_MEMORY),
+        layout: options.layout.placed_for(image)
+    };
+
+    // Check the program fits before creating any output files
+    if let Err(err) = config.layout.stack(image, config.memory_size) {
+        fail(EXIT_INVALID_PROGRAM, err.to_string());
+    }
+
+    let input: Box<dyn BufRead> = match &options.input {
+        Some(input) => {
+            let file = File::open(input)
+                .unwrap_or_else(|err| fail(EXIT_IO, format!("failed to open {}: {}", input, err)));
+            Box::new(BufReader::new(file))
+        },
+        None => Box::new(device::console_input())
+    };
+    let output: Box<dyn Write> = match &options.output {
+        Some(output) => {
+            let file = File::create(output)
+                .unwrap_or_else(|err| fail(EXIT_IO, format!("failed to create {}: {}", output, err)));
+            Box::new(BufWriter::new(file))
+        },
+        None => Box::new(io::stdout())
+    };
+
+    let mut vm = VM::from_image(image, &config, Streams::new(input, output))
+        .unwrap_or_else(|err| fail(EXIT_INVALID_PROGRAM, err.to_string()));
+    vm.set_step_limit(options.max_steps);
+    vm.set_budget(options.budget, options.costs.clone());
+    if let Some(trace) = &options.trace {
+        vm.set_tracer(Some(create_tracer(image, trace, options)));
+    }
+    vm
+}
+
+/// Creates a tracer which writes to `path`, or stderr for `-`
+fn create_tracer(image: &Image, path: &str, options: &RunOptions) -> Tracer {
+    let output: Box<dyn Write> = if path == "-" {
+        Box::new(io::stderr())
     } else {
-        panic!("Must provide an input file");
+        let file = File::create(path)
+            .unwrap_or_else(|err| fail(EXIT_IO, format!("failed to create {}: {}", path, err)));
+        Box::new(BufWriter::new(file))
+    };
+
+    let symbols = image.symbols.as_deref().unwrap_or_default();
+    let mut tracer = Tracer::new(options.trace_format, output).with_symbols(symbols);
+    for filter in &options.trace_filters {
+        let range = trace::parse_filter(filter, symbols)
+            .unwrap_or_else(|message| fail(EXIT_USAGE, format!("bad trace filter `{}`: {}", filter, message)));
+        tracer = tracer.with_filter(range);
+    }
+    tracer
+}
+
+/// Exits with 0 on success, or one of the `EXIT_` codes above
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let subcommand = match cli::parse_args(&args) {
+        Ok(subcommand) => subcommand,
+        Err(message) => {
+            eprintln!("error: {}\n\n{}", message, cli::USAGE);
+            process::exit(EXIT_USAGE);
+        }
+    };
+
+    match subcommand {
+        Subcommand::Help => println!("{}", cli::USAGE),
+        Subcommand::Assemble { inputs, output, origin } => {
+            let (image, _) = assemble(&mut read_sources(&inputs), origin);
+            let written = File::create(&output).map_err(ImageError::from).and_then(|file| {
+                let mut writer = BufWriter::new(file);
+                image.write(&mut writer)?;
+                Ok(writer.flush()?)
+            });
+            if let Err(err) = written {
+                fail(EXIT_IO, format!("failed to write {}: {}", output, err));
+            }
+        },
+        Subcommand::Check { inputs } => {
+            if !check(&mut read_sources(&inputs)) {
+                process::exit(EXIT_INVALID_PROGRAM);
+            }
+        },
+        Subcommand::Run { inputs, options } => {
+            let (image, _) = load(&inputs, options.layout.origin());
+            let mut vm = create_vm(&image, &options);
+            let result = vm.run(image.entry_point as usize);
+            if options.stats {
+                eprintln!("executed {} instructions costing {}", vm.instructions_executed(), vm.budget_spent());
+            }
+            match result {
+                Ok(ExitStatus::Halted) => {},
+                Ok(ExitStatus::StepLimitReached) => fail(EXIT_STEP_LIMIT, format!(
+                    "stopped after executing {} instructions", options.max_steps.unwrap_or(0)
+                )),
+                Ok(ExitStatus::BudgetExhausted) => fail(EXIT_BUDGET, format!(
+                    "stopped after spending {} of a budget of {} on {} instructions",
+                    vm.budget_spent(), options.budget.unwrap_or(0), vm.instructions_executed()
+                )),
+                Err(trap) => fail(EXIT_TRAP, trap.to_string())
+            }
+        },
+        Subcommand::Disassemble { input } => {
+            let (image, _) = load(std::slice::from_ref(&input), Origin::default());
+            match disasm::disassemble(&image) {
+                Ok(listing) => print!("{}", listing),
+                Err(err) => fail(EXIT_INVALID_PROGRAM, format!("cannot disassemble {}: {}", input, err))
+            }
+        },
+        Subcommand::Debug { inputs, options } => {
+            let (image, source_map) = load(&inputs, options.layout.origin());
+            let vm = create_vm(&image, &options);
+            let mut debugger = Debugger::new(vm, &image, source_map);
+            if let Err(err) = debugger.run(&mut device::console_input(), &mut io::stdout()) {
+                fail(EXIT_IO, err.to_string());
+            }
+        }
     }
 }
@@ -0,0 +1,231 @@
+use std::fmt;
+use std::ops::Range;
+use assembler::{Origin, Section};
+use image::Image;
+use vm::MAX_MEMORY;
+
+/// Number of bytes reserved for the stack unless the layout says otherwise
+pub const STACK_SIZE: u32 = 1_000_000; // 1MB
+
+/// Where the parts of a program are placed in memory
+#[derive(Clone, Debug, PartialEq)]
+pub struct MemoryLayout {
+    /// Start of the text section
+    pub code_base: u32,
+
+    /// Start of the data section, or right after the text section if not given
+    pub data_base: Option<u32>,
+
+    /// Address the stack grows down from, or the top of memory if not given
+    pub stack_top: Option<u32>,
+
+    /// Most bytes the stack may grow to
+    /// The stack is shrunk if needed so it stays clear of anything below it
+    pub stack_size: u32,
+
+    /// Memory set aside for the program to use as it likes
+    /// Nothing is loaded into it and the stack may not grow into it
+    pub heap: Option<Range<u32>>
+}
+
+impl Default for MemoryLayout {
+    fn default() -> MemoryLayout {
+        MemoryLayout {
+            code_base: 0,
+            data_base: None,
+            stack_top: None,
+            stack_size: STACK_SIZE,
+            heap: None
+        }
+    }
+}
+
+/// How to set up the memory of a VM
+#[derive(Clone, Debug, PartialEq)]
+pub struct VmConfig {
+    pub memory_size: usize,
+    pub layout: MemoryLayout
+}
+
+impl VmConfig {
+    pub fn new(memory_size: usize) -> VmConfig {
+        VmConfig {
+            memory_size,
+            layout: MemoryLayout::default()
+        }
+    }
+}
+
+impl Default for VmConfig {
+    fn default() -> VmConfig {
+        VmConfig::new(MAX_MEMORY)
+    }
+}
+
+/// The reason a program could not be loaded into memory
+#[derive(Clone, Debug, PartialEq)]
+pub enum LoadError {
+    /// There is more memory than an address can refer to
+    MemoryTooLarge(usize),
+
+    /// A region of the layout reaches past the end of memory
+    DoesNotFit { region: &'static str, end: u64, memory_size: usize },
+
+    /// Two regions of the layout share some memory
+    Overlap(&'static str, &'static str)
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::MemoryTooLarge(size) =>
+                write!(f, "{} bytes of memory is more than can be addressed", size),
+            LoadError::DoesNotFit { region, end, memory_size } =>
+                write!(f, "the {} needs {} bytes of memory but only {} are available", region, end, memory_size),
+            LoadError::Overlap(first, second) => write!(f, "the {} overlaps the {}", first, second)
+        }
+    }
+}
+
+impl MemoryLayout {
+    /// Addresses to assemble a program for so it matches this layout
+    pub fn origin(&self) -> Origin {
+        Origin {
+            text: self.code_base,
+            data: self.data_base
+        }
+    }
+
+    /// The layout with its code and data bases moved to wherever the sections of `image` were assembled for
+    /// An image records the address of each section, so it is loaded there whatever the layout says
+    pub fn placed_for(&self, image: &Image) -> MemoryLayout {
+        let mut layout = self.clone();
+        for section in &image.sections {
+            match section.kind {
+                Section::Text => layout.code_base = section.address,
+                Section::Data => layout.data_base = Some(section.address)
+            };
+        }
+        layout
+    }
+
+    /// Checks that `image` fits into `memory_size` bytes laid out like this without anything overlapping
+    /// Returns the range of memory the stack may use
+    pub fn stack(&self, image: &Image, memory_size: usize) -> Result<Range<u32>, LoadError> {
+        if memory_size > i32::MAX as usize {
+            return Err(LoadError::MemoryTooLarge(memory_size));
+        }
+        let memory_end = memory_size as u64;
+
+        let mut regions: Vec<(&'static str, Range<u64>)> = vec![];
+        for section in image.sections.iter().filter(|section| !section.data.is_empty()) {
+            let region = match section.kind {
+                Section::Text => "text section",
+                Section::Data => "data section"
+            };
+            let start = u64::from(section.address);
+            regions.push((region, start..start + section.data.len() as u64));
+        }
+        if let Some(heap) = self.heap.as_ref().filter(|heap| heap.start < heap.end) {
+            regions.push(("heap", u64::from(heap.start)..u64::from(heap.end)));
+        }
+
+        for (index, (region, range)) in regions.iter().enumerate() {
+            if range.end > memory_end {
+                return Err(LoadError::DoesNotFit { region, end: range.end, memory_size });
+            }
+            let overlap = regions[index + 1..].iter()
+                .find(|(_, other)| range.start < other.end && other.start < range.end);
+            if let Some((other, _)) = overlap {
+                return Err(LoadError::Overlap(region, other));
+            }
+        }
+
+        let top = self.stack_top.map_or(memory_end, u64::from);
+        if top > memory_end {
+            return Err(LoadError::DoesNotFit { region: "stack", end: top, memory_size });
+        }
+        let mut bottom = top.saturating_sub(u64::from(self.stack_size));
+        for (region, range) in regions.iter().filter(|(_, range)| range.start < top) {
+            if range.end > top {
+                return Err(LoadError::Overlap(region, "stack"));
+            }
+            bottom = bottom.max(range.end);
+        }
+        Ok(bottom as u32..top as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use device::Streams;
+    use image::ImageSection;
+    use test_support;
+    use vm::{ExitStatus, VM};
+    use super::*;
+
+    fn image(sections: &[(Section, u32, usize)]) -> Image {
+        Image {
+            entry_point: 0,
+            sections: sections.iter()
+                .map(|(kind, address, size)| ImageSection { kind: *kind, address: *address, data: vec![1; *size] })
+                .collect(),
+            symbols: None
+        }
+    }
+
+    #[test]
+    fn stack_sits_at_the_top_of_memory() {
+        let layout = MemoryLayout::default();
+        let program = image(&[(Section::Text, 0, 100)]);
+        assert_eq!(layout.stack(&program, 2_000_000), Ok(1_000_000..2_000_000));
+
+        // The stack shrinks rather than overlapping the program
+        assert_eq!(layout.stack(&program, 1000), Ok(100..1000));
+    }
+
+    #[test]
+    fn layout_places_the_stack_and_heap() {
+        let layout = MemoryLayout {
+            code_base: 0x1000,
+            data_base: Some(0x2000),
+            stack_top: Some(0x8000),
+            stack_size: 0x1000,
+            heap: Some(0x8000..0x10000)
+        };
+        let program = image(&[(Section::Text, 0x1000, 24), (Section::Data, 0x2000, 8)]);
+        assert_eq!(layout.stack(&program, 0x10000), Ok(0x7000..0x8000));
+        assert_eq!(layout.stack(&program, 0x9000), Err(LoadError::DoesNotFit { region: "heap", end: 0x10000, memory_size: 0x9000 }));
+
+        let heap_over_data = MemoryLayout { heap: Some(0x2004..0x3000), ..layout.clone() };
+        assert_eq!(heap_over_data.stack(&program, 0x10000), Err(LoadError::Overlap("data section", "heap")));
+
+        let heap_over_stack = MemoryLayout { heap: Some(0x7800..0x9000), ..layout.clone() };
+        assert_eq!(heap_over_stack.stack(&program, 0x10000), Err(LoadError::Overlap("heap", "stack")));
+    }
+
+    #[test]
+    fn saved_images_run_where_they_were_assembled_for() {
+        let origin = Origin { text: 0x1000, data: Some(0x2000) };
+        let mut bytes = vec![];
+        test_support::assemble_at("test_files/31-sections.asm", origin).image.write(&mut bytes).unwrap();
+        let image = Image::read(&mut &bytes[..]).unwrap();
+
+        // Running the image needs none of the options it was assembled with
+        let layout = MemoryLayout::default().placed_for(&image);
+        assert_eq!(layout.origin(), origin);
+        let config = VmConfig { memory_size: 0x10000, layout };
+        let mut vm = VM::from_image(&image, &config, Streams::new(io::empty(), vec![])).unwrap();
+        assert_eq!(vm.run(image.entry_point as usize), Ok(ExitStatus::Halted));
+        assert_eq!(vm.device().output(), b"42Z");
+    }
+
+    #[test]
+    fn rejects_programs_which_do_not_fit() {
+        let program = image(&[(Section::Text, 0, 100)]);
+        let layout = MemoryLayout::default();
+        assert_eq!(layout.stack(&program, 50), Err(LoadError::DoesNotFit { region: "text section", end: 100, memory_size: 50 }));
+        assert_eq!(layout.stack(&program, usize::MAX), Err(LoadError::MemoryTooLarge(usize::MAX)));
+    }
+}
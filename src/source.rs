@@ -0,0 +1,241 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use diagnostic::Diagnostic;
+use syntax::split_lines;
+use tokenizer::*;
+
+struct SourceFile {
+    name: Rc<str>,
+    text: Rc<str>,
+
+    /// Whether the file was pulled in by `.include` rather than given to the assembly
+    included: bool
+}
+
+/// The source files making up a program, in the order they are assembled
+/// Files pulled in by `.include` are added as they are found, so diagnostics can show their lines too
+#[derive(Default)]
+pub struct SourceFiles {
+    files: Vec<SourceFile>
+}
+
+impl SourceFiles {
+    pub fn new() -> SourceFiles {
+        SourceFiles::default()
+    }
+
+    /// Adds a file to the end of the program
+    /// `name` is used in diagnostics, and includes are resolved relative to it
+    pub fn add<S: Into<String>>(&mut self, name: &str, text: S) {
+        self.files.push(SourceFile {
+            name: Rc::from(name),
+            text: Rc::from(text.into()),
+            included: false
+        });
+    }
+
+    /// The text of a file, if it is part of the program
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.files.iter()
+            .find(|file| &*file.name == name)
+            .map(|file| &*file.text)
+    }
+
+    /// Names of every file, including those pulled in by `.include` during `tokenize`
+    pub fn names(&self) -> Vec<&str> {
+        self.files.iter().map(|file| &*file.name).collect()
+    }
+
+    /// Formats a diagnostic with the lines it points at, whichever file they are in
+    pub fn render(&self, diagnostic: &Diagnostic) -> String {
+        diagnostic.render_with(&|name| self.get(name))
+    }
+
+    /// Sorts diagnostics by where they appear, keeping each file together in the order files were added
+    pub fn sort(&self, diagnostics: &mut [Diagnostic]) {
+        let names = self.names();
+        Diagnostic::sort_by_file(diagnostics, |file| {
+            names.iter().position(|name| *name == file).unwrap_or(names.len())
+        });
+    }
+
+    /// Tokenizes every file added so far in order, replacing each `.include` with the tokens of the file it names
+    /// A file already included earlier in the program is skipped, while a file which ends up including itself is an error
+    pub fn tokenize(&mut self) -> (Vec<Token>, Vec<Diagnostic>) {
+        let top_level: Vec<(Rc<str>, Rc<str>)> = self.files.iter()
+            .filter(|file| !file.included)
+            .map(|file| (file.name.clone(), file.text.clone()))
+            .collect();
+
+        let mut includer = Includer {
+            files: self,
+            seen: HashSet::new(),
+            stack: vec![],
+            tokens: vec![],
+            diagnostics: vec![]
+        };
+        for (name, text) in top_level {
+            let identity = identity(Path::new(&*name));
+            if includer.seen.insert(identity.clone()) {
+                includer.read(&name, &text, identity);
+            }
+        }
+        restore_names(&mut includer.tokens);
+        (includer.tokens, includer.diagnostics)
+    }
+}
+
+/// Identifies a file no matter which path it was reached through
+/// Names which are not on disk, such as source given straight to the assembler, stay as they are
+fn identity(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+struct Includer<'a> {
+    files: &'a mut SourceFiles,
+
+    /// Every file read so far
+    seen: HashSet<PathBuf>,
+
+    /// Files currently being read, each with the name it was included as
+    stack: Vec<(PathBuf, Rc<str>)>,
+    tokens: Vec<Token>,
+    diagnostics: Vec<Diagnostic>
+}
+
+impl<'a> Includer<'a> {
+    fn read(&mut self, name: &str, text: &str, identity: PathBuf) {
+        self.stack.push((identity, Rc::from(name)));
+
+        // Reading from memory cannot fail
+        let tokens = Tokenizer::from_source(name, text).tokenize().unwrap();
+        for line in split_lines(tokens) {
+            if line[0].token_type == TokenType::Directive(DirectiveType::Include) {
+                self.include(&line);
+            } else {
+                self.tokens.extend(line);
+            }
+        }
+        self.stack.pop();
+    }
+
+    /// Reads the file named by an `.include` line in place of the line
+    /// Nothing is read if the file has already been included anywhere in the program
+    fn include(&mut self, line: &[Token]) {
+        let path = match line.get(1).map(|token| &token.token_type) {
+            Some(TokenType::String(bytes)) => String::from_utf8_lossy(bytes).into_owned(),
+            Some(other) => {
+                self.diagnostics.push(Diagnostic::error(format!("expected a file path but saw \"{}\"", other), &line[1]));
+                return;
+            },
+            None => {
+                self.diagnostics.push(Diagnostic::error_after("expected a file path after \".include\"".to_string(), &line[0]));
+                return;
+            }
+        };
+        if let Some(extra) = line.get(2) {
+            self.diagnostics.push(Diagnostic::error(
+                format!("expected the end of the line but saw \"{}\"", extra.token_type),
+                extra
+            ));
+            return;
+        }
+
+        // Paths are relative to the directory of the file doing the including
+        let resolved = Path::new(&*line[0].file).parent().unwrap_or_else(|| Path::new("")).join(&path);
+        let name = resolved.to_string_lossy().into_owned();
+        let identity = identity(&resolved);
+        if let Some(start) = self.stack.iter().position(|(file, _)| *file == identity) {
+            let mut chain: Vec<String> = self.stack[start..].iter().map(|(_, name)| format!("`{}`", name)).collect();
+            chain.push(format!("`{}`", name));
+            self.diagnostics.push(
+                Diagnostic::error(format!("including `{}` here would create a cycle", name), &line[1])
+                    .with_help(format!("each file includes the next: {}", chain.join(" -> ")))
+            );
+            return;
+        }
+        if !self.seen.insert(identity.clone()) {
+            return;
+        }
+
+        match fs::read_to_string(&resolved) {
+            Ok(text) => {
+                self.files.files.push(SourceFile {
+                    name: Rc::from(name.as_str()),
+                    text: Rc::from(text.as_str()),
+                    included: true
+                });
+                self.read(&name, &text, identity);
+            },
+            Err(err) => self.diagnostics.push(Diagnostic::error(format!("cannot read `{}`: {}", name, err), &line[1]))
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use super::*;
+
+    fn tokenize(path: &str) -> (SourceFiles, Vec<Token>, Vec<Diagnostic>) {
+        let mut files = SourceFiles::new();
+        files.add(path, fs::read_to_string(path).unwrap());
+        let (tokens, diagnostics) = files.tokenize();
+        (files, tokens, diagnostics)
+    }
+
+    #[test]
+    fn includes_files_relative_to_the_includer_once() {
+        let (files, tokens, diagnostics) = tokenize("test_files/37-include.asm");
+        assert_eq!(diagnostics, vec![]);
+        assert_eq!(files.names(), vec!["test_files/37-include.asm", "test_files/lib/print.asm", "test_files/lib/macros.asm"]);
+
+        // The tokens of an included file name it, and replace the `.include` line
+        // `print.asm` includes `macros.asm` before any of its own lines
+        let first_included = tokens.iter().find(|token| &*token.file != "test_files/37-include.asm").unwrap();
+        assert_eq!(&*first_included.file, "test_files/lib/macros.asm");
+        assert!(tokens.iter().all(|token| token.token_type != TokenType::Directive(DirectiveType::Include)));
+    }
+
+    #[test]
+    fn reports_cycles_and_missing_files() {
+        let (files, _, diagnostics) = tokenize("test_files/lib/cycle-a.asm");
+
+        // The reason a file is missing is worded by the OS
+        let missing = fs::read_to_string("test_files/lib/missing.asm").unwrap_err();
+        assert_eq!(missing.kind(), io::ErrorKind::NotFound);
+        let cannot_read = format!("cannot read `test_files/lib/missing.asm`: {}", missing);
+        let found: Vec<(&str, u32, &str)> = diagnostics.iter()
+            .map(|d| (d.file.as_str(), d.line, d.message.as_str()))
+            .collect();
+        assert_eq!(found, vec![
+            ("test_files/lib/cycle-b.asm", 2, "including `test_files/lib/cycle-a.asm` here would create a cycle"),
+            ("test_files/lib/cycle-a.asm", 3, &cannot_read),
+        ]);
+        assert_eq!(
+            diagnostics[0].help.as_deref(),
+            Some("each file includes the next: `test_files/lib/cycle-a.asm` -> `test_files/lib/cycle-b.asm` -> `test_files/lib/cycle-a.asm`")
+        );
+
+        // Diagnostics show the line from the file they point at
+        assert!(files.render(&diagnostics[0]).contains("2 | .include \"cycle-a.asm\""));
+    }
+
+    #[test]
+    fn files_share_one_program() {
+        let mut files = SourceFiles::new();
+        files.add("main.asm", "CALL Print\nEND\n");
+        files.add("print.asm", "Print OUT\nRET\n");
+        let (tokens, diagnostics) = files.tokenize();
+        assert_eq!(diagnostics, vec![]);
+        assert_eq!(split_lines(tokens).len(), 4);
+
+        let mut diagnostics = ::syntax::verify(Tokenizer::from_source("print.asm", "JMP Nowhere\n"));
+        diagnostics.extend(::syntax::verify(Tokenizer::from_source("main.asm", "JMP Nowhere\n")));
+        files.sort(&mut diagnostics);
+        let order: Vec<&str> = diagnostics.iter().map(|d| d.file.as_str()).collect();
+        assert_eq!(order, vec!["main.asm", "print.asm"]);
+    }
+}
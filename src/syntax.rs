@@ -1,182 +1,591 @@
+use std::collections::{HashMap, HashSet};
+use assembler::{Section, MAX_SPACE, MIN_INDIRECT_OFFSET, MAX_INDIRECT_OFFSET};
+use diagnostic::{closest_match, Diagnostic};
+use expr::Expr;
 use tokenizer::*;
 
-fn end_of_file() -> Option<String> {
-    Some(format!("Unexpected end of file"))
+fn unexpected(expected: &str, token: &Token) -> Diagnostic {
+    Diagnostic::error(format!("expected {} but saw \"{}\"", expected, token.token_type), token)
 }
 
-fn error_message(expected: &str, token: &Token) -> Option<String> {
-    Some(format!("Line {}: Expected {} but saw \"{}\"", token.line_number, expected, token.token_type))
+fn missing(expected: &str, previous: &Token) -> Diagnostic {
+    Diagnostic::error_after(format!("expected {} after \"{}\"", expected, previous.token_type), previous)
 }
 
-pub fn verify(mut tokens: Tokenizer) -> Option<String> {
-    let mut token = tokens.next();
-    let mut prev_label = false;
-    while token.is_some() {
-        let t = token.unwrap();
-        match &t.token_type {
-            &TokenType::Label(_) => {
-                token = tokens.next();
-                if !prev_label {
-                    prev_label = true;
-                    continue;
+fn is_register(token_type: &TokenType) -> bool {
+    matches!(token_type, TokenType::Register(_))
+}
+
+fn is_label(token_type: &TokenType) -> bool {
+    matches!(token_type, TokenType::Label(_))
+}
+
+/// Labels and constants stand for integers too, as do expressions built from them
+pub fn is_integer(token_type: &TokenType) -> bool {
+    matches!(token_type, TokenType::Integer(_) | TokenType::Label(_) | TokenType::Expression(_))
+}
+
+/// Anything which can be used as an integer can be used as an address
+fn is_address(token_type: &TokenType) -> bool {
+    is_integer(token_type)
+}
+
+/// Whether the token may stand for an integer satisfying `is_valid`
+/// Only literal integers can be checked here, the rest are checked once the program is assembled
+fn is_integer_where(token_type: &TokenType, is_valid: fn(i32) -> bool) -> bool {
+    match token_type {
+        TokenType::Integer(val) => is_valid(*val),
+        other => is_integer(other)
+    }
+}
+
+/// Checks the operand at `index` has the expected kind
+/// If the line ends before the operand, the error points just past the previous token
+fn expect<'a>(
+    command: &Token,
+    operands: &'a [Token],
+    index: usize,
+    expected: &str,
+    is_expected: fn(&TokenType) -> bool
+) -> Result<&'a Token, Diagnostic> {
+    match operands.get(index) {
+        Some(token) if is_expected(&token.token_type) => Ok(token),
+        Some(token) => Err(unexpected(expected, token)),
+        None => {
+            let previous = if index == 0 { command } else { &operands[index - 1] };
+            Err(missing(expected, previous))
+        }
+    }
+}
+
+/// Groups tokens by the line they appear on
+pub fn split_lines<I: IntoIterator<Item = Token>>(tokens: I) -> Vec<Vec<Token>> {
+    let mut lines: Vec<Vec<Token>> = vec![];
+    for token in tokens {
+        let same_line = match lines.last() {
+            Some(line) => line[0].line_number == token.line_number
+                && line[0].file == token.file
+                && line[0].expansion == token.expansion,
+            None => false
+        };
+        if same_line {
+            lines.last_mut().unwrap().push(token);
+        } else {
+            lines.push(vec![token]);
+        }
+    }
+    lines
+}
+
+/// Finds every label and constant definition, reporting any name defined more than once
+/// A constant may be given a new value with `.set` as often as needed, as long as it is never defined another way
+/// Returns the labels and constants separately, with the names of the constants defined by `.set`
+fn collect_labels(lines: &[Vec<Token>], diagnostics: &mut Vec<Diagnostic>) -> (HashMap<String, Token>, HashMap<String, Token>, HashSet<String>) {
+    let mut labels: HashMap<String, Token> = HashMap::new();
+    let mut constants: HashMap<String, Token> = HashMap::new();
+    let mut reassignable: HashSet<String> = HashSet::new();
+    for line in lines {
+        let (kind, token) = match (&line[0].token_type, line.get(1)) {
+            (TokenType::Label(_), _) => ("label", &line[0]),
+            (TokenType::Directive(DirectiveType::Equ), Some(name)) |
+            (TokenType::Directive(DirectiveType::Set), Some(name)) if is_label(&name.token_type) => ("constant", name),
+            _ => continue
+        };
+        let is_set = line[0].token_type == TokenType::Directive(DirectiveType::Set);
+        let name = token.token_type.to_string();
+        match labels.get(&name).or_else(|| constants.get(&name)) {
+            Some(_) if is_set && reassignable.contains(&name) => {},
+            Some(first) => {
+                let diagnostic = Diagnostic::error(format!("{} `{}` is already defined", kind, name), token)
+                    .with_note(format!("`{}` was first defined here", name), first);
+                diagnostics.push(if kind == "constant" && constants.contains_key(&name) && (is_set || reassignable.contains(&name)) {
+                    diagnostic.with_help("a constant which changes must be given every value with `.set`".to_string())
                 } else {
-                    return error_message("a label, directive, or instruction", &t);
-                }
-            },
-            &TokenType::Directive(ref directive) => {
-                let result = verify_directive(&mut tokens, directive);
-                if result.is_some() {
-                    return result;
-                }
+                    diagnostic
+                });
             },
-            &TokenType::Instruction(ref instruction) => {
-                let result = verify_instruction(&mut tokens, instruction);
-                if result.is_some() {
-                    return result;
+            None => {
+                if is_set {
+                    reassignable.insert(name.clone());
                 }
-            },
-            _ => {
-                return error_message("a label, directive, or instruction", &t);
+                let definitions = if kind == "label" { &mut labels } else { &mut constants };
+                definitions.insert(name, token.clone());
             }
-        };
-        prev_label = false;
-        token = tokens.next();
+        }
     }
-    None
+    (labels, constants, reassignable)
 }
 
-fn verify_directive(tokens: &mut Tokenizer, directive: &DirectiveType) -> Option<String> {
-    let next_token = tokens.next();
-    if !next_token.is_some() {
-        return end_of_file();
+/// Reports a reference to a label which is never defined, suggesting a similar label if there is one
+pub fn undefined_label<'a, I>(name: &str, token: &Token, labels: I) -> Diagnostic
+    where I: IntoIterator<Item = &'a str>
+{
+    let diagnostic = Diagnostic::error(format!("undefined label `{}`", name), token);
+    match closest_match(name, labels) {
+        Some(suggestion) => diagnostic.with_help(format!("a label with a similar name exists: `{}`", suggestion)),
+        None => diagnostic
     }
-    let next_token = next_token.unwrap();
+}
+
+/// Whether a directive emits data into the program
+fn is_data(directive: &DirectiveType) -> bool {
     match directive {
-        &DirectiveType::Byte => {
-            match &next_token.token_type {
-                &TokenType::Character(_) => None,
-                _ => error_message("an ASCII character", &next_token)
+        DirectiveType::Byte |
+        DirectiveType::Half |
+        DirectiveType::Word |
+        DirectiveType::Ascii |
+        DirectiveType::Asciz |
+        DirectiveType::Space => true,
+        DirectiveType::Align |
+        DirectiveType::Macro |
+        DirectiveType::EndMacro |
+        DirectiveType::Include |
+        DirectiveType::Equ |
+        DirectiveType::Set |
+        DirectiveType::Text |
+        DirectiveType::Data |
+        DirectiveType::Entry => false
+    }
+}
+
+/// Checks every line of a program, collecting all of the problems found
+/// Each line may hold a label followed by at most one directive or instruction
+pub fn verify<I: IntoIterator<Item = Token>>(tokens: I) -> Vec<Diagnostic> {
+    let lines = split_lines(tokens);
+    let mut diagnostics = vec![];
+    let (labels, constants, reassignable) = collect_labels(&lines, &mut diagnostics);
+    let mut used_labels: HashSet<&str> = HashSet::new();
+
+    // Constants defined by `.set` only have a value after the first `.set`
+    let mut set_so_far: HashSet<&str> = HashSet::new();
+    let names = || labels.keys().chain(constants.keys()).map(|name| name.as_str());
+
+    let mut pending_label: Option<&Token> = None;
+    let mut entry: Option<&Token> = None;
+    let mut section = Section::Text;
+
+    // Whether a label refers to the data directly before the next directive in each section
+    let mut data_reachable: HashMap<Section, bool> = HashMap::new();
+
+    for line in &lines {
+        let start = if is_label(&line[0].token_type) {
+            if let Some(label) = pending_label {
+                diagnostics.push(missing("a directive or instruction", label));
             }
-        },
-        &DirectiveType::Word => {
-            match &next_token.token_type {
-                &TokenType::Integer(_) => None,
-                _ => error_message("an integer", &next_token)
+            pending_label = Some(&line[0]);
+            1
+        } else {
+            0
+        };
+        let command = match line.get(start) {
+            Some(command) => command,
+            None => continue
+        };
+        let label = pending_label.take();
+        let operands = &line[start + 1..];
+
+        let result = match &command.token_type {
+            TokenType::Directive(directive) => {
+                match directive {
+                    // A label before a section switch belongs to the next command, as it does when assembling
+                    DirectiveType::Text => {
+                        section = Section::Text;
+                        pending_label = label;
+                    },
+                    DirectiveType::Data => {
+                        section = Section::Data;
+                        pending_label = label;
+                    },
+                    DirectiveType::Entry => {
+                        if entry.is_some() {
+                            diagnostics.push(Diagnostic::error(
+                                "the entry point has already been declared".to_string(),
+                                command
+                            ));
+                        }
+                        entry = Some(command);
+                    },
+                    _ => {}
+                };
+
+                if is_data(directive) {
+                    let reachable = data_reachable.entry(section).or_insert(false);
+                    if label.is_none() && !*reachable {
+                        diagnostics.push(Diagnostic::warning(
+                            "unreachable directive: no label refers to this data".to_string(),
+                            command
+                        ));
+                    }
+                    *reachable = true;
+                }
+
+                verify_directive(command, directive, operands)
+            },
+            TokenType::Instruction(instruction) => {
+                data_reachable.insert(section, false);
+                verify_instruction(command, instruction, operands)
+            },
+            _ => Err(unexpected("a label, directive, or instruction", command))
+        };
+
+        match result {
+            Ok(count) => {
+                if let Some(extra) = operands.get(count) {
+                    diagnostics.push(unexpected("the end of the line", extra));
+                    continue;
+                }
+                // The name a constant is given is a definition rather than a use
+                let uses = match command.token_type {
+                    TokenType::Directive(DirectiveType::Equ) |
+                    TokenType::Directive(DirectiveType::Set) => &operands[1..],
+                    _ => operands
+                };
+                for operand in uses {
+                    let symbols = match &operand.token_type {
+                        TokenType::Label(name) => vec![name.as_str()],
+                        TokenType::Expression(expr) | TokenType::Indirect(_, expr) => expr.symbols(),
+                        _ => vec![]
+                    };
+                    for name in symbols {
+                        match labels.get_key_value(name).or_else(|| constants.get_key_value(name)) {
+                            Some((name, _)) => {
+                                used_labels.insert(name);
+                            },
+                            None => diagnostics.push(undefined_label(name, operand, names()))
+                        };
+                        if reassignable.contains(name) && !set_so_far.contains(name) {
+                            diagnostics.push(
+                                Diagnostic::error(format!("constant `{}` is used before it is set", name), operand)
+                                    .with_note(format!("`{}` is first set here", name), &constants[name])
+                            );
+                        }
+                    }
+                }
+                if let (TokenType::Directive(DirectiveType::Set), Some(name)) = (&command.token_type, operands.first()) {
+                    if let TokenType::Label(name) = &name.token_type {
+                        set_so_far.insert(name);
+                    }
+                }
+            },
+            Err(diagnostic) => diagnostics.push(diagnostic)
+        };
+    }
+
+    if let Some(label) = pending_label {
+        diagnostics.push(missing("a directive or instruction", label));
+    }
+
+    // Labels and constants starting with an underscore are allowed to go unused
+    for (kind, definitions) in &[("label", &labels), ("constant", &constants)] {
+        for (name, token) in definitions.iter() {
+            if !used_labels.contains(name.as_str()) && !name.starts_with('_') {
+                diagnostics.push(Diagnostic::warning(format!("{} `{}` is never used", kind, name), token));
             }
         }
     }
+    Diagnostic::sort(&mut diagnostics);
+    diagnostics
 }
 
-fn verify_instruction(tokens: &mut Tokenizer, instruction: &InstructionType) -> Option<String> {
-    // Skip instructions which take no arguments
-    match instruction {
-        &InstructionType::OutputASCII |
-        &InstructionType::OutputInteger |
-        &InstructionType::InputASCII |
-        &InstructionType::InputInteger |
-        &InstructionType::ConvertASCIIToInteger |
-        &InstructionType::ConvertIntegerToASCII |
-        &InstructionType::End => return None,
-        _ => {}
+/// Checks the operands of a single directive or instruction, including that there are no more than it takes
+/// Used for commands which were not read from lines checked by `verify`
+pub fn verify_operands(command: &Token, operands: &[Token]) -> Result<(), Diagnostic> {
+    let count = match &command.token_type {
+        TokenType::Directive(directive) => verify_directive(command, directive, operands)?,
+        TokenType::Instruction(instruction) => verify_instruction(command, instruction, operands)?,
+        _ => return Err(unexpected("a directive or instruction", command))
     };
+    match operands.get(count) {
+        Some(extra) => Err(unexpected("the end of the line", extra)),
+        None => Ok(())
+    }
+}
 
-    let next_token = tokens.next();
-    if !next_token.is_some() {
-        return match instruction {
-            &InstructionType::OutputASCII |
-            &InstructionType::OutputInteger |
-            &InstructionType::InputASCII |
-            &InstructionType::InputInteger |
-            &InstructionType::ConvertASCIIToInteger |
-            &InstructionType::ConvertIntegerToASCII |
-            &InstructionType::End => return None,
-            _ => end_of_file()
-        };
+/// Checks the operands of a directive, returning how many it takes
+fn verify_directive(command: &Token, directive: &DirectiveType, operands: &[Token]) -> Result<usize, Diagnostic> {
+    match directive {
+        DirectiveType::Byte => {
+            expect(command, operands, 0, "an ASCII character or an integer between -128 and 255", |t| match t {
+                // Escapes such as `'\xFF'` give characters past ASCII, but anything more does not fit in a byte
+                TokenType::Character(c) => *c <= '\u{FF}',
+                t => is_integer_where(t, |val| (-128..=255).contains(&val))
+            })?;
+            Ok(1)
+        },
+        DirectiveType::Half => {
+            expect(command, operands, 0, "an integer between -32768 and 65535", |t| {
+                is_integer_where(t, |val| (-32_768..=65_535).contains(&val))
+            })?;
+            Ok(1)
+        },
+        DirectiveType::Word => {
+            expect(command, operands, 0, "an integer", is_integer)?;
+            Ok(1)
+        },
+        DirectiveType::Equ |
+        DirectiveType::Set => {
+            expect(command, operands, 0, "a constant name", is_label)?;
+            expect(command, operands, 1, "an integer", is_integer)?;
+            Ok(2)
+        },
+        DirectiveType::Entry => {
+            expect(command, operands, 0, "a label", is_label)?;
+            Ok(1)
+        },
+        DirectiveType::Ascii |
+        DirectiveType::Asciz => {
+            expect(command, operands, 0, "a string", |t| matches!(t, TokenType::String(_)))?;
+            Ok(1)
+        },
+        DirectiveType::Space => {
+            let expected = format!("an integer between 0 and {}", MAX_SPACE);
+            expect(command, operands, 0, &expected, |t| is_integer_where(t, |val| (0..=MAX_SPACE).contains(&val)))?;
+            Ok(1)
+        },
+        DirectiveType::Align => {
+            expect(command, operands, 0, "a positive integer", |t| is_integer_where(t, |val| val > 0))?;
+            Ok(1)
+        },
+        DirectiveType::Text |
+        DirectiveType::Data => Ok(0),
+        DirectiveType::Macro |
+        DirectiveType::EndMacro |
+        DirectiveType::Include => Err(Diagnostic::error(
+            format!("`{}` must start a line of its own", command.token_type),
+            command
+        ))
     }
-    let next_token = next_token.unwrap();
+}
+
+/// Checks the operands of an instruction, returning how many it takes
+fn verify_instruction(command: &Token, instruction: &InstructionType, operands: &[Token]) -> Result<usize, Diagnostic> {
+    use tokenizer::InstructionType::*;
     match instruction {
-        &InstructionType::Jump => {
-            match &next_token.token_type {
-                &TokenType::Label(_) => None,
-                _ => error_message("a label", &next_token)
-            }
+        // Instructions which take no arguments
+        OutputASCII |
+        OutputInteger |
+        InputASCII |
+        InputInteger |
+        ConvertASCIIToInteger |
+        ConvertIntegerToASCII |
+        Return |
+        Leave |
+        End => Ok(0),
+
+        Jump |
+        Call => {
+            expect(command, operands, 0, "a label or an address", is_address)?;
+            Ok(1)
         },
-        &InstructionType::JumpRelative => {
-            match &next_token.token_type {
-                &TokenType::Register(_) => None,
-                _ => error_message("a register", &next_token)
-            }
+        Enter => {
+            expect(command, operands, 0, "an integer", is_integer)?;
+            Ok(1)
         },
-        &InstructionType::NonZeroJump |
-        &InstructionType::GreaterThanZeroJump |
-        &InstructionType::LessThanZeroJump |
-        &InstructionType::CompareZeroJump |
-        &InstructionType::LoadAddress |
-        &InstructionType::StoreWord |
-        &InstructionType::LoadWord |
-        &InstructionType::StoreByte |
-        &InstructionType::LoadByte => {
-            let second_op = tokens.next();
-            if !second_op.is_some() {
-                return end_of_file();
-            }
-            let second_op = second_op.unwrap();
-
-            match &next_token.token_type {
-                &TokenType::Register(_) => match &second_op.token_type {
-                    &TokenType::Label(_) => None,
-                    _ => error_message("a label", &second_op)
-                },
-                _ => error_message("a register", &next_token)
-            }
+        JumpRelative |
+        Push |
+        Pop => {
+            expect(command, operands, 0, "a register", is_register)?;
+            Ok(1)
         },
 
-        &InstructionType::Move |
-        &InstructionType::Subtract |
-        &InstructionType::Multiply |
-        &InstructionType::Divide |
-        &InstructionType::And |
-        &InstructionType::Or |
-        &InstructionType::Compare => {
-            let second_op = tokens.next();
-            if !second_op.is_some() {
-                return end_of_file();
-            }
-            let second_op = second_op.unwrap();
-
-            match &next_token.token_type {
-                &TokenType::Register(_) => match &second_op.token_type {
-                    &TokenType::Register(_) => None,
-                    _ => error_message("a register", &second_op)
-                },
-                _ => error_message("a register", &next_token)
+        StoreWord |
+        LoadWord |
+        StoreHalf |
+        LoadHalf |
+        LoadHalfSigned |
+        StoreByte |
+        LoadByte |
+        LoadByteSigned |
+        StoreWordIndirect |
+        LoadWordIndirect |
+        StoreHalfIndirect |
+        LoadHalfIndirect |
+        LoadHalfSignedIndirect |
+        StoreByteIndirect |
+        LoadByteIndirect |
+        LoadByteSignedIndirect => {
+            expect(command, operands, 0, "a register", is_register)?;
+            let address = expect(command, operands, 1, "a label or an address", |t| is_address(t) || matches!(t, TokenType::Indirect(..)))?;
+            // Offsets worked out from constants are checked once their value is known
+            if let TokenType::Indirect(_, Expr::Integer(offset)) = address.token_type {
+                if !(MIN_INDIRECT_OFFSET..=MAX_INDIRECT_OFFSET).contains(&offset) {
+                    return Err(unexpected(
+                        &format!("an offset between {} and {}", MIN_INDIRECT_OFFSET, MAX_INDIRECT_OFFSET),
+                        address
+                    ));
+                }
             }
+            Ok(2)
         },
 
-        &InstructionType::Add |
-        &InstructionType::AddImmediate => {
-            let second_op = tokens.next();
-            if !second_op.is_some() {
-                return end_of_file();
-            }
-            let second_op = second_op.unwrap();
-
-            match &next_token.token_type {
-                &TokenType::Register(_) => match &second_op.token_type {
-                    &TokenType::Register(_) |
-                    &TokenType::Integer(_) => None,
-                    _ => error_message("a register or an integer", &second_op)
-                },
-                _ => error_message("a register", &next_token)
-            }
+        NonZeroJump |
+        GreaterThanZeroJump |
+        LessThanZeroJump |
+        CompareZeroJump |
+        LoadAddress => {
+            expect(command, operands, 0, "a register", is_register)?;
+            expect(command, operands, 1, "a label or an address", is_address)?;
+            Ok(2)
         },
 
-        &InstructionType::OutputASCII |
-        &InstructionType::OutputInteger |
-        &InstructionType::InputASCII |
-        &InstructionType::InputInteger |
-        &InstructionType::ConvertASCIIToInteger |
-        &InstructionType::ConvertIntegerToASCII |
-        &InstructionType::End => None
+        Move |
+        Subtract |
+        Multiply |
+        Divide |
+        And |
+        Or |
+        Compare => {
+            expect(command, operands, 0, "a register", is_register)?;
+            expect(command, operands, 1, "a register", is_register)?;
+            Ok(2)
+        },
+
+        Add |
+        AddImmediate => {
+            expect(command, operands, 0, "a register", is_register)?;
+            expect(command, operands, 1, "a register or an integer", |t| is_register(t) || is_integer(t))?;
+            Ok(2)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diagnostic::Severity;
+
+    #[test]
+    fn valid_programs_have_no_errors() {
+        for path in &["test_files/27-call-stack.asm", "test_files/30-mixed-layout.asm", "test_files/32-strings.asm"] {
+            let diagnostics = verify(Tokenizer::new(path));
+            assert!(!diagnostics.iter().any(|d| d.is_error()), "{}: {:?}", path, diagnostics);
+        }
+    }
+
+    #[test]
+    fn collects_every_problem_in_order() {
+        let diagnostics = verify(Tokenizer::new("test_files/34-diagnostics.asm"));
+        let found: Vec<(Severity, u32, u32, &str)> = diagnostics.iter()
+            .map(|d| (d.severity, d.line, d.columns.start, d.message.as_str()))
+            .collect();
+        assert_eq!(found, vec![
+            (Severity::Warning, 2, 1, "label `Start` is never used"),
+            (Severity::Error, 2, 11, "expected a register but saw \"5\""),
+            (Severity::Error, 3, 9, "expected a register or an integer after \"reg_1\""),
+            (Severity::Warning, 4, 1, "label `Loop` is never used"),
+            (Severity::Error, 4, 10, "undefined label `Nowhere`"),
+            (Severity::Error, 5, 1, "label `Loop` is already defined"),
+            (Severity::Warning, 6, 1, "unreachable directive: no label refers to this data"),
+            (Severity::Error, 6, 7, "expected an ASCII character or an integer between -128 and 255 but saw \"300\""),
+            (Severity::Error, 7, 5, "undefined label `Strat`"),
+            (Severity::Warning, 8, 1, "label `Lonely` is never used"),
+            (Severity::Error, 8, 7, "expected a directive or instruction after \"Lonely\""),
+        ]);
+    }
+
+    #[test]
+    fn labels_carry_over_section_switches() {
+        let diagnostics = verify(Tokenizer::from_source("buffer.asm", "LDA io Buf\nEND\nBuf\n.data\n.word 0\n"));
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+
+        let diagnostics = verify(Tokenizer::from_source("buffer.asm", "LDA io Buf\nEND\nBuf .data\n.text\n.word 0\n"));
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+
+        let found: Vec<(u32, String)> = verify(Tokenizer::from_source("buffer.asm", "LDA io Buf\nEND\nBuf\n.data\n"))
+            .into_iter()
+            .map(|d| (d.line, d.message))
+            .collect();
+        assert_eq!(found, vec![(3, "expected a directive or instruction after \"Buf\"".to_string())]);
+    }
+
+    #[test]
+    fn duplicate_labels_point_at_the_first_definition() {
+        let diagnostics = verify(Tokenizer::new("test_files/34-diagnostics.asm"));
+        let duplicate = diagnostics.iter().find(|d| d.message.contains("already defined")).unwrap();
+        assert_eq!(duplicate.notes.len(), 1);
+        assert_eq!((duplicate.notes[0].line, duplicate.notes[0].columns.clone()), (4, 1..5));
+    }
+
+    #[test]
+    fn undefined_labels_suggest_similar_names() {
+        let diagnostics = verify(Tokenizer::new("test_files/34-diagnostics.asm"));
+        let help: Vec<Option<&str>> = diagnostics.iter()
+            .filter(|d| d.message.starts_with("undefined label"))
+            .map(|d| d.help.as_deref())
+            .collect();
+        assert_eq!(help, vec![None, Some("a label with a similar name exists: `Start`")]);
+    }
+
+    #[test]
+    fn constants_are_checked_like_labels() {
+        let source = concat!(
+            ".equ SIZE 4\n",
+            ".set SIZE 8\n",
+            ".equ _SPARE 1\n",
+            ".equ UNUSED 2\n",
+            "Start + reg_1 SIZE*COUNT\n",
+            "JMP Start+SIZE\n",
+            ".equ 5 5\n",
+            "LDW reg_1 [fp-FRAME]\n",
+        );
+        let diagnostics = verify(Tokenizer::from_source("constants.asm", source).tokenize().unwrap());
+        let found: Vec<(u32, &str)> = diagnostics.iter().map(|d| (d.line, d.message.as_str())).collect();
+        assert_eq!(found, vec![
+            (2, "constant `SIZE` is already defined"),
+            (4, "constant `UNUSED` is never used"),
+            (5, "undefined label `COUNT`"),
+            (7, "expected a constant name but saw \"5\""),
+            (8, "undefined label `FRAME`"),
+        ]);
+    }
+
+    #[test]
+    fn set_constants_are_used_after_they_are_set() {
+        let source = concat!(
+            ".set STEP 1\n",
+            ".equ FIXED STEP\n",
+            "+ reg_1 LATER\n",
+            ".set LATER FIXED\n",
+            ".set STEP STEP+LATER\n",
+            ".equ STEP 3\n",
+            ".set FIXED 4\n",
+        );
+        let diagnostics = verify(Tokenizer::from_source("set.asm", source).tokenize().unwrap());
+        let found: Vec<(u32, &str)> = diagnostics.iter().map(|d| (d.line, d.message.as_str())).collect();
+        assert_eq!(found, vec![
+            (3, "constant `LATER` is used before it is set"),
+            (6, "constant `STEP` is already defined"),
+            (7, "constant `FIXED` is already defined"),
+        ]);
+    }
+
+    #[test]
+    fn bytes_only_hold_single_byte_characters() {
+        let source = "A .byte '\\xFF'\nB .byte '€'\nLDA reg_1 A\nLDA reg_1 B\n";
+        let diagnostics = verify(Tokenizer::from_source("bytes.asm", source));
+        let found: Vec<(u32, &str)> = diagnostics.iter().map(|d| (d.line, d.message.as_str())).collect();
+        assert_eq!(found, vec![(2, "expected an ASCII character or an integer between -128 and 255 but saw \"€\"")]);
+    }
+
+    #[test]
+    fn space_is_limited_to_the_size_of_memory() {
+        let source = ".data\nA .space 0x7FFFFFF0\nB .space 10_000_000\n.text\nLDA reg_1 A\nLDA reg_1 B\n";
+        let diagnostics = verify(Tokenizer::from_source("space.asm", source));
+        let found: Vec<(u32, &str)> = diagnostics.iter().map(|d| (d.line, d.message.as_str())).collect();
+        assert_eq!(found, vec![(2, "expected an integer between 0 and 10000000 but saw \"2147483632\"")]);
+    }
+
+    #[test]
+    fn renders_the_offending_line() {
+        let path = "test_files/34-diagnostics.asm";
+        let source = ::std::fs::read_to_string(path).unwrap();
+        let rendered = verify(Tokenizer::new(path))[1].render(&source);
+        assert_eq!(rendered, concat!(
+            "error: expected a register but saw \"5\"\n",
+            " --> test_files/34-diagnostics.asm:2:11\n",
+            "  |\n",
+            "2 | Start MOV 5 reg_1\n",
+            "  |           ^\n"
+        ));
     }
 }
@@ -0,0 +1,16 @@
+//! Fixtures shared by the unit tests of every module
+use std::fs;
+use {assemble_files, Assembly, Origin, SourceFiles};
+
+/// Reads, checks and assembles a program for the default origin
+/// Panics if the program has any errors
+pub fn assemble(path: &str) -> Assembly {
+    assemble_at(path, Origin::default())
+}
+
+/// Reads, checks and assembles a program with its sections placed at `origin`
+pub fn assemble_at(path: &str, origin: Origin) -> Assembly {
+    let mut files = SourceFiles::new();
+    files.add(path, fs::read_to_string(path).unwrap());
+    assemble_files(&mut files, origin).unwrap_or_else(|diagnostics| panic!("{} has errors: {:?}", path, diagnostics))
+}
@@ -1,25 +1,59 @@
+use std::collections::HashSet;
 use std::fmt;
 use std::marker;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Lines};
+use std::io;
+use std::io::{BufRead, BufReader};
 use std::iter::Iterator;
+use std::rc::Rc;
+use expr;
+use expr::Expr;
+use macros::Expansion;
 
 pub trait ByteCode where Self: marker::Sized {
     fn to_bytecode(&self) -> i32;
-    fn from_bytecode(i32) -> Option<Self>;
+    fn from_bytecode(code: i32) -> Option<Self>;
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum DirectiveType {
     Byte,
-    Word
+    Half,
+    Word,
+    Text,
+    Data,
+    Entry,
+    Ascii,
+    Asciz,
+    Space,
+    Align,
+    Macro,
+    EndMacro,
+    Include,
+    Equ,
+    Set
 }
 
+// Directives added after `.byte` and `.word` use negative codes
+// so they can never be mistaken for an instruction opcode
 impl ByteCode for DirectiveType {
     fn to_bytecode(&self) -> i32 {
         match self {
-            &DirectiveType::Byte => 0,
-            &DirectiveType::Word => 1
+            DirectiveType::Byte => 0,
+            DirectiveType::Word => 1,
+            DirectiveType::Half => -1,
+            DirectiveType::Text => -2,
+            DirectiveType::Data => -3,
+            DirectiveType::Entry => -4,
+            DirectiveType::Ascii => -5,
+            DirectiveType::Asciz => -6,
+            DirectiveType::Space => -7,
+            DirectiveType::Align => -8,
+            DirectiveType::Macro => -9,
+            DirectiveType::EndMacro => -10,
+            DirectiveType::Include => -11,
+            DirectiveType::Equ => -12,
+            DirectiveType::Set => -13
         }
     }
 
@@ -27,6 +61,19 @@ impl ByteCode for DirectiveType {
         match code {
             0 => Some(DirectiveType::Byte),
             1 => Some(DirectiveType::Word),
+            -1 => Some(DirectiveType::Half),
+            -2 => Some(DirectiveType::Text),
+            -3 => Some(DirectiveType::Data),
+            -4 => Some(DirectiveType::Entry),
+            -5 => Some(DirectiveType::Ascii),
+            -6 => Some(DirectiveType::Asciz),
+            -7 => Some(DirectiveType::Space),
+            -8 => Some(DirectiveType::Align),
+            -9 => Some(DirectiveType::Macro),
+            -10 => Some(DirectiveType::EndMacro),
+            -11 => Some(DirectiveType::Include),
+            -12 => Some(DirectiveType::Equ),
+            -13 => Some(DirectiveType::Set),
             _ => None
         }
     }
@@ -55,6 +102,18 @@ pub enum InstructionType {
     LoadWord,
     StoreByte,
     LoadByte,
+    StoreWordIndirect,
+    LoadWordIndirect,
+    StoreByteIndirect,
+    LoadByteIndirect,
+    StoreHalf,
+    LoadHalf,
+    LoadHalfSigned,
+    LoadByteSigned,
+    StoreHalfIndirect,
+    LoadHalfIndirect,
+    LoadHalfSignedIndirect,
+    LoadByteSignedIndirect,
 
     Add,
     AddImmediate,
@@ -65,44 +124,72 @@ pub enum InstructionType {
     And,
     Or,
 
-    Compare
+    Compare,
+
+    Push,
+    Pop,
+    Call,
+    Return,
+    Enter,
+    Leave
 }
 
 impl ByteCode for InstructionType {
     fn to_bytecode(&self) -> i32 {
         match self {
-            &InstructionType::End => 2,
-            &InstructionType::OutputInteger => 3,
-            &InstructionType::InputInteger => 4,
-            &InstructionType::OutputASCII => 5,
-            &InstructionType::InputASCII => 6,
-            &InstructionType::ConvertASCIIToInteger => 7,
-            &InstructionType::ConvertIntegerToASCII => 8,
-
-            &InstructionType::Jump => 9,
-            &InstructionType::JumpRelative => 10,
-            &InstructionType::NonZeroJump => 11,
-            &InstructionType::GreaterThanZeroJump => 12,
-            &InstructionType::LessThanZeroJump => 13,
-            &InstructionType::CompareZeroJump => 14,
-
-            &InstructionType::Move => 15,
-            &InstructionType::LoadAddress => 16,
-            &InstructionType::StoreWord => 17,
-            &InstructionType::LoadWord => 18,
-            &InstructionType::StoreByte => 19,
-            &InstructionType::LoadByte => 20,
-
-            &InstructionType::Add => 21,
-            &InstructionType::AddImmediate => 22,
-            &InstructionType::Subtract => 23,
-            &InstructionType::Multiply => 24,
-            &InstructionType::Divide => 25,
-
-            &InstructionType::And => 26,
-            &InstructionType::Or => 27,
-
-            &InstructionType::Compare => 28
+            InstructionType::End => 2,
+            InstructionType::OutputInteger => 3,
+            InstructionType::InputInteger => 4,
+            InstructionType::OutputASCII => 5,
+            InstructionType::InputASCII => 6,
+            InstructionType::ConvertASCIIToInteger => 7,
+            InstructionType::ConvertIntegerToASCII => 8,
+
+            InstructionType::Jump => 9,
+            InstructionType::JumpRelative => 10,
+            InstructionType::NonZeroJump => 11,
+            InstructionType::GreaterThanZeroJump => 12,
+            InstructionType::LessThanZeroJump => 13,
+            InstructionType::CompareZeroJump => 14,
+
+            InstructionType::Move => 15,
+            InstructionType::LoadAddress => 16,
+            InstructionType::StoreWord => 17,
+            InstructionType::LoadWord => 18,
+            InstructionType::StoreByte => 19,
+            InstructionType::LoadByte => 20,
+
+            InstructionType::Add => 21,
+            InstructionType::AddImmediate => 22,
+            InstructionType::Subtract => 23,
+            InstructionType::Multiply => 24,
+            InstructionType::Divide => 25,
+
+            InstructionType::And => 26,
+            InstructionType::Or => 27,
+
+            InstructionType::Compare => 28,
+
+            InstructionType::Push => 29,
+            InstructionType::Pop => 30,
+            InstructionType::Call => 31,
+            InstructionType::Return => 32,
+            InstructionType::Enter => 33,
+            InstructionType::Leave => 34,
+
+            InstructionType::StoreWordIndirect => 35,
+            InstructionType::LoadWordIndirect => 36,
+            InstructionType::StoreByteIndirect => 37,
+            InstructionType::LoadByteIndirect => 38,
+
+            InstructionType::StoreHalf => 39,
+            InstructionType::LoadHalf => 40,
+            InstructionType::LoadHalfSigned => 41,
+            InstructionType::LoadByteSigned => 42,
+            InstructionType::StoreHalfIndirect => 43,
+            InstructionType::LoadHalfIndirect => 44,
+            InstructionType::LoadHalfSignedIndirect => 45,
+            InstructionType::LoadByteSignedIndirect => 46
         }
     }
 
@@ -140,6 +227,27 @@ impl ByteCode for InstructionType {
             27 => Some(InstructionType::Or),
 
             28 => Some(InstructionType::Compare),
+
+            29 => Some(InstructionType::Push),
+            30 => Some(InstructionType::Pop),
+            31 => Some(InstructionType::Call),
+            32 => Some(InstructionType::Return),
+            33 => Some(InstructionType::Enter),
+            34 => Some(InstructionType::Leave),
+
+            35 => Some(InstructionType::StoreWordIndirect),
+            36 => Some(InstructionType::LoadWordIndirect),
+            37 => Some(InstructionType::StoreByteIndirect),
+            38 => Some(InstructionType::LoadByteIndirect),
+
+            39 => Some(InstructionType::StoreHalf),
+            40 => Some(InstructionType::LoadHalf),
+            41 => Some(InstructionType::LoadHalfSigned),
+            42 => Some(InstructionType::LoadByteSigned),
+            43 => Some(InstructionType::StoreHalfIndirect),
+            44 => Some(InstructionType::LoadHalfIndirect),
+            45 => Some(InstructionType::LoadHalfSignedIndirect),
+            46 => Some(InstructionType::LoadByteSignedIndirect),
             _ => None
         }
     }
@@ -165,19 +273,19 @@ pub enum Register {
 impl ByteCode for Register {
     fn to_bytecode(&self) -> i32 {
         match self {
-            &Register::Reg0 => 0,
-            &Register::Reg1 => 1,
-            &Register::Reg2 => 2,
-            &Register::Reg3 => 3,
-            &Register::Reg4 => 4,
-            &Register::Reg5 => 5,
-            &Register::Reg6 => 6,
-            &Register::IO => 7,
-            &Register::PC => 8,
-            &Register::SL => 9,
-            &Register::SP => 10,
-            &Register::FP => 11,
-            &Register::SB => 12,
+            Register::Reg0 => 0,
+            Register::Reg1 => 1,
+            Register::Reg2 => 2,
+            Register::Reg3 => 3,
+            Register::Reg4 => 4,
+            Register::Reg5 => 5,
+            Register::Reg6 => 6,
+            Register::IO => 7,
+            Register::PC => 8,
+            Register::SL => 9,
+            Register::SP => 10,
+            Register::FP => 11,
+            Register::SB => 12,
         }
     }
 
@@ -207,74 +315,153 @@ pub enum TokenType {
     Directive(DirectiveType),
     Instruction(InstructionType),
     Integer(i32),
+    String(Vec<u8>),
     Register(Register),
+    Indirect(Register, Expr),
     Label(String),
+
+    /// Worked out by the assembler, such as `Buffer+4`
+    Expression(Expr),
     None
 }
 
 impl fmt::Display for TokenType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            &TokenType::Character(ref str) => write!(f, "{}", str),
-            &TokenType::Directive(ref directive) => match directive {
-                &DirectiveType::Byte => write!(f, ".byte"),
-                &DirectiveType::Word => write!(f, ".word")
+            TokenType::Character(str) => write!(f, "{}", str),
+            TokenType::Directive(directive) => match directive {
+                DirectiveType::Byte => write!(f, ".byte"),
+                DirectiveType::Half => write!(f, ".half"),
+                DirectiveType::Text => write!(f, ".text"),
+                DirectiveType::Data => write!(f, ".data"),
+                DirectiveType::Entry => write!(f, ".entry"),
+                DirectiveType::Ascii => write!(f, ".ascii"),
+                DirectiveType::Asciz => write!(f, ".asciz"),
+                DirectiveType::Space => write!(f, ".space"),
+                DirectiveType::Align => write!(f, ".align"),
+                DirectiveType::Macro => write!(f, ".macro"),
+                DirectiveType::EndMacro => write!(f, ".endm"),
+                DirectiveType::Include => write!(f, ".include"),
+                DirectiveType::Equ => write!(f, ".equ"),
+                DirectiveType::Set => write!(f, ".set"),
+                DirectiveType::Word => write!(f, ".word")
+            },
+            TokenType::Instruction(instruction) => match instruction {
+                InstructionType::Add |
+                InstructionType::AddImmediate => write!(f, "+"),
+                InstructionType::And => write!(f, "&&"),
+                InstructionType::Call => write!(f, "CALL"),
+                InstructionType::ConvertASCIIToInteger => write!(f, "A2I"),
+                InstructionType::ConvertIntegerToASCII => write!(f, "I2A"),
+                InstructionType::Divide => write!(f, "/"),
+                InstructionType::End => write!(f, "END"),
+                InstructionType::Enter => write!(f, "ENTER"),
+                InstructionType::Compare => write!(f, "=="),
+                InstructionType::CompareZeroJump => write!(f, "=0"),
+                InstructionType::GreaterThanZeroJump => write!(f, ">0"),
+                InstructionType::InputASCII => write!(f, "ASCI"),
+                InstructionType::InputInteger => write!(f, "IN"),
+                InstructionType::Jump => write!(f, "JMP"),
+                InstructionType::JumpRelative => write!(f, "JMR"),
+                InstructionType::Leave => write!(f, "LEAVE"),
+                InstructionType::LessThanZeroJump => write!(f, "<0"),
+                InstructionType::LoadAddress => write!(f, "LDA"),
+                InstructionType::LoadByte |
+                InstructionType::LoadByteIndirect => write!(f, "LDB"),
+                InstructionType::LoadByteSigned |
+                InstructionType::LoadByteSignedIndirect => write!(f, "LDBS"),
+                InstructionType::LoadHalf |
+                InstructionType::LoadHalfIndirect => write!(f, "LDH"),
+                InstructionType::LoadHalfSigned |
+                InstructionType::LoadHalfSignedIndirect => write!(f, "LDHS"),
+                InstructionType::LoadWord |
+                InstructionType::LoadWordIndirect => write!(f, "LDW"),
+                InstructionType::Move => write!(f, "MOV"),
+                InstructionType::Multiply => write!(f, "*"),
+                InstructionType::NonZeroJump => write!(f, "!0"),
+                InstructionType::Or => write!(f, "||"),
+                InstructionType::OutputASCII => write!(f, "ASCO"),
+                InstructionType::OutputInteger => write!(f, "OUT"),
+                InstructionType::Pop => write!(f, "POP"),
+                InstructionType::Push => write!(f, "PUSH"),
+                InstructionType::Return => write!(f, "RET"),
+                InstructionType::StoreByte |
+                InstructionType::StoreByteIndirect => write!(f, "STB"),
+                InstructionType::StoreHalf |
+                InstructionType::StoreHalfIndirect => write!(f, "STH"),
+                InstructionType::StoreWord |
+                InstructionType::StoreWordIndirect => write!(f, "STW"),
+                InstructionType::Subtract => write!(f, "-")
             },
-            &TokenType::Instruction(ref instruction) => match instruction {
-                &InstructionType::Add |
-                &InstructionType::AddImmediate => write!(f, "+"),
-                &InstructionType::And => write!(f, "&&"),
-                &InstructionType::ConvertASCIIToInteger => write!(f, "A2I"),
-                &InstructionType::ConvertIntegerToASCII => write!(f, "I2A"),
-                &InstructionType::Divide => write!(f, "/"),
-                &InstructionType::End => write!(f, "END"),
-                &InstructionType::Compare => write!(f, "=="),
-                &InstructionType::CompareZeroJump => write!(f, "=0"),
-                &InstructionType::GreaterThanZeroJump => write!(f, ">0"),
-                &InstructionType::InputASCII => write!(f, "ASCI"),
-                &InstructionType::InputInteger => write!(f, "IN"),
-                &InstructionType::Jump => write!(f, "JMP"),
-                &InstructionType::JumpRelative => write!(f, "JMR"),
-                &InstructionType::LessThanZeroJump => write!(f, "<0"),
-                &InstructionType::LoadAddress => write!(f, "LDA"),
-                &InstructionType::LoadByte => write!(f, "LDB"),
-                &InstructionType::LoadWord => write!(f, "LDW"),
-                &InstructionType::Move => write!(f, "MOV"),
-                &InstructionType::Multiply => write!(f, "*"),
-                &InstructionType::NonZeroJump => write!(f, "!0"),
-                &InstructionType::Or => write!(f, "||"),
-                &InstructionType::OutputASCII => write!(f, "ASCO"),
-                &InstructionType::OutputInteger => write!(f, "OUT"),
-                &InstructionType::StoreByte => write!(f, "STB"),
-                &InstructionType::StoreWord => write!(f, "STW"),
-                &InstructionType::Subtract => write!(f, "-")
+            TokenType::Integer(val) => write!(f, "{}", val),
+            TokenType::String(string) => {
+                write!(f, "\"")?;
+                for byte in string {
+                    match *byte {
+                        b'"' => write!(f, "\\\"")?,
+                        b'\\' => write!(f, "\\\\")?,
+                        b'\n' => write!(f, "\\n")?,
+                        b'\t' => write!(f, "\\t")?,
+                        0 => write!(f, "\\0")?,
+                        b' '..=b'~' => write!(f, "{}", *byte as char)?,
+                        _ => write!(f, "\\x{:02X}", byte)?
+                    };
+                }
+                write!(f, "\"")
             },
-            &TokenType::Integer(ref val) => write!(f, "{}", val),
-            &TokenType::Label(ref label) => write!(f, "{}", label),
-            &TokenType::None => write!(f, "None"),
-            &TokenType::Register(ref register) => match register {
-                &Register::FP => write!(f, "FP"),
-                &Register::IO => write!(f, "IO"),
-                &Register::PC => write!(f, "PC"),
-                &Register::Reg0 => write!(f, "reg_0"),
-                &Register::Reg1 => write!(f, "reg_1"),
-                &Register::Reg2 => write!(f, "reg_2"),
-                &Register::Reg3 => write!(f, "reg_3"),
-                &Register::Reg4 => write!(f, "reg_4"),
-                &Register::Reg5 => write!(f, "reg_5"),
-                &Register::Reg6 => write!(f, "reg_6"),
-                &Register::SB => write!(f, "SB"),
-                &Register::SL => write!(f, "SL"),
-                &Register::SP => write!(f, "SP")
+            TokenType::Label(label) => write!(f, "{}", expr::source_name(label)),
+            TokenType::Expression(expr) => write!(f, "{}", expr),
+            TokenType::None => write!(f, "None"),
+            TokenType::Register(register) => write!(f, "{}", register),
+            TokenType::Indirect(register, offset) => match offset {
+                Expr::Integer(0) => write!(f, "[{}]", register),
+                Expr::Integer(value) => write!(f, "[{}{:+}]", register, value),
+                _ => {
+                    let offset = offset.to_string();
+                    let sign = if offset.starts_with('-') { "" } else { "+" };
+                    write!(f, "[{}{}{}]", register, sign, offset)
+                }
             }
         }
     }
 }
 
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Register::FP => write!(f, "fp"),
+            Register::IO => write!(f, "io"),
+            Register::PC => write!(f, "pc"),
+            Register::Reg0 => write!(f, "reg_0"),
+            Register::Reg1 => write!(f, "reg_1"),
+            Register::Reg2 => write!(f, "reg_2"),
+            Register::Reg3 => write!(f, "reg_3"),
+            Register::Reg4 => write!(f, "reg_4"),
+            Register::Reg5 => write!(f, "reg_5"),
+            Register::Reg6 => write!(f, "reg_6"),
+            Register::SB => write!(f, "sb"),
+            Register::SL => write!(f, "sl"),
+            Register::SP => write!(f, "sp")
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Token {
     pub token_type: TokenType,
-    pub line_number: u32
+    pub line_number: u32,
+
+    /// Column the token starts at, counting from 1
+    pub column: u32,
+
+    /// Number of characters the token spans in the source
+    pub width: u32,
+
+    /// Path of the file the token was read from
+    pub file: Rc<str>,
+
+    /// The macro call which produced the token, if it was copied from the body of a macro
+    pub expansion: Option<Rc<Expansion>>
 }
 
 impl fmt::Display for Token {
@@ -287,15 +474,16 @@ impl Token {
     pub fn new(t: TokenType, l: u32) -> Token {
         Token {
             token_type: t,
-            line_number: l
+            line_number: l,
+            column: 0,
+            width: 0,
+            file: Rc::from(""),
+            expansion: None
         }
     }
 
     pub fn new_none() -> Token {
-        Token {
-            token_type: TokenType::None,
-            line_number: 0
-        }
+        Token::new(TokenType::None, 0)
     }
 
     pub fn is_none(&self) -> bool {
@@ -303,26 +491,278 @@ impl Token {
     }
 }
 
+/// Splits a line into words separated by whitespace, stopping at the start of a comment
+/// Quoted strings and characters are kept together as a single word, as is anything in parentheses
+/// Each word is paired with the byte index it starts at
+fn split_words(line: &str) -> Vec<(usize, &str)> {
+    let mut words = vec![];
+    let mut start = None;
+    let mut end = line.len();
+    let mut quote = None;
+    let mut escaped = false;
+    let mut depth = 0;
+    for (i, c) in line.char_indices() {
+        if let Some(quote_char) = quote {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == quote_char {
+                quote = None;
+            }
+        } else if c == '#' {
+            end = i;
+            break;
+        } else if c == '(' || c == ')' {
+            if c == '(' {
+                depth += 1;
+            } else if depth > 0 {
+                depth -= 1;
+            }
+            start = start.or(Some(i));
+        } else if c.is_whitespace() && depth == 0 {
+            if let Some(word_start) = start.take() {
+                words.push((word_start, &line[word_start..i]));
+            }
+        } else if start.is_none() {
+            start = Some(i);
+            if c == '"' || c == '\'' {
+                quote = Some(c);
+            }
+        }
+    }
+    if let Some(word_start) = start {
+        words.push((word_start, &line[word_start..end]));
+    }
+    words
+}
+
+/// Replaces escape sequences in the contents of a character or string literal
+/// Returns `None` if an escape sequence is not recognized
+fn unescape(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buffer = [0; 4];
+            bytes.extend(c.encode_utf8(&mut buffer).as_bytes());
+            continue;
+        }
+
+        let byte = match chars.next()? {
+            'n' => b'\n',
+            't' => b'\t',
+            '0' => 0,
+            '\\' => b'\\',
+            '\'' => b'\'',
+            '"' => b'"',
+            'x' => {
+                let digits: String = chars.by_ref().take(2).collect();
+                if digits.len() != 2 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return None;
+                }
+                u8::from_str_radix(&digits, 16).ok()?
+            },
+            _ => return None
+        };
+        bytes.push(byte);
+    }
+    Some(bytes)
+}
+
+/// Parses a string literal such as `"Hello, world\n"` into its bytes
+fn parse_string(token: &str) -> Option<Vec<u8>> {
+    if token.len() < 2 || !token.starts_with('"') || !token.ends_with('"') {
+        return None;
+    }
+    unescape(&token[1..token.len() - 1])
+}
+
+/// Parses a character literal such as `'A'`, `' '` or `'\n'`
+fn parse_character(token: &str) -> Option<char> {
+    if token.len() < 3 || !token.starts_with('\'') || !token.ends_with('\'') {
+        return None;
+    }
+    let inner = &token[1..token.len() - 1];
+    let mut chars = inner.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c != '\\' => Some(c),
+        _ => match unescape(inner) {
+            Some(ref bytes) if bytes.len() == 1 => Some(bytes[0] as char),
+            _ => None
+        }
+    }
+}
+
+/// Parses a decimal, hexadecimal (`0x`), binary (`0b`) or octal (`0o`) integer
+/// Digits may be separated with underscores, such as `1_000_000`
+/// Prefixed literals may use all 32 bits, so `0xFFFFFFFF` is -1
+pub fn parse_integer(token: &str) -> Option<i32> {
+    let (negative, unsigned) = match token.strip_prefix('-') {
+        Some(unsigned) => (true, unsigned),
+        None => (false, token)
+    };
+    let lowercase = unsigned.to_lowercase();
+    let (radix, digits) = if lowercase.starts_with("0x") {
+        (16, &unsigned[2..])
+    } else if lowercase.starts_with("0b") {
+        (2, &unsigned[2..])
+    } else if lowercase.starts_with("0o") {
+        (8, &unsigned[2..])
+    } else {
+        (10, unsigned)
+    };
+    if digits.is_empty() || digits.starts_with('_') {
+        return None;
+    }
+    let digits: String = digits.chars().filter(|c| *c != '_').collect();
+    if !digits.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+
+    let value = i64::from_str_radix(&digits, radix).ok()?;
+    let value = if negative { -value } else { value };
+    let max = if radix == 10 { i32::MAX as i64 } else { u32::MAX as i64 };
+    if value < i32::MIN as i64 || value > max {
+        None
+    } else {
+        Some(value as u32 as i32)
+    }
+}
+
+/// Looks up a register by the name used for it in source code
+pub fn register_from_name(name: &str) -> Option<Register> {
+    match name {
+        "reg_0" => Some(Register::Reg0),
+        "reg_1" => Some(Register::Reg1),
+        "reg_2" => Some(Register::Reg2),
+        "reg_3" => Some(Register::Reg3),
+        "reg_4" => Some(Register::Reg4),
+        "reg_5" => Some(Register::Reg5),
+        "reg_6" => Some(Register::Reg6),
+        "io" => Some(Register::IO),
+        "pc" => Some(Register::PC),
+        "sl" => Some(Register::SL),
+        "sp" => Some(Register::SP),
+        "fp" => Some(Register::FP),
+        "sb" => Some(Register::SB),
+        _ => None
+    }
+}
+
+/// Parses a register-indirect operand such as `[reg_1]`, `[reg_1+8]`, `[fp-4]` or `[sp+FRAME*2]`
+///
+/// Everything after the register is one displacement, so `[fp-A+B]` is `fp` plus `-A+B`
+fn parse_indirect(token: &str) -> Option<(Register, Expr)> {
+    if token.len() < 2 || !token.starts_with('[') || !token.ends_with(']') {
+        return None;
+    }
+    let inner = &token[1..token.len() - 1];
+    let (name, offset) = match inner.find(['+', '-']) {
+        Some(sign) => (&inner[..sign], &inner[sign..]),
+        None => (inner, "0")
+    };
+    let register = register_from_name(name)?;
+
+    // A plain offset is written like any other integer, so `[reg_1+0x10]` works too
+    let offset = match offset.strip_prefix('+') {
+        Some(positive) if !positive.starts_with('-') => positive,
+        Some(_) => return None,
+        None => offset
+    };
+    let offset = match parse_integer(offset) {
+        Some(value) => Expr::Integer(value),
+        None => expr::parse(offset)?
+    };
+    Some((register, offset))
+}
+
+/// Turns words which name a label or constant, such as `loop-end`, back from expressions into labels
+/// A word holding an operator is read as an expression, unless it is defined at the start of a line
+/// or by `.equ` or `.set`, in which case it is a name everywhere in the program
+pub fn restore_names(tokens: &mut [Token]) {
+    let mut names = HashSet::new();
+    for (index, token) in tokens.iter().enumerate() {
+        let defines = match index.checked_sub(1).map(|previous| &tokens[previous]) {
+            Some(previous) if previous.line_number == token.line_number && previous.file == token.file =>
+                matches!(previous.token_type, TokenType::Directive(DirectiveType::Equ) | TokenType::Directive(DirectiveType::Set)),
+            _ => true
+        };
+        if let Some(name) = written_name(token).filter(|_| defines) {
+            names.insert(name);
+        }
+    }
+    for token in tokens.iter_mut() {
+        if let Some(name) = written_name(token).filter(|name| names.contains(name)) {
+            token.token_type = TokenType::Label(name);
+        }
+    }
+}
+
+/// The word an expression was written as, if it could be a name
+/// An expression only shows the same text as its word when it is written without brackets or extra digits
+fn written_name(token: &Token) -> Option<String> {
+    let text = match &token.token_type {
+        TokenType::Expression(expr) => expr.to_string(),
+        _ => return None
+    };
+    let starts_like_a_name = text.starts_with(|c: char| c.is_alphabetic() || c == '_');
+    if starts_like_a_name && text.chars().count() as u32 == token.width {
+        Some(text)
+    } else {
+        None
+    }
+}
+
 pub struct Tokenizer {
-    lines: Lines<BufReader<File>>,
+    lines: Box<dyn Iterator<Item = io::Result<String>>>,
     newest_tokens: Vec<Token>,
     line_number: u32,
+    file_path: Rc<str>,
+
+    /// Why reading the source stopped early, if it did
+    error: Option<io::Error>
 }
 
 impl Tokenizer {
+    /// Tokenizes a file
+    ///
+    /// Panics if the file cannot be opened
     pub fn new(file_path: &str) -> Tokenizer {
-        // Open the file stream
-        let file_stream = File::open(file_path);
-        if !file_stream.is_ok() {
-            panic!("Failed to open: {}", file_path);
-        }
-        let file_stream = file_stream.ok().unwrap();
-        let file = BufReader::new(file_stream);
+        Tokenizer::open(file_path).unwrap_or_else(|err| panic!("Failed to open {}: {}", file_path, err))
+    }
+
+    /// Tokenizes a file, failing if it cannot be opened
+    pub fn open(file_path: &str) -> io::Result<Tokenizer> {
+        let file = File::open(file_path)?;
+        Ok(Tokenizer::from_reader(file_path, BufReader::new(file)))
+    }
 
+    /// Tokenizes source code read from any reader, such as stdin
+    /// `name` is used as the file name in tokens and diagnostics
+    pub fn from_reader<R: BufRead + 'static>(name: &str, reader: R) -> Tokenizer {
         Tokenizer {
-            lines: file.lines(),
+            lines: Box::new(reader.lines()),
             newest_tokens: vec![],
-            line_number: 0
+            line_number: 0,
+            file_path: Rc::from(name),
+            error: None
+        }
+    }
+
+    /// Tokenizes source code which is already in memory, given as either a `&str` or a `String`
+    /// `name` is used as the file name in tokens and diagnostics
+    pub fn from_source<S: Into<String>>(name: &str, source: S) -> Tokenizer {
+        Tokenizer::from_reader(name, io::Cursor::new(source.into()))
+    }
+
+    /// Reads every token up front so they can be verified and assembled without reading the source again
+    /// Fails if the source could not be read to the end
+    pub fn tokenize(mut self) -> io::Result<Vec<Token>> {
+        let tokens = self.by_ref().collect();
+        match self.error.take() {
+            Some(err) => Err(err),
+            None => Ok(tokens)
         }
     }
 }
@@ -331,34 +771,87 @@ impl Iterator for Tokenizer {
     type Item = Token;
 
     fn next(&mut self) -> Option<Token> {
-        if self.newest_tokens.len() > 0 {
+        if !self.newest_tokens.is_empty() {
             return Some(self.newest_tokens.remove(0));
         }
+        if self.error.is_some() {
+            return None;
+        }
 
         let line = self.lines.next();
         self.line_number += 1;
         match line {
-            Some(line)  => {
-                let line = line.unwrap();
-                let mut line = line.trim();
-                if let Some(start_comment) = line.find("#") {
-                    line = &line[..start_comment];
-                }
-                if line.len() == 0 {
+            Some(Ok(line)) => {
+                let words = split_words(&line);
+                if words.is_empty() {
                     return self.next();
                 }
 
                 // Parse each token
-                for token in line.split_whitespace() {
-                    let new_token = match token {
+                for (start, token) in words {
+                    let mut new_token = match token {
                         ".byte" => Token::new(
                             TokenType::Directive(DirectiveType::Byte),
                             self.line_number
                         ),
+                        ".half" => Token::new(
+                            TokenType::Directive(DirectiveType::Half),
+                            self.line_number
+                        ),
                         ".word" => Token::new(
                             TokenType::Directive(DirectiveType::Word),
                             self.line_number
                         ),
+                        ".text" => Token::new(
+                            TokenType::Directive(DirectiveType::Text),
+                            self.line_number
+                        ),
+                        ".data" => Token::new(
+                            TokenType::Directive(DirectiveType::Data),
+                            self.line_number
+                        ),
+                        ".entry" => Token::new(
+                            TokenType::Directive(DirectiveType::Entry),
+                            self.line_number
+                        ),
+                        ".ascii" => Token::new(
+                            TokenType::Directive(DirectiveType::Ascii),
+                            self.line_number
+                        ),
+                        ".asciz" => Token::new(
+                            TokenType::Directive(DirectiveType::Asciz),
+                            self.line_number
+                        ),
+                        ".space" |
+                        ".zero" => Token::new(
+                            TokenType::Directive(DirectiveType::Space),
+                            self.line_number
+                        ),
+                        ".align" => Token::new(
+                            TokenType::Directive(DirectiveType::Align),
+                            self.line_number
+                        ),
+                        ".macro" => Token::new(
+                            TokenType::Directive(DirectiveType::Macro),
+                            self.line_number
+                        ),
+                        ".endm" => Token::new(
+                            TokenType::Directive(DirectiveType::EndMacro),
+                            self.line_number
+                        ),
+                        ".include" => Token::new(
+                            TokenType::Directive(DirectiveType::Include),
+                            self.line_number
+                        ),
+                        ".equ" => Token::new(
+                            TokenType::Directive(DirectiveType::Equ),
+                            self.line_number
+                        ),
+                        ".set" => Token::new(
+                            TokenType::Directive(DirectiveType::Set),
+                            self.line_number
+                        ),
+
                         "JMP" => Token::new(
                             TokenType::Instruction(InstructionType::Jump),
                             self.line_number
@@ -408,6 +901,22 @@ impl Iterator for Tokenizer {
                             TokenType::Instruction(InstructionType::LoadByte),
                             self.line_number
                         ),
+                        "LDBS" => Token::new(
+                            TokenType::Instruction(InstructionType::LoadByteSigned),
+                            self.line_number
+                        ),
+                        "STH" => Token::new(
+                            TokenType::Instruction(InstructionType::StoreHalf),
+                            self.line_number
+                        ),
+                        "LDH" => Token::new(
+                            TokenType::Instruction(InstructionType::LoadHalf),
+                            self.line_number
+                        ),
+                        "LDHS" => Token::new(
+                            TokenType::Instruction(InstructionType::LoadHalfSigned),
+                            self.line_number
+                        ),
 
                         "+" => Token::new(
                             TokenType::Instruction(InstructionType::Add),
@@ -440,56 +949,28 @@ impl Iterator for Tokenizer {
                             self.line_number
                         ),
 
-                        "reg_0" => Token::new(
-                            TokenType::Register(Register::Reg0),
-                            self.line_number
-                        ),
-                        "reg_1" => Token::new(
-                            TokenType::Register(Register::Reg1),
+                        "PUSH" => Token::new(
+                            TokenType::Instruction(InstructionType::Push),
                             self.line_number
                         ),
-                        "reg_2" => Token::new(
-                            TokenType::Register(Register::Reg2),
+                        "POP" => Token::new(
+                            TokenType::Instruction(InstructionType::Pop),
                             self.line_number
                         ),
-                        "reg_3" => Token::new(
-                            TokenType::Register(Register::Reg3),
+                        "CALL" => Token::new(
+                            TokenType::Instruction(InstructionType::Call),
                             self.line_number
                         ),
-                        "reg_4" => Token::new(
-                            TokenType::Register(Register::Reg4),
+                        "RET" => Token::new(
+                            TokenType::Instruction(InstructionType::Return),
                             self.line_number
                         ),
-                        "reg_5" => Token::new(
-                            TokenType::Register(Register::Reg5),
+                        "ENTER" => Token::new(
+                            TokenType::Instruction(InstructionType::Enter),
                             self.line_number
                         ),
-                        "reg_6" => Token::new(
-                            TokenType::Register(Register::Reg6),
-                            self.line_number
-                        ),
-                        "io" => Token::new(
-                            TokenType::Register(Register::IO),
-                            self.line_number
-                        ),
-                        "pc" => Token::new(
-                            TokenType::Register(Register::PC),
-                            self.line_number
-                        ),
-                        "sl" => Token::new(
-                            TokenType::Register(Register::SL),
-                            self.line_number
-                        ),
-                        "sp" => Token::new(
-                            TokenType::Register(Register::SP),
-                            self.line_number
-                        ),
-                        "fp" => Token::new(
-                            TokenType::Register(Register::FP),
-                            self.line_number
-                        ),
-                        "sb" => Token::new(
-                            TokenType::Register(Register::SB),
+                        "LEAVE" => Token::new(
+                            TokenType::Instruction(InstructionType::Leave),
                             self.line_number
                         ),
 
@@ -523,17 +1004,34 @@ impl Iterator for Tokenizer {
                         ),
 
                         _ =>  {
-                            // TODO: Handle characters and escape sequences better
-                            let num = token.parse::<i32>();
-                            if token.chars().nth(0).unwrap() == '\'' &&
-                               token.chars().nth(token.len() - 1).unwrap() == '\'' {
-                               Token::new(
-                                   TokenType::Character(token.chars().nth(1).unwrap()),
-                                   self.line_number
-                               )
-                            } else if num.is_ok() {
+                            if let Some(register) = register_from_name(token) {
                                 Token::new(
-                                    TokenType::Integer(num.unwrap()),
+                                    TokenType::Register(register),
+                                    self.line_number
+                                )
+                            } else if let Some((register, offset)) = parse_indirect(token) {
+                                Token::new(
+                                    TokenType::Indirect(register, offset),
+                                    self.line_number
+                                )
+                            } else if let Some(string) = parse_string(token) {
+                                Token::new(
+                                    TokenType::String(string),
+                                    self.line_number
+                                )
+                            } else if let Some(character) = parse_character(token) {
+                                Token::new(
+                                    TokenType::Character(character),
+                                    self.line_number
+                                )
+                            } else if let Some(num) = parse_integer(token) {
+                                Token::new(
+                                    TokenType::Integer(num),
+                                    self.line_number
+                                )
+                            } else if let Some(expr) = expr::parse(token).filter(|expr| !matches!(expr, Expr::Symbol(_))) {
+                                Token::new(
+                                    TokenType::Expression(expr),
                                     self.line_number
                                 )
                             } else {
@@ -545,15 +1043,160 @@ impl Iterator for Tokenizer {
                         }
                     };
 
+                    new_token.column = line[..start].chars().count() as u32 + 1;
+                    new_token.width = token.chars().count() as u32;
+                    new_token.file = self.file_path.clone();
                     self.newest_tokens.push(new_token);
                 }
 
                 // Return the first token
-                return Some(self.newest_tokens.remove(0));
+                Some(self.newest_tokens.remove(0))
             },
-            None => {
-                return None;
-            }
-        };
+            Some(Err(err)) => {
+                self.error = Some(err);
+                None
+            },
+            None => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use super::*;
+
+    #[test]
+    fn every_source_gives_the_same_tokens() {
+        let path = "test_files/27-call-stack.asm";
+        let source = fs::read_to_string(path).unwrap();
+        let from_file = Tokenizer::open(path).unwrap().tokenize().unwrap();
+        assert_eq!(Tokenizer::from_source(path, source.as_str()).tokenize().unwrap(), from_file);
+        assert_eq!(Tokenizer::from_source(path, source.clone()).tokenize().unwrap(), from_file);
+        assert_eq!(Tokenizer::from_reader(path, io::Cursor::new(source.into_bytes())).tokenize().unwrap(), from_file);
+    }
+
+    #[test]
+    fn tokens_name_their_source() {
+        let tokens = Tokenizer::from_source("<stdin>", "\nEND").tokenize().unwrap();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(&*tokens[0].file, "<stdin>");
+        assert_eq!(tokens[0].line_number, 2);
+    }
+
+    #[test]
+    fn reports_read_errors() {
+        assert!(Tokenizer::open("test_files/missing.asm").is_err());
+
+        // Lines which are not UTF-8 cannot be read
+        let reader = io::Cursor::new(b"END\n\xFF\nEND\n".to_vec());
+        let error = Tokenizer::from_reader("bad.asm", reader).tokenize().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn quoted_words_keep_spaces_and_hashes() {
+        assert_eq!(split_words(".byte ' ' # space"), vec![(0, ".byte"), (6, "' '")]);
+        assert_eq!(split_words(".byte '#'"), vec![(0, ".byte"), (6, "'#'")]);
+        assert_eq!(
+            split_words(r#"  .ascii "a \"b\" # c" # d"#),
+            vec![(2, ".ascii"), (9, r#""a \"b\" # c""#)]
+        );
+    }
+
+    #[test]
+    fn expressions_stay_together_inside_parentheses() {
+        assert_eq!(split_words("+ io (SIZE - 2)*COUNT # 30"), vec![(0, "+"), (2, "io"), (5, "(SIZE - 2)*COUNT")]);
+
+        let tokens = Tokenizer::from_source("expr.asm", "LDA reg_1 Buffer+4\nJMP Loop\n+ io -1").tokenize().unwrap();
+        assert_eq!(tokens[2].token_type, TokenType::Expression(expr::parse("Buffer+4").unwrap()));
+        assert_eq!(tokens[4].token_type, TokenType::Label("Loop".to_string()));
+        assert_eq!(tokens[7].token_type, TokenType::Integer(-1));
+    }
+
+    #[test]
+    fn words_defined_as_names_are_not_expressions() {
+        let source = "\
+            JMP loop-end\n\
+            .equ max-size 4\n\
+            + io max-size\n\
+            loop-end LDA reg_1 loop-end+4\n\
+            LDA reg_2 max-size-loop-end\n\
+            LDA reg_3 (loop)-end\n\
+        ";
+        let mut tokens = Tokenizer::from_source("names.asm", source).tokenize().unwrap();
+        restore_names(&mut tokens);
+        let label = |name: &str| TokenType::Label(name.to_string());
+        assert_eq!(tokens[1].token_type, label("loop-end"));
+        assert_eq!(tokens[3].token_type, label("max-size"));
+        assert_eq!(tokens[7].token_type, label("max-size"));
+        assert_eq!(tokens[8].token_type, label("loop-end"));
+        assert_eq!(tokens[11].token_type, TokenType::Expression(expr::parse("loop-end+4").unwrap()));
+        assert_eq!(tokens[14].token_type, TokenType::Expression(expr::parse("max-size-loop-end").unwrap()));
+        assert_eq!(tokens[17].token_type, TokenType::Expression(expr::parse("(loop)-end").unwrap()));
+
+        let assembly = ::assemble("names.asm", "JMP loop-end\nloop-end END\n", Default::default()).unwrap();
+        assert_eq!(assembly.image.symbols.unwrap()[0].name, "loop-end");
+    }
+
+    #[test]
+    fn character_escapes() {
+        assert_eq!(parse_character("'A'"), Some('A'));
+        assert_eq!(parse_character("' '"), Some(' '));
+        assert_eq!(parse_character(r"'\n'"), Some('\n'));
+        assert_eq!(parse_character(r"'\t'"), Some('\t'));
+        assert_eq!(parse_character(r"'\0'"), Some('\0'));
+        assert_eq!(parse_character(r"'\\'"), Some('\\'));
+        assert_eq!(parse_character(r"'\''"), Some('\''));
+        assert_eq!(parse_character(r"'\x41'"), Some('A'));
+        assert_eq!(parse_character(r"'\q'"), None);
+        assert_eq!(parse_character(r"'\x4'"), None);
+        assert_eq!(parse_character("'ab'"), None);
+    }
+
+    #[test]
+    fn string_escapes() {
+        assert_eq!(parse_string(r#""a\tb\n""#), Some(b"a\tb\n".to_vec()));
+        assert_eq!(parse_string(r#""\xFF\0""#), Some(vec![0xFF, 0]));
+        assert_eq!(parse_string(r#""bad\"#), None);
+    }
+
+    #[test]
+    fn indirect_offsets_use_integer_formats() {
+        assert_eq!(parse_indirect("[reg_2]"), Some((Register::Reg2, Expr::Integer(0))));
+        assert_eq!(parse_indirect("[reg_2+0x0]"), Some((Register::Reg2, Expr::Integer(0))));
+        assert_eq!(parse_indirect("[fp-0b100]"), Some((Register::FP, Expr::Integer(-4))));
+        assert_eq!(parse_indirect("[sp+1_000]"), Some((Register::SP, Expr::Integer(1000))));
+        assert_eq!(parse_indirect("[sp+-4]"), None);
+        assert_eq!(parse_indirect("[sp+]"), None);
+    }
+
+    #[test]
+    fn indirect_offsets_can_be_expressions() {
+        assert_eq!(parse_indirect("[sp+x]"), Some((Register::SP, Expr::Symbol("x".to_string()))));
+        assert_eq!(parse_indirect("[fp-FRAME+4]"), Some((Register::FP, expr::parse("-FRAME+4").unwrap())));
+        assert_eq!(parse_indirect("[reg_1+(SIZE*2)]"), Some((Register::Reg1, expr::parse("SIZE*2").unwrap())));
+
+        for text in ["[fp-4]", "[sp+x]", "[fp-FRAME+4]", "[reg_1+SIZE*2]"] {
+            let (register, offset) = parse_indirect(text).unwrap();
+            assert_eq!(TokenType::Indirect(register, offset).to_string(), text);
+        }
+    }
+
+    #[test]
+    fn integer_formats() {
+        assert_eq!(parse_integer("42"), Some(42));
+        assert_eq!(parse_integer("-42"), Some(-42));
+        assert_eq!(parse_integer("1_000_000"), Some(1_000_000));
+        assert_eq!(parse_integer("0x2A"), Some(42));
+        assert_eq!(parse_integer("-0x2a"), Some(-42));
+        assert_eq!(parse_integer("0b1010_1010"), Some(170));
+        assert_eq!(parse_integer("0o17"), Some(15));
+        assert_eq!(parse_integer("0xFFFFFFFF"), Some(-1));
+        assert_eq!(parse_integer("2147483648"), None);
+        assert_eq!(parse_integer("0x"), None);
+        assert_eq!(parse_integer("_1"), None);
+        assert_eq!(parse_integer("0b12"), None);
+        assert_eq!(parse_integer("label"), None);
     }
 }
@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+use std::ops::Range;
+use assembler::{Command, INSTRUCTION_SIZE};
+use disasm;
+use image::Symbol;
+use tokenizer::{parse_integer, Register};
+use vm::VmTrap;
+
+/// How each traced instruction is written out
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TraceFormat {
+    /// One aligned line of text per instruction
+    #[default]
+    Text,
+
+    /// One JSON object per line
+    Json
+}
+
+/// Everything a single instruction did
+pub struct TraceEvent<'a> {
+    pub pc: i32,
+    pub command: &'a Command,
+
+    /// Registers the instruction changed, with their old and new values
+    pub registers: Vec<(Register, i32, i32)>,
+
+    /// Memory the instruction wrote to, with the bytes written
+    pub memory: Vec<(i32, &'a [u8])>,
+    pub trap: Option<&'a VmTrap>
+}
+
+/// Writes a log of the instructions a VM executes
+pub struct Tracer {
+    format: TraceFormat,
+    output: Box<dyn Write>,
+
+    /// Only instructions at addresses in these ranges are traced, or all of them if there are none
+    filters: Vec<Range<i32>>,
+
+    /// Name shown for each address which has a label
+    labels: HashMap<i32, String>
+}
+
+impl Tracer {
+    pub fn new(format: TraceFormat, output: Box<dyn Write>) -> Tracer {
+        Tracer {
+            format,
+            output,
+            filters: vec![],
+            labels: HashMap::new()
+        }
+    }
+
+    /// Names addresses using the labels in a symbol table
+    pub fn with_symbols(mut self, symbols: &[Symbol]) -> Tracer {
+        for symbol in symbols {
+            self.labels.entry(symbol.address as i32).or_insert_with(|| symbol.name.to_string());
+        }
+        self
+    }
+
+    /// Traces instructions with an address in `range`
+    /// Once any filter is added, instructions outside of every filter are skipped
+    pub fn with_filter(mut self, range: Range<i32>) -> Tracer {
+        self.filters.push(range);
+        self
+    }
+
+    pub fn is_traced(&self, pc: i32) -> bool {
+        self.filters.is_empty() || self.filters.iter().any(|range| range.contains(&pc))
+    }
+
+    pub fn record(&mut self, event: &TraceEvent) -> io::Result<()> {
+        let line = match self.format {
+            TraceFormat::Text => self.text(event),
+            TraceFormat::Json => self.json(event)
+        };
+        writeln!(self.output, "{}", line)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+
+    /// Whether a change to the PC is worth showing
+    /// Moving on to the next instruction is not
+    fn is_jump(register: &Register, old: i32, new: i32) -> bool {
+        *register != Register::PC || (new != old && new != old.wrapping_add(INSTRUCTION_SIZE))
+    }
+
+    /// Labelled instructions are preceded by a line holding the label
+    fn text(&self, event: &TraceEvent) -> String {
+        let mut line = match self.labels.get(&event.pc) {
+            Some(label) => format!("{}:\n", label),
+            None => String::new()
+        };
+        line.push_str(&format!("{:>8}: {:<24}", event.pc, disasm::format_instruction(event.command, &self.labels)));
+        for (register, old, new) in &event.registers {
+            if Tracer::is_jump(register, *old, *new) {
+                line.push_str(&format!("  {}: {} -> {}", register, old, new));
+            }
+        }
+        for (address, bytes) in &event.memory {
+            let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+            line.push_str(&format!("  [{}]: {}", address, bytes.join(" ")));
+        }
+        if let Some(trap) = event.trap {
+            line.push_str(&format!("  trap: {}", trap));
+        }
+        line.trim_end().to_string()
+    }
+
+    fn json(&self, event: &TraceEvent) -> String {
+        let registers: Vec<String> = event.registers.iter()
+            .filter(|(register, old, new)| Tracer::is_jump(register, *old, *new))
+            .map(|(register, old, new)| format!("\"{}\":[{},{}]", register, old, new))
+            .collect();
+        let memory: Vec<String> = event.memory.iter()
+            .map(|(address, bytes)| {
+                let bytes: Vec<String> = bytes.iter().map(|byte| byte.to_string()).collect();
+                format!("{{\"address\":{},\"bytes\":[{}]}}", address, bytes.join(","))
+            })
+            .collect();
+
+        let mut line = format!("{{\"pc\":{}", event.pc);
+        if let Some(label) = self.labels.get(&event.pc) {
+            line.push_str(&format!(",\"label\":{}", json_string(label)));
+        }
+        line.push_str(&format!(
+            ",\"instruction\":{},\"registers\":{{{}}},\"memory\":[{}]",
+            json_string(&disasm::format_instruction(event.command, &self.labels)),
+            registers.join(","),
+            memory.join(",")
+        ));
+        if let Some(trap) = event.trap {
+            line.push_str(&format!(",\"trap\":{}", json_string(&trap.to_string())));
+        }
+        line.push('}');
+        line
+    }
+}
+
+/// Quotes a string for JSON, escaping anything which needs it
+fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c)
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Parses a trace filter, which is either a label or a range of addresses or labels such as `Loop..Done`
+/// A label on its own covers everything up to the next label
+pub fn parse_filter(filter: &str, symbols: &[Symbol]) -> Result<Range<i32>, String> {
+    let address = |text: &str| -> Result<i32, String> {
+        match symbols.iter().find(|symbol| symbol.name == text) {
+            Some(symbol) => Ok(symbol.address as i32),
+            None => parse_integer(text).ok_or_else(|| format!("unknown label `{}`", text))
+        }
+    };
+
+    if let Some(index) = filter.find("..") {
+        return Ok(address(&filter[..index])?..address(&filter[index + 2..])?);
+    }
+    let start = address(filter)?;
+    let end = symbols.iter()
+        .map(|symbol| symbol.address as i32)
+        .filter(|address| *address > start)
+        .min()
+        .unwrap_or(i32::MAX);
+    Ok(start..end)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use device::Streams;
+    use memory::VmConfig;
+    use test_support::assemble;
+    use vm::{TrapKind, VM};
+    use super::*;
+
+    /// Collects what the tracer writes so it can be checked after the VM is done with it
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Output which cannot be written to
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn trace(path: &str, format: TraceFormat, filter: Option<&str>) -> Vec<String> {
+        let image = assemble(path).image;
+        let symbols = image.symbols.clone().unwrap();
+        let buffer = SharedBuffer::default();
+        let mut tracer = Tracer::new(format, Box::new(buffer.clone())).with_symbols(&symbols);
+        if let Some(filter) = filter {
+            tracer = tracer.with_filter(parse_filter(filter, &symbols).unwrap());
+        }
+
+        let mut vm = VM::from_image(&image, &VmConfig::default(), Streams::new(io::empty(), io::sink())).unwrap();
+        vm.set_tracer(Some(tracer));
+        vm.run(image.entry_point as usize).unwrap();
+        let output = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        output.lines().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn text_shows_changes() {
+        let lines = trace("test_files/27-call-stack.asm", TraceFormat::Text, None);
+        assert_eq!(lines[0], "       0: + reg_1 21                reg_1: 0 -> 21");
+        assert_eq!(lines[2], "      24: CALL Double               pc: 24 -> 72  sp: 9999996 -> 9999992  [9999992]: 24 00 00 00");
+        assert_eq!(lines[3], "Double:");
+        assert_eq!(lines.last().unwrap(), "      60: END");
+    }
+
+    #[test]
+    fn json_lines() {
+        let lines = trace("test_files/27-call-stack.asm", TraceFormat::Json, None);
+        assert_eq!(lines[1], "{\"pc\":12,\"instruction\":\"PUSH reg_1\",\"registers\":{\"sp\":[10000000,9999996]},\"memory\":[{\"address\":9999996,\"bytes\":[21,0,0,0]}]}");
+        assert_eq!(lines[3], "{\"pc\":72,\"label\":\"Double\",\"instruction\":\"ENTER 4\",\"registers\":{\"sp\":[9999992,9999984],\"fp\":[10000000,9999988]},\"memory\":[{\"address\":9999988,\"bytes\":[128,150,152,0]}]}");
+    }
+
+    #[test]
+    fn filters_by_label_and_range() {
+        let lines = trace("test_files/27-call-stack.asm", TraceFormat::Text, Some("Double"));
+        assert_eq!(lines.len(), 10);
+        assert!(lines[1].starts_with("      72: ENTER 4"));
+
+        let lines = trace("test_files/27-call-stack.asm", TraceFormat::Text, Some("0..24"));
+        assert_eq!(lines.len(), 2);
+    }
+
+    #[test]
+    fn failing_to_write_traps_after_the_instruction() {
+        let image = assemble("test_files/27-call-stack.asm").image;
+        let mut vm = VM::from_image(&image, &VmConfig::default(), Streams::new(io::empty(), io::sink())).unwrap();
+        vm.set_tracer(Some(Tracer::new(TraceFormat::Text, Box::new(Broken))));
+        let broken = VmTrap { kind: TrapKind::TraceError("broken".to_string()), pc: 0 };
+        assert_eq!(vm.run(0), Err(broken));
+        assert_eq!(vm.get_register(Register::Reg1), 21);
+        assert_eq!(vm.pc(), INSTRUCTION_SIZE);
+    }
+
+    #[test]
+    fn escapes_json_strings() {
+        assert_eq!(json_string("a \"b\"\\\n\u{1}"), "\"a \\\"b\\\"\\\\\\n\\u0001\"");
+    }
+}
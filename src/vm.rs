@@ -1,82 +1,627 @@
+use std::fmt;
 use std::io;
 use std::io::Cursor;
+use std::ops::Range;
 use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
-use assembler::{Command, CommandType};
+use device::{Console, Device};
+use assembler::{decode_indirect, Command, CommandType, Section, INSTRUCTION_SIZE};
+use image::{Image, ImageSection};
+use memory::{LoadError, VmConfig};
 use tokenizer::*;
+use trace::{TraceEvent, Tracer};
 
-pub struct VM {
+/// Highest opcode used by any instruction
+const MAX_OPCODE: i32 = 46;
+
+/// Size of a value pushed onto the stack in bytes
+const STACK_SLOT_SIZE: i32 = 4;
+
+/// Default amount of memory available to a program
+pub const MAX_MEMORY: usize = 10_000_000; // 10MB
+
+/// Describes how a program finished running
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExitStatus {
+    /// The program reached an `END` instruction
+    Halted,
+
+    /// The program executed as many instructions as it was allowed to
+    StepLimitReached,
+
+    /// The next instruction would have cost more than was left of the budget
+    BudgetExhausted
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExitStatus::Halted => write!(f, "halted"),
+            ExitStatus::StepLimitReached => write!(f, "step limit reached"),
+            ExitStatus::BudgetExhausted => write!(f, "budget exhausted")
+        }
+    }
+}
+
+/// How much of the budget each instruction uses up when it is executed
+/// Every instruction costs 1 unless told otherwise
+#[derive(Clone, Debug, PartialEq)]
+pub struct CostTable {
+    /// Cost of each instruction, indexed by opcode
+    costs: Vec<u64>
+}
+
+impl CostTable {
+    pub fn new() -> CostTable {
+        CostTable {
+            costs: vec![1; MAX_OPCODE as usize + 1]
+        }
+    }
+
+    pub fn cost(&self, instruction: &InstructionType) -> u64 {
+        self.costs[instruction.to_bytecode() as usize]
+    }
+
+    pub fn set_cost(&mut self, instruction: &InstructionType, cost: u64) {
+        self.costs[instruction.to_bytecode() as usize] = cost;
+    }
+
+    /// Sets the cost of every instruction written as `mnemonic`, such as `LDW` or `+`
+    /// Returns false if there is no such instruction
+    pub fn set_cost_by_name(&mut self, mnemonic: &str, cost: u64) -> bool {
+        let instructions: Vec<InstructionType> = (0..=MAX_OPCODE)
+            .filter_map(InstructionType::from_bytecode)
+            .filter(|instruction| TokenType::Instruction(instruction.clone()).to_string() == mnemonic)
+            .collect();
+        for instruction in &instructions {
+            self.set_cost(instruction, cost);
+        }
+        !instructions.is_empty()
+    }
+}
+
+impl Default for CostTable {
+    fn default() -> CostTable {
+        CostTable::new()
+    }
+}
+
+/// Outcome of executing one or more instructions
+#[derive(Clone, Debug, PartialEq)]
+pub enum StepResult {
+    /// The program has not finished and can keep running
+    Continue,
+
+    /// The program finished
+    Exited(ExitStatus),
+
+    /// An instruction caused a fault
+    Trapped(VmTrap)
+}
+
+impl From<Result<Option<ExitStatus>, VmTrap>> for StepResult {
+    fn from(result: Result<Option<ExitStatus>, VmTrap>) -> StepResult {
+        match result {
+            Ok(None) => StepResult::Continue,
+            Ok(Some(status)) => StepResult::Exited(status),
+            Err(trap) => StepResult::Trapped(trap)
+        }
+    }
+}
+
+/// The reason the VM stopped executing a program early
+#[derive(Clone, Debug, PartialEq)]
+pub enum TrapKind {
+    /// The opcode does not belong to any known instruction
+    IllegalOpcode(i32),
+
+    /// An instruction was fetched from outside of memory
+    FetchOutOfBounds(i32),
+
+    /// Data was loaded from outside of memory
+    LoadOutOfBounds(i32),
+
+    /// Data was stored outside of memory
+    StoreOutOfBounds(i32),
+
+    /// Integer division with a divisor of zero
+    DivideByZero,
+
+    /// An operand referenced a register which does not exist
+    BadRegister(i32),
+
+    /// The result of an arithmetic instruction does not fit in a register
+    ArithmeticOverflow,
+
+    /// The stack pointer moved past the stack limit
+    StackOverflow,
+
+    /// The stack pointer moved above the stack base
+    StackUnderflow,
+
+    /// Reading from or writing to the device failed
+    DeviceError(String),
+
+    /// Writing to the trace failed
+    /// The instruction being traced has still been executed
+    TraceError(String)
+}
+
+impl fmt::Display for TrapKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrapKind::IllegalOpcode(opcode) => write!(f, "illegal opcode {}", opcode),
+            TrapKind::FetchOutOfBounds(address) =>
+                write!(f, "instruction fetch out of bounds at address {}", address),
+            TrapKind::LoadOutOfBounds(address) =>
+                write!(f, "load out of bounds at address {}", address),
+            TrapKind::StoreOutOfBounds(address) =>
+                write!(f, "store out of bounds at address {}", address),
+            TrapKind::DivideByZero => write!(f, "divide by zero"),
+            TrapKind::BadRegister(register) => write!(f, "bad register index {}", register),
+            TrapKind::ArithmeticOverflow => write!(f, "arithmetic overflow"),
+            TrapKind::StackOverflow => write!(f, "stack overflow"),
+            TrapKind::StackUnderflow => write!(f, "stack underflow"),
+            TrapKind::DeviceError(err) => write!(f, "device error: {}", err),
+            TrapKind::TraceError(err) => write!(f, "trace error: {}", err)
+        }
+    }
+}
+
+/// A fault raised while executing a program
+#[derive(Clone, Debug, PartialEq)]
+pub struct VmTrap {
+    pub kind: TrapKind,
+
+    /// Address of the instruction which caused the fault
+    pub pc: i32
+}
+
+impl fmt::Display for VmTrap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (pc = {})", self.kind, self.pc)
+    }
+}
+
+/// Runs programs, reading input from and writing output to a device
+/// The console is used unless another device is given
+pub struct VM<D: Device = Console> {
     registers: [i32; 13],
-    memory: Vec<u8>
+    memory: Vec<u8>,
+    device: D,
+
+    /// Most instructions `run` may execute, if limited
+    step_limit: Option<u64>,
+
+    /// Most the program may spend on instructions, if limited
+    budget: Option<u64>,
+    costs: CostTable,
+
+    /// How much of the budget has been spent so far
+    spent: u64,
+
+    /// Number of instructions which have finished executing
+    executed: u64,
+
+    tracer: Option<Tracer>,
+
+    /// Memory written by the instruction being traced, as addresses and lengths
+    writes: Option<Vec<(i32, usize)>>
 }
 
-impl VM {
-    pub fn new(code: Vec<u8>) -> VM {
-        // Expand available memory
-        const MAX_MEMORY: usize = 10_000_000; // 10MB
-        let mut memory = vec![0; MAX_MEMORY];
-
-        // Copy bytecode into memory
-        let mut i = code.len();
-        while i > 0 {
-            i -= 1;
-            memory[i] = code[i];
+impl VM<Console> {
+    pub fn new(code: Vec<u8>) -> Result<VM, LoadError> {
+        VM::with_memory_size(code, MAX_MEMORY)
+    }
+
+    /// Creates a VM with `memory_size` bytes of memory which uses the console for I/O
+    pub fn with_memory_size(code: Vec<u8>, memory_size: usize) -> Result<VM, LoadError> {
+        VM::with_device(code, memory_size, Console::new())
+    }
+}
+
+impl<D: Device> VM<D> {
+    /// Creates a VM with `memory_size` bytes of memory which uses `device` for I/O
+    /// The code is loaded at address 0 and the stack sits at the top of memory
+    pub fn with_device(code: Vec<u8>, memory_size: usize, device: D) -> Result<VM<D>, LoadError> {
+        let image = Image {
+            entry_point: 0,
+            sections: vec![ImageSection { kind: Section::Text, address: 0, data: code }],
+            symbols: None
+        };
+        VM::from_image(&image, &VmConfig::new(memory_size), device)
+    }
+
+    /// Creates a VM which uses `device` for I/O and loads `image` into memory laid out by `config`
+    /// The stack registers are set from the layout and the PC points at the image's entry point
+    pub fn from_image(image: &Image, config: &VmConfig, device: D) -> Result<VM<D>, LoadError> {
+        let stack = config.layout.stack(image, config.memory_size)?;
+        let mut memory = vec![0; config.memory_size];
+
+        // Only sections with data are checked against the layout, so an empty one may be anywhere
+        for section in image.sections.iter().filter(|section| !section.data.is_empty()) {
+            let start = section.address as usize;
+            memory[start..start + section.data.len()].copy_from_slice(&section.data);
         }
-        VM {
-            registers: [0; 13],
-            memory: memory
+
+        // The stack grows downward from its top
+        let mut registers = [0; 13];
+        registers[Register::SB as usize] = stack.end as i32;
+        registers[Register::SP as usize] = stack.end as i32;
+        registers[Register::FP as usize] = stack.end as i32;
+        registers[Register::SL as usize] = stack.start as i32;
+        registers[Register::PC as usize] = image.entry_point as i32;
+
+        Ok(VM {
+            registers,
+            memory,
+            device,
+            step_limit: None,
+            budget: None,
+            costs: CostTable::new(),
+            spent: 0,
+            executed: 0,
+            tracer: None,
+            writes: None
+        })
+    }
+
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    pub fn device_mut(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    /// Stops `run` after it has executed `limit` instructions
+    pub fn set_step_limit(&mut self, limit: Option<u64>) {
+        self.step_limit = limit;
+    }
+
+    /// Stops the program before it executes an instruction which would take its spending past `budget`
+    /// Spending is counted from the start of the program, using `costs` to price each instruction
+    pub fn set_budget(&mut self, budget: Option<u64>, costs: CostTable) {
+        self.budget = budget;
+        self.costs = costs;
+    }
+
+    /// Number of instructions executed so far, not counting any which trapped
+    pub fn instructions_executed(&self) -> u64 {
+        self.executed
+    }
+
+    /// How much of the budget the instructions executed so far have cost
+    pub fn budget_spent(&self) -> u64 {
+        self.spent
+    }
+
+    /// Records every instruction executed from now on with `tracer`
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    pub fn run(&mut self, start_address: usize) -> Result<ExitStatus, VmTrap> {
+        self.set_pc(start_address as i32);
+        let result = match self.step_limit {
+            Some(limit) => self.run_for(limit),
+            None => loop {
+                let result = StepResult::from(self.try_step());
+                if result != StepResult::Continue {
+                    break self.finish(result);
+                }
+            }
+        };
+        match result {
+            StepResult::Continue => Ok(ExitStatus::StepLimitReached),
+            StepResult::Exited(status) => Ok(status),
+            StepResult::Trapped(trap) => Err(trap)
         }
     }
 
-    pub fn run(&mut self, start_address: usize) {
-        let pc = Register::PC.to_bytecode() as usize;
-        self.registers[pc] = start_address as i32;
-
-        loop {
-            let address = self.registers[pc] as usize;
-            let bytecode = {
-                let mut memory = Cursor::new(&mut self.memory[address..]);
-                [
-                    memory.read_i32::<LittleEndian>().unwrap(),
-                    memory.read_i32::<LittleEndian>().unwrap(),
-                    memory.read_i32::<LittleEndian>().unwrap(),
-                ]
-            };
-
-            let command = Command::from_bytecode(&bytecode);
-            let running = match command.cmd_type {
-                CommandType::Instruction(instruction) =>
-                    self.execute(instruction, &bytecode),
-                _ => false
-            };
-            if !running {
+    /// Executes the instruction the PC points to
+    pub fn step(&mut self) -> StepResult {
+        let result = StepResult::from(self.try_step());
+        self.finish(result)
+    }
+
+    /// Executes at most `count` instructions
+    /// Returns `StepResult::Continue` if the program is still running afterwards
+    pub fn run_for(&mut self, count: u64) -> StepResult {
+        let mut result = StepResult::Continue;
+        for _ in 0..count {
+            result = StepResult::from(self.try_step());
+            if result != StepResult::Continue {
                 break;
             }
+        }
+        self.finish(result)
+    }
+
+    /// Executes instructions until `predicate` holds after one of them
+    /// Returns `StepResult::Continue` if the predicate stopped the program
+    pub fn run_until<F: FnMut(&VM<D>) -> bool>(&mut self, mut predicate: F) -> StepResult {
+        let result = loop {
+            let result = StepResult::from(self.try_step());
+            if result != StepResult::Continue || predicate(self) {
+                break result;
+            }
+        };
+        self.finish(result)
+    }
+
+    /// Executes the instruction the PC points to
+    /// Returns how the program finished if the instruction ended it
+    fn try_step(&mut self) -> Result<Option<ExitStatus>, VmTrap> {
+        let bytecode = self.fetch()?;
+        let instruction = match Command::from_bytecode(&bytecode).cmd_type {
+            CommandType::Instruction(instruction) => instruction,
+            _ => return Err(self.trap(TrapKind::IllegalOpcode(bytecode[0])))
+        };
+
+        // An instruction which would go over the budget is not executed
+        let spent = self.spent.saturating_add(self.costs.cost(&instruction));
+        if self.budget.is_some_and(|budget| spent > budget) {
+            return Ok(Some(ExitStatus::BudgetExhausted));
+        }
+
+        let traced = match &self.tracer {
+            Some(tracer) => tracer.is_traced(self.pc()),
+            None => false
+        };
+        let (result, recorded) = if traced {
+            let registers = self.registers;
+            self.writes = Some(vec![]);
+            let result = self.execute_next(instruction, &bytecode);
+            let recorded = self.trace(&registers, &bytecode, result.as_ref().err());
+            (result, recorded)
+        } else {
+            (self.execute_next(instruction, &bytecode), Ok(()))
+        };
+
+        if result.is_ok() {
+            self.spent = spent;
+            self.executed += 1;
+        }
+        recorded.and(result)
+    }
+
+    /// Reads the instruction the PC points to
+    fn fetch(&self) -> Result<[i32; 3], VmTrap> {
+        let address = self.pc();
+        let range = match self.memory_range(address, INSTRUCTION_SIZE as usize) {
+            Some(range) => range,
+            None => return Err(self.trap(TrapKind::FetchOutOfBounds(address)))
+        };
+        let mut memory = Cursor::new(&self.memory[range]);
+        Ok([
+            memory.read_i32::<LittleEndian>().unwrap(),
+            memory.read_i32::<LittleEndian>().unwrap(),
+            memory.read_i32::<LittleEndian>().unwrap(),
+        ])
+    }
+
+    /// Executes a fetched instruction and moves the PC on to the next one
+    fn execute_next(&mut self, instruction: InstructionType, bytecode: &[i32; 3]) -> Result<Option<ExitStatus>, VmTrap> {
+        let pc = Register::PC.to_bytecode() as usize;
+        let status = self.execute(instruction, bytecode)?;
+        if status.is_none() {
+            self.registers[pc] = self.registers[pc].wrapping_add(INSTRUCTION_SIZE);
+        }
+        Ok(status)
+    }
+
+    /// Tells the tracer what the last instruction changed
+    /// `registers` holds the values the registers had before it ran
+    /// Traps if the tracer could not write the instruction out, unless it had already trapped
+    fn trace(&mut self, registers: &[i32; 13], bytecode: &[i32; 3], trap: Option<&VmTrap>) -> Result<(), VmTrap> {
+        let writes = self.writes.take().unwrap_or_default();
+        let tracer = match &mut self.tracer {
+            Some(tracer) => tracer,
+            None => return Ok(())
+        };
+        let (current, memory) = (&self.registers, &self.memory);
+        let command = Command::from_bytecode(bytecode);
+        let changed = (0..registers.len())
+            .filter(|index| registers[*index] != current[*index])
+            .map(|index| (Register::from_bytecode(index as i32).unwrap(), registers[index], current[index]))
+            .collect();
+        let memory = writes.iter()
+            .map(|(address, len)| (*address, &memory[*address as usize..*address as usize + len]))
+            .collect();
+        let pc = registers[Register::PC as usize];
+        let event = TraceEvent {
+            pc,
+            command: &command,
+            registers: changed,
+            memory,
+            trap
+        };
+        match tracer.record(&event) {
+            Err(err) if trap.is_none() => Err(VmTrap { kind: TrapKind::TraceError(err.to_string()), pc }),
+            _ => Ok(())
+        }
+    }
+
+    /// Makes sure everything the program printed has been written out
+    /// Failing to do so traps, unless the program had already trapped
+    fn finish(&mut self, result: StepResult) -> StepResult {
+        match self.flush() {
+            Err(trap) if !matches!(result, StepResult::Trapped(_)) => StepResult::Trapped(trap),
+            _ => result
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), VmTrap> {
+        let traced = match self.tracer.as_mut().map(|tracer| tracer.flush()) {
+            Some(Err(err)) => Err(self.trap(TrapKind::TraceError(err.to_string()))),
+            _ => Ok(())
+        };
+        let written = self.device.flush().map_err(|err| self.device_error(err));
+        written.and(traced)
+    }
+
+    pub fn get_register(&self, register: Register) -> i32 {
+        self.registers[register as usize]
+    }
+
+    pub fn set_register(&mut self, register: Register, value: i32) {
+        self.registers[register as usize] = value;
+    }
+
+    pub fn pc(&self) -> i32 {
+        self.get_register(Register::PC)
+    }
+
+    /// Moves execution to `address`
+    pub fn set_pc(&mut self, address: i32) {
+        self.set_register(Register::PC, address);
+    }
+
+    /// The whole of the VM's memory
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
 
-            self.registers[pc] += 12;
+    /// The `len` bytes of memory starting at `address`, if they all exist
+    pub fn read_memory(&self, address: i32, len: usize) -> Option<&[u8]> {
+        let range = self.memory_range(address, len)?;
+        Some(&self.memory[range])
+    }
+
+    /// Builds a trap for the instruction currently pointed to by the PC
+    fn trap(&self, kind: TrapKind) -> VmTrap {
+        VmTrap {
+            kind,
+            pc: self.registers[Register::PC as usize]
         }
     }
 
-    fn execute(&mut self, instruction: InstructionType, bytecode: &[i32; 3]) -> bool {
+    fn device_error(&self, err: io::Error) -> VmTrap {
+        self.trap(TrapKind::DeviceError(err.to_string()))
+    }
+
+    /// Validates a register operand and converts it to an index
+    fn register(&self, code: i32) -> Result<usize, VmTrap> {
+        match Register::from_bytecode(code) {
+            Some(_) => Ok(code as usize),
+            None => Err(self.trap(TrapKind::BadRegister(code)))
+        }
+    }
+
+    /// Resolves the address operand of a load or store instruction
+    /// Register-indirect forms add an offset to the value of a base register
+    fn address(&self, instruction: &InstructionType, operand: i32) -> Result<i32, VmTrap> {
+        use tokenizer::InstructionType::*;
+        match instruction {
+            LoadByteIndirect |
+            LoadByteSignedIndirect |
+            LoadHalfIndirect |
+            LoadHalfSignedIndirect |
+            LoadWordIndirect |
+            StoreByteIndirect |
+            StoreHalfIndirect |
+            StoreWordIndirect => {
+                let (base, offset) = decode_indirect(operand);
+                let base = self.register(base)?;
+                Ok(self.registers[base].wrapping_add(offset))
+            },
+            _ => Ok(operand)
+        }
+    }
+
+    /// Range of `len` bytes of memory starting at `address`, if they all exist
+    fn memory_range(&self, address: i32, len: usize) -> Option<Range<usize>> {
+        if address < 0 {
+            return None;
+        }
+        let start = address as usize;
+        let end = start.checked_add(len)?;
+        if end > self.memory.len() {
+            None
+        } else {
+            Some(start..end)
+        }
+    }
+
+    fn load(&self, address: i32, len: usize) -> Result<&[u8], VmTrap> {
+        match self.memory_range(address, len) {
+            Some(range) => Ok(&self.memory[range]),
+            None => Err(self.trap(TrapKind::LoadOutOfBounds(address)))
+        }
+    }
+
+    /// Memory an instruction is about to write to
+    fn store(&mut self, address: i32, len: usize) -> Result<&mut [u8], VmTrap> {
+        match self.memory_range(address, len) {
+            Some(range) => {
+                if let Some(writes) = &mut self.writes {
+                    writes.push((address, len));
+                }
+                Ok(&mut self.memory[range])
+            },
+            None => Err(self.trap(TrapKind::StoreOutOfBounds(address)))
+        }
+    }
+
+    /// Pushes a value onto the stack
+    fn push(&mut self, value: i32) -> Result<(), VmTrap> {
+        let sp = self.registers[Register::SP as usize];
+        let new_sp = sp.wrapping_sub(STACK_SLOT_SIZE);
+        if sp < STACK_SLOT_SIZE || new_sp < self.registers[Register::SL as usize] {
+            return Err(self.trap(TrapKind::StackOverflow));
+        }
+        let mut memory = self.store(new_sp, STACK_SLOT_SIZE as usize)?;
+        memory.write_i32::<LittleEndian>(value).unwrap();
+        self.registers[Register::SP as usize] = new_sp;
+        Ok(())
+    }
+
+    /// Pops a value off of the stack
+    fn pop(&mut self) -> Result<i32, VmTrap> {
+        let sp = self.registers[Register::SP as usize];
+        match sp.checked_add(STACK_SLOT_SIZE) {
+            Some(new_sp) if new_sp <= self.registers[Register::SB as usize] => {
+                let value = self.load(sp, STACK_SLOT_SIZE as usize)?
+                    .read_i32::<LittleEndian>()
+                    .unwrap();
+                self.registers[Register::SP as usize] = new_sp;
+                Ok(value)
+            },
+            _ => Err(self.trap(TrapKind::StackUnderflow))
+        }
+    }
+
+    /// Applies a checked arithmetic operation, trapping if it overflows
+    fn arithmetic(&self, result: Option<i32>) -> Result<i32, VmTrap> {
+        match result {
+            Some(value) => Ok(value),
+            None => Err(self.trap(TrapKind::ArithmeticOverflow))
+        }
+    }
+
+    fn execute(&mut self, instruction: InstructionType, bytecode: &[i32; 3]) -> Result<Option<ExitStatus>, VmTrap> {
         use tokenizer::InstructionType::*;
         match instruction {
             // Add together two registers and store the result in the first
             Add => {
-                let destination = bytecode[1] as usize;
-                let source = bytecode[2] as usize;
-                self.registers[destination] += self.registers[source];
+                let destination = self.register(bytecode[1])?;
+                let source = self.register(bytecode[2])?;
+                let result = self.registers[destination].checked_add(self.registers[source]);
+                self.registers[destination] = self.arithmetic(result)?;
             },
 
             // Add an immediate value to a register
             AddImmediate => {
-                let register = bytecode[1] as usize;
+                let register = self.register(bytecode[1])?;
                 let value = bytecode[2];
-                self.registers[register] += value;
+                let result = self.registers[register].checked_add(value);
+                self.registers[register] = self.arithmetic(result)?;
             },
 
             // Perform a boolean AND on two registers
             And => {
-                let reg1 = bytecode[1] as usize;
-                let reg2 = bytecode[2] as usize;
+                let reg1 = self.register(bytecode[1])?;
+                let reg2 = self.register(bytecode[2])?;
                 let reg1_value = self.registers[reg1];
                 let reg2_value = self.registers[reg2];
                 self.registers[reg1] = if reg1_value != 0 && reg2_value != 0 {
@@ -86,13 +631,25 @@ impl VM {
                 };
             },
 
+            // Push the address of the next instruction onto the stack
+            // then jump to the specified address
+            Call => {
+                let return_address = self.registers[Register::PC as usize]
+                    .wrapping_add(INSTRUCTION_SIZE);
+                self.push(return_address)?;
+
+                // Remove offset that will be automatically applied
+                let address = bytecode[1].wrapping_sub(INSTRUCTION_SIZE);
+                self.registers[Register::PC as usize] = address;
+            },
+
             // Compares the contents of two registers
             // -1 if the first is less than the second
             // 1  if the first is greater than the second
             // 0  if they're equal
             Compare => {
-                let reg1 = bytecode[1] as usize;
-                let reg2 = bytecode[2] as usize;
+                let reg1 = self.register(bytecode[1])?;
+                let reg2 = self.register(bytecode[2])?;
                 let val1 = self.registers[reg1];
                 let val2 = self.registers[reg2];
                 self.registers[reg1] = if val1 < val2 {
@@ -106,10 +663,10 @@ impl VM {
 
             // Jumps to an address if the given register contains a zero value
             CompareZeroJump => {
-                let register = bytecode[1] as usize;
+                let register = self.register(bytecode[1])?;
                 let address = bytecode[2];
                 // Remove offset that will be automatically applied
-                let address = address - 12;
+                let address = address.wrapping_sub(INSTRUCTION_SIZE);
                 if self.registers[register] == 0 {
                     self.registers[Register::PC as usize] = address;
                 }
@@ -118,9 +675,9 @@ impl VM {
             // Converts the ASCII representation of a number to the equivalent integer
             // '5' => 5
             ConvertASCIIToInteger => {
-                let mut ascii = self.registers[Register::IO as usize];
-                ascii -= '0' as i32;
-                self.registers[Register::IO as usize] = if ascii < 0 || ascii > 9 {
+                let ascii = self.registers[Register::IO as usize];
+                let ascii = ascii.wrapping_sub('0' as i32);
+                self.registers[Register::IO as usize] = if !(0..=9).contains(&ascii) {
                     -1
                 } else {
                     ascii
@@ -130,9 +687,9 @@ impl VM {
             // Converts an integer value to the equivalent ASCII character
             // 5 => '5'
             ConvertIntegerToASCII => {
-                let mut integer = self.registers[Register::IO as usize];
-                integer += '0' as i32;
-                self.registers[Register::IO as usize] = if integer < 48 || integer > 57 {
+                let integer = self.registers[Register::IO as usize];
+                let integer = integer.wrapping_add('0' as i32);
+                self.registers[Register::IO as usize] = if !(48..=57).contains(&integer) {
                     48
                 } else {
                     integer
@@ -141,47 +698,70 @@ impl VM {
 
             // Perform integer division between two registers
             Divide => {
-                let destination = bytecode[1] as usize;
-                let source = bytecode[2] as usize;
-                self.registers[destination] /= self.registers[source];
+                let destination = self.register(bytecode[1])?;
+                let source = self.register(bytecode[2])?;
+                let divisor = self.registers[source];
+                if divisor == 0 {
+                    return Err(self.trap(TrapKind::DivideByZero));
+                }
+                let result = self.registers[destination].checked_div(divisor);
+                self.registers[destination] = self.arithmetic(result)?;
             },
 
             // If the contents of a register are greater than 0
             // jump to the specified address
             GreaterThanZeroJump => {
-                let register = bytecode[1] as usize;
+                let register = self.register(bytecode[1])?;
                 let address = bytecode[2];
                 // Remove offset that will be automatically applied
-                let address = address - 12;
+                let address = address.wrapping_sub(INSTRUCTION_SIZE);
                 if self.registers[register] > 0 {
                     self.registers[Register::PC as usize] = address;
                 }
             },
 
+            // Set up a new stack frame
+            // Saves the frame pointer, points it at the top of the stack
+            // then reserves the given number of bytes for locals
+            // Nothing is changed if the frame does not fit on the stack
+            Enter => {
+                let locals = bytecode[1];
+                let frame = self.registers[Register::SP as usize].wrapping_sub(STACK_SLOT_SIZE);
+                let new_sp = match frame.checked_sub(locals) {
+                    Some(new_sp) if locals >= 0 && new_sp >= self.registers[Register::SL as usize] => new_sp,
+                    _ => return Err(self.trap(TrapKind::StackOverflow))
+                };
+
+                let frame_pointer = self.registers[Register::FP as usize];
+                self.push(frame_pointer)?;
+                self.registers[Register::FP as usize] = frame;
+                self.registers[Register::SP as usize] = new_sp;
+            },
+
             // Take in a character from the user and store it in the IO register
+            // Stores -1 if there is no more input
             InputASCII => {
                 let mut input = String::new();
-                match io::stdin().read_line(&mut input) {
-                    Ok(_) => {
-                        let character = input.chars().nth(0).unwrap();
-                        self.registers[Register::IO as usize] = character as i32;
-                    },
-                    Err(err) => println!("error: {}", err)
+                if let Err(err) = self.device.read_line(&mut input) {
+                    return Err(self.device_error(err));
                 }
+                let character = match input.chars().next() {
+                    Some(character) => character as i32,
+                    None => -1
+                };
+                self.registers[Register::IO as usize] = character;
             },
 
             // Take in a number from the user and store it in the IO register
             InputInteger => {
                 let mut input = String::new();
-                match io::stdin().read_line(&mut input) {
-                    Ok(_) => {
-                        let num = input.trim().parse::<i32>();
-                        match num {
-                            Ok(n) => self.registers[Register::IO as usize] = n,
-                            Err(err) => println!("error: {}", err)
-                        }
-                    },
-                    Err(err) => println!("error: {}", err)
+                if let Err(err) = self.device.read_line(&mut input) {
+                    return Err(self.device_error(err));
+                }
+                let num = input.trim().parse::<i32>();
+                match num {
+                    Ok(n) => self.registers[Register::IO as usize] = n,
+                    Err(err) => eprintln!("error: {}", err)
                 }
             },
 
@@ -190,27 +770,46 @@ impl VM {
                 let address = bytecode[1];
 
                 // Remove offset that will be automatically applied
-                let address = address - 12;
+                let address = address.wrapping_sub(INSTRUCTION_SIZE);
                 self.registers[Register::PC as usize] = address;
             },
 
             // Jumps to an address stored in a register
             JumpRelative => {
-                let register = bytecode[1] as usize;
+                let register = self.register(bytecode[1])?;
                 let address = self.registers[register];
 
                 // Remove offset that will be automatically applied
-                let address = address - 12;
+                let address = address.wrapping_sub(INSTRUCTION_SIZE);
                 self.registers[Register::PC as usize] = address;
             },
 
+            // Tear down the current stack frame
+            // Discards any locals and restores the previous frame pointer
+            // The stack pointer is left where it was if there is no saved frame pointer
+            Leave => {
+                let frame_pointer = self.registers[Register::FP as usize];
+                if frame_pointer > self.registers[Register::SB as usize] {
+                    return Err(self.trap(TrapKind::StackUnderflow));
+                }
+                let sp = self.registers[Register::SP as usize];
+                self.registers[Register::SP as usize] = frame_pointer;
+                match self.pop() {
+                    Ok(value) => self.registers[Register::FP as usize] = value,
+                    Err(trap) => {
+                        self.registers[Register::SP as usize] = sp;
+                        return Err(trap);
+                    }
+                }
+            },
+
             // If the contents of a register are less than 0
             // jump to the specified address
             LessThanZeroJump => {
-                let register = bytecode[1] as usize;
+                let register = self.register(bytecode[1])?;
                 let address = bytecode[2];
                 // Remove offset that will be automatically applied
-                let address = address - 12;
+                let address = address.wrapping_sub(INSTRUCTION_SIZE);
                 if self.registers[register] < 0 {
                     self.registers[Register::PC as usize] = address;
                 }
@@ -218,52 +817,81 @@ impl VM {
 
             // Loads the address of a label into a register
             LoadAddress => {
-                let register = bytecode[1] as usize;
+                let register = self.register(bytecode[1])?;
                 let address = bytecode[2];
                 self.registers[register] = address;
             },
 
             // Load a byte of data from memory and place it into a register
-            LoadByte => {
-                let register = bytecode[1] as usize;
-                let address = bytecode[2] as usize;
-                let mut memory = Cursor::new(&mut self.memory[address..]);
-                let value = memory.read_u8().unwrap();
+            // The byte is treated as unsigned
+            LoadByte |
+            LoadByteIndirect => {
+                let register = self.register(bytecode[1])?;
+                let address = self.address(&instruction, bytecode[2])?;
+                let value = self.load(address, 1)?[0];
                 self.registers[register] = value as i32;
             },
 
-            // Load a word of data from memory and place it into a register
-            LoadWord => {
-                let register = bytecode[1] as usize;
-                let address = bytecode[2] as usize;
-                let mut memory = Cursor::new(&mut self.memory[address..]);
-                let value = memory.read_u16::<LittleEndian>().unwrap();
+            // Load a byte of data from memory and sign extend it into a register
+            LoadByteSigned |
+            LoadByteSignedIndirect => {
+                let register = self.register(bytecode[1])?;
+                let address = self.address(&instruction, bytecode[2])?;
+                let value = self.load(address, 1)?[0] as i8;
+                self.registers[register] = value as i32;
+            },
+
+            // Load a half word of data from memory and place it into a register
+            // The half word is treated as unsigned
+            LoadHalf |
+            LoadHalfIndirect => {
+                let register = self.register(bytecode[1])?;
+                let address = self.address(&instruction, bytecode[2])?;
+                let value = self.load(address, 2)?.read_u16::<LittleEndian>().unwrap();
+                self.registers[register] = value as i32;
+            },
+
+            // Load a half word of data from memory and sign extend it into a register
+            LoadHalfSigned |
+            LoadHalfSignedIndirect => {
+                let register = self.register(bytecode[1])?;
+                let address = self.address(&instruction, bytecode[2])?;
+                let value = self.load(address, 2)?.read_i16::<LittleEndian>().unwrap();
                 self.registers[register] = value as i32;
             },
 
+            // Load a word of data from memory and place it into a register
+            LoadWord |
+            LoadWordIndirect => {
+                let register = self.register(bytecode[1])?;
+                let address = self.address(&instruction, bytecode[2])?;
+                let value = self.load(address, 4)?.read_i32::<LittleEndian>().unwrap();
+                self.registers[register] = value;
+            },
+
             // Copy a value from register B and place it in register A
             Move => {
-                let reg_a = bytecode[1] as usize;
-                let reg_b = bytecode[2] as usize;
+                let reg_a = self.register(bytecode[1])?;
+                let reg_b = self.register(bytecode[2])?;
                 let val_b = self.registers[reg_b];
                 self.registers[reg_a] = val_b;
             },
 
             // Multiply the values in two registers together and store it in the first
             Multiply => {
-                let reg_a = bytecode[1] as usize;
-                let reg_b = bytecode[2] as usize;
+                let reg_a = self.register(bytecode[1])?;
+                let reg_b = self.register(bytecode[2])?;
                 let val_a = self.registers[reg_a];
                 let val_b = self.registers[reg_b];
-                self.registers[reg_a] = val_a * val_b;
+                self.registers[reg_a] = self.arithmetic(val_a.checked_mul(val_b))?;
             },
 
             // Jumps to an address if the given register contains a non-zero value
             NonZeroJump => {
-                let register = bytecode[1] as usize;
+                let register = self.register(bytecode[1])?;
                 let address = bytecode[2];
                 // Remove offset that will be automatically applied
-                let address = address - 12;
+                let address = address.wrapping_sub(INSTRUCTION_SIZE);
                 if self.registers[register] != 0 {
                     self.registers[Register::PC as usize] = address;
                 }
@@ -272,8 +900,8 @@ impl VM {
             // If one of the registers contains a non-zero value, store 1
             // Otherwise, store 0 in the first register
             Or => {
-                let reg1 = bytecode[1] as usize;
-                let reg2 = bytecode[2] as usize;
+                let reg1 = self.register(bytecode[1])?;
+                let reg2 = self.register(bytecode[2])?;
                 let reg1_value = self.registers[reg1];
                 let reg2_value = self.registers[reg2];
                 self.registers[reg1] = if reg1_value != 0 || reg2_value != 0 {
@@ -283,47 +911,416 @@ impl VM {
                 };
             },
 
-            // Print out an ASCII character to stdout
+            // Remove the value from the top of the stack and store it in a register
+            Pop => {
+                let register = self.register(bytecode[1])?;
+                self.registers[register] = self.pop()?;
+            },
+
+            // Print out an ASCII character
             OutputASCII => {
-                print!("{}", (self.registers[Register::IO as usize] as u8) as char);
+                let character = (self.registers[Register::IO as usize] as u8) as char;
+                if let Err(err) = self.device.write(&character.to_string()) {
+                    return Err(self.device_error(err));
+                }
             },
 
-            // Print out a signed integer to stdout
+            // Print out a signed integer
             OutputInteger => {
-                print!("{}", self.registers[Register::IO as usize]);
+                let value = self.registers[Register::IO as usize];
+                if let Err(err) = self.device.write(&value.to_string()) {
+                    return Err(self.device_error(err));
+                }
+            },
+
+            // Push the value in a register onto the stack
+            Push => {
+                let register = self.register(bytecode[1])?;
+                let value = self.registers[register];
+                self.push(value)?;
             },
 
-            // Stores a byte of data at a location
-            StoreByte => {
-                let register = bytecode[1] as usize;
-                let address = bytecode[2] as usize;
+            // Return to the address on the top of the stack
+            Return => {
+                let address = self.pop()?;
+
+                // Remove offset that will be automatically applied
+                let address = address.wrapping_sub(INSTRUCTION_SIZE);
+                self.registers[Register::PC as usize] = address;
+            },
+
+            // Stores the low byte of a register at a location
+            StoreByte |
+            StoreByteIndirect => {
+                let register = self.register(bytecode[1])?;
+                let address = self.address(&instruction, bytecode[2])?;
                 let value = self.registers[register] as u8;
-                let mut memory = &mut self.memory[address..];
-                let _ = memory.write_u8(value);
+                self.store(address, 1)?[0] = value;
             },
 
-            // Stores a word of data at a location
-            StoreWord => {
-                let register = bytecode[1] as usize;
-                let address = bytecode[2] as usize;
+            // Stores the low half word of a register at a location
+            StoreHalf |
+            StoreHalfIndirect => {
+                let register = self.register(bytecode[1])?;
+                let address = self.address(&instruction, bytecode[2])?;
                 let value = self.registers[register] as u16;
-                let mut memory = &mut self.memory[address..];
-                let _ = memory.write_u16::<LittleEndian>(value);
+                let mut memory = self.store(address, 2)?;
+                memory.write_u16::<LittleEndian>(value).unwrap();
+            },
+
+            // Stores a word of data at a location
+            StoreWord |
+            StoreWordIndirect => {
+                let register = self.register(bytecode[1])?;
+                let address = self.address(&instruction, bytecode[2])?;
+                let value = self.registers[register];
+                let mut memory = self.store(address, 4)?;
+                memory.write_i32::<LittleEndian>(value).unwrap();
             },
 
             // Subtracts the value in register A from register B
             // and stores it in register A
             Subtract => {
-                let reg_a = bytecode[1] as usize;
-                let reg_b = bytecode[2] as usize;
+                let reg_a = self.register(bytecode[1])?;
+                let reg_b = self.register(bytecode[2])?;
                 let val_a = self.registers[reg_a];
                 let val_b = self.registers[reg_b];
-                self.registers[reg_a] = val_a - val_b;
+                self.registers[reg_a] = self.arithmetic(val_a.checked_sub(val_b))?;
             },
 
             // End the program
-            End => return false
+            End => return Ok(Some(ExitStatus::Halted))
         };
-        true
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use device::Streams;
+    use assembler::Origin;
+    use memory::MemoryLayout;
+    use test_support;
+    use super::*;
+
+    type TestVM = VM<Streams<Cursor<&'static str>, Vec<u8>>>;
+
+    /// A device whose streams have been closed
+    struct Closed;
+
+    impl Device for Closed {
+        fn read_line(&mut self, _line: &mut String) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"))
+        }
+
+        fn write(&mut self, _text: &str) -> io::Result<()> {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"))
+        }
+    }
+
+    fn load_with_input(path: &str, input: &'static str) -> TestVM {
+        let image = test_support::assemble(path).image;
+        VM::from_image(&image, &VmConfig::default(), Streams::new(Cursor::new(input), vec![])).unwrap()
+    }
+
+    fn load(path: &str) -> TestVM {
+        load_with_input(path, "")
+    }
+
+    /// Runs `source` from its entry point in a VM with `memory_size` bytes of memory
+    fn run_source(source: &str, memory_size: usize) -> (TestVM, Result<ExitStatus, VmTrap>) {
+        let image = ::assemble("test.asm", source, Origin::default()).unwrap().image;
+        let device = Streams::new(Cursor::new(""), vec![]);
+        let mut vm = VM::from_image(&image, &VmConfig::new(memory_size), device).unwrap();
+        let start = vm.pc() as usize;
+        let result = vm.run(start);
+        (vm, result)
+    }
+
+    /// The trap raised by running `source`
+    fn trap(source: &str) -> VmTrap {
+        run_source(source, MAX_MEMORY).1.unwrap_err()
+    }
+
+    /// Runs hand-written bytecode, for instructions the assembler would refuse to write
+    fn run_bytecode(words: &[i32]) -> Result<ExitStatus, VmTrap> {
+        let mut code = vec![];
+        for word in words {
+            code.write_i32::<LittleEndian>(*word).unwrap();
+        }
+        let mut vm = VM::with_device(code, MAX_MEMORY, Streams::new(Cursor::new(""), vec![])).unwrap();
+        vm.run(0)
+    }
+
+    #[test]
+    fn step_executes_one_instruction() {
+        let mut vm = load("test_files/27-call-stack.asm");
+        assert_eq!(vm.step(), StepResult::Continue);
+        assert_eq!(vm.get_register(Register::Reg1), 21);
+        assert_eq!(vm.pc(), INSTRUCTION_SIZE);
+    }
+
+    #[test]
+    fn run_for_stops_after_the_count() {
+        let mut vm = load("test_files/27-call-stack.asm");
+        assert_eq!(vm.run_for(2), StepResult::Continue);
+        assert_eq!(vm.get_register(Register::SP), MAX_MEMORY as i32 - 4);
+        assert_eq!(vm.read_memory(MAX_MEMORY as i32 - 4, 4), Some(&[21, 0, 0, 0][..]));
+        assert_eq!(vm.run_for(100), StepResult::Exited(ExitStatus::Halted));
+        assert_eq!(vm.get_register(Register::IO), 42);
+    }
+
+    #[test]
+    fn run_until_stops_when_the_predicate_holds() {
+        let mut vm = load("test_files/27-call-stack.asm");
+        assert_eq!(vm.run_until(|vm| vm.get_register(Register::Reg4) != 0), StepResult::Continue);
+        assert_eq!(vm.get_register(Register::Reg4), 21);
+        assert_eq!(vm.run_until(|_| false), StepResult::Exited(ExitStatus::Halted));
+    }
+
+    #[test]
+    fn traps_are_reported_with_the_pc() {
+        let mut vm = load("test_files/27-call-stack.asm");
+        vm.set_pc(-12);
+        assert_eq!(vm.step(), StepResult::Trapped(VmTrap { kind: TrapKind::FetchOutOfBounds(-12), pc: -12 }));
+        assert_eq!(vm.read_memory(MAX_MEMORY as i32 - 2, 4), None);
+    }
+
+    #[test]
+    fn arithmetic_faults_trap() {
+        assert_eq!(trap("+ io 1\n/ io reg_1\nEND\n"), VmTrap { kind: TrapKind::DivideByZero, pc: 12 });
+        assert_eq!(
+            trap("+ reg_1 2147483647\n+ reg_1 1\nEND\n"),
+            VmTrap { kind: TrapKind::ArithmeticOverflow, pc: 12 }
+        );
+        assert_eq!(
+            trap("+ reg_1 -2147483648\n+ reg_2 -1\n/ reg_1 reg_2\nEND\n"),
+            VmTrap { kind: TrapKind::ArithmeticOverflow, pc: 24 }
+        );
+        assert_eq!(
+            trap("+ reg_1 65536\nMOV reg_2 reg_1\n* reg_1 reg_2\nEND\n"),
+            VmTrap { kind: TrapKind::ArithmeticOverflow, pc: 24 }
+        );
+    }
+
+    #[test]
+    fn memory_accesses_outside_memory_trap() {
+        assert_eq!(
+            trap("+ io 1\nLDW io 9999998\nEND\n"),
+            VmTrap { kind: TrapKind::LoadOutOfBounds(9999998), pc: 12 }
+        );
+        assert_eq!(
+            trap("+ io 1\nSTH io 9999999\nEND\n"),
+            VmTrap { kind: TrapKind::StoreOutOfBounds(9999999), pc: 12 }
+        );
+    }
+
+    #[test]
+    fn indirect_addresses_outside_memory_trap() {
+        assert_eq!(
+            trap("+ reg_1 -4\nLDW io [reg_1]\nEND\n"),
+            VmTrap { kind: TrapKind::LoadOutOfBounds(-4), pc: 12 }
+        );
+        assert_eq!(
+            trap("MOV reg_1 sp\nLDB io [reg_1]\nEND\n"),
+            VmTrap { kind: TrapKind::LoadOutOfBounds(MAX_MEMORY as i32), pc: 12 }
+        );
+        assert_eq!(
+            trap("MOV reg_1 sp\nSTW io [reg_1-2]\nEND\n"),
+            VmTrap { kind: TrapKind::StoreOutOfBounds(MAX_MEMORY as i32 - 2), pc: 12 }
+        );
+
+        // The offset wraps rather than overflowing when it is added to the base
+        assert_eq!(
+            trap("+ reg_1 2147483647\nSTB io [reg_1+1]\nEND\n"),
+            VmTrap { kind: TrapKind::StoreOutOfBounds(i32::MIN), pc: 12 }
+        );
+    }
+
+    #[test]
+    fn signed_loads_sign_extend() {
+        let source = "
+            .data
+            Bytes .byte 128
+                  .byte 127
+            Halves .half 32768
+                   .half 32767
+            .text
+            LDBS reg_1 Bytes
+            LDBS reg_2 Bytes+1
+            LDB reg_3 Bytes
+            LDHS reg_4 Halves
+            LDHS reg_5 Halves+2
+            LDH reg_6 Halves
+            LDA io Halves
+            LDHS io [io]
+            END
+        ";
+        let (vm, result) = run_source(source, MAX_MEMORY);
+        assert_eq!(result, Ok(ExitStatus::Halted));
+        assert_eq!(vm.get_register(Register::Reg1), -128);
+        assert_eq!(vm.get_register(Register::Reg2), 127);
+        assert_eq!(vm.get_register(Register::Reg3), 128);
+        assert_eq!(vm.get_register(Register::Reg4), -32768);
+        assert_eq!(vm.get_register(Register::Reg5), 32767);
+        assert_eq!(vm.get_register(Register::Reg6), 32768);
+        assert_eq!(vm.get_register(Register::IO), -32768);
+    }
+
+    #[test]
+    fn bad_bytecode_traps() {
+        let add = InstructionType::AddImmediate.to_bytecode();
+        let mov = InstructionType::Move.to_bytecode();
+        assert_eq!(
+            run_bytecode(&[add, 1, 5, 99, 0, 0]),
+            Err(VmTrap { kind: TrapKind::IllegalOpcode(99), pc: 12 })
+        );
+        assert_eq!(
+            run_bytecode(&[add, 1, 5, mov, 1, 13]),
+            Err(VmTrap { kind: TrapKind::BadRegister(13), pc: 12 })
+        );
+        assert_eq!(
+            run_bytecode(&[mov, -1, 1]),
+            Err(VmTrap { kind: TrapKind::BadRegister(-1), pc: 0 })
+        );
+    }
+
+    #[test]
+    fn pushing_past_the_stack_limit_traps() {
+        // Four instructions leave room for two slots on the stack
+        let (vm, result) = run_source("PUSH reg_1\nPUSH reg_1\nPUSH reg_1\nEND\n", 56);
+        assert_eq!(result, Err(VmTrap { kind: TrapKind::StackOverflow, pc: 24 }));
+        assert_eq!(vm.get_register(Register::SL), 48);
+        assert_eq!(vm.get_register(Register::SP), 48);
+
+        let (vm, result) = run_source("+ reg_1 1\nENTER 8\nEND\n", 44);
+        assert_eq!(result, Err(VmTrap { kind: TrapKind::StackOverflow, pc: 12 }));
+        assert_eq!(vm.get_register(Register::SP), 44);
+        assert_eq!(vm.get_register(Register::FP), 44);
+
+        let (vm, result) = run_source("ENTER -4\nEND\n", MAX_MEMORY);
+        assert_eq!(result, Err(VmTrap { kind: TrapKind::StackOverflow, pc: 0 }));
+        assert_eq!(vm.get_register(Register::SP), MAX_MEMORY as i32);
+    }
+
+    #[test]
+    fn taking_from_an_empty_stack_traps() {
+        let top = MAX_MEMORY as i32;
+        for source in &["+ io 1\nPOP reg_1\nEND\n", "+ io 1\nRET\n", "+ io 1\nLEAVE\nEND\n"] {
+            let (vm, result) = run_source(source, MAX_MEMORY);
+            assert_eq!(result, Err(VmTrap { kind: TrapKind::StackUnderflow, pc: 12 }), "{}", source);
+            assert_eq!(vm.get_register(Register::SP), top, "{}", source);
+        }
+
+        // Leaving a frame which was never entered keeps what was pushed
+        let (vm, result) = run_source("PUSH reg_1\nLEAVE\nEND\n", MAX_MEMORY);
+        assert_eq!(result, Err(VmTrap { kind: TrapKind::StackUnderflow, pc: 12 }));
+        assert_eq!(vm.get_register(Register::SP), top - 4);
+    }
+
+    #[test]
+    fn output_goes_to_the_device() {
+        let mut vm = load("test_files/32-strings.asm");
+        let start = vm.pc() as usize;
+        assert_eq!(vm.run(start), Ok(ExitStatus::Halted));
+        assert_eq!(vm.device().output(), b"Hello, #1 world99");
+    }
+
+    #[test]
+    fn input_comes_from_the_device() {
+        let mut vm = load_with_input("test_files/12-integer-input.asm", "-17\n");
+        assert_eq!(vm.run(0), Ok(ExitStatus::Halted));
+        assert_eq!(vm.device().output(), b"-12");
+
+        let mut vm = load_with_input("test_files/11-ascii-input.asm", "A\n");
+        assert_eq!(vm.run(0), Ok(ExitStatus::Halted));
+        assert_eq!(vm.device().output(), b"65");
+
+        // The end of input reads as -1
+        let mut vm = load("test_files/11-ascii-input.asm");
+        assert_eq!(vm.run(0), Ok(ExitStatus::Halted));
+        assert_eq!(vm.device().output(), b"-1");
+    }
+
+    #[test]
+    fn device_errors_trap() {
+        let closed = VmTrap { kind: TrapKind::DeviceError("closed".to_string()), pc: 12 };
+        let image = test_support::assemble("test_files/03-add-immediate-and-output.asm").image;
+        let mut vm = VM::from_image(&image, &VmConfig::default(), Closed).unwrap();
+        assert_eq!(vm.run(0), Err(closed.clone()));
+
+        // The instruction which failed is not finished
+        let image = test_support::assemble("test_files/11-ascii-input.asm").image;
+        let mut vm = VM::from_image(&image, &VmConfig::default(), Closed).unwrap();
+        assert_eq!(vm.step(), StepResult::Trapped(VmTrap { pc: 0, ..closed }));
+        assert_eq!(vm.pc(), 0);
+        assert_eq!(vm.get_register(Register::IO), 0);
+    }
+
+    #[test]
+    fn layout_sets_the_stack_registers() {
+        let layout = MemoryLayout {
+            code_base: 0x1000,
+            stack_top: Some(0x8000),
+            stack_size: 0x1000,
+            ..MemoryLayout::default()
+        };
+        let image = test_support::assemble_at("test_files/27-call-stack.asm", layout.origin()).image;
+        let config = VmConfig { memory_size: 0x10000, layout };
+        let mut vm = VM::from_image(&image, &config, Streams::new(Cursor::new(""), vec![])).unwrap();
+        assert_eq!(vm.pc(), 0x1000);
+        assert_eq!(vm.get_register(Register::SB), 0x8000);
+        assert_eq!(vm.get_register(Register::SP), 0x8000);
+        assert_eq!(vm.get_register(Register::SL), 0x7000);
+        assert_eq!(vm.run(0x1000), Ok(ExitStatus::Halted));
+        assert_eq!(vm.device().output(), b"42");
+    }
+
+    #[test]
+    fn rejects_code_which_does_not_fit() {
+        let device = Streams::new(Cursor::new(""), vec![]);
+        let result = VM::with_device(vec![0; 100], 50, device);
+        assert_eq!(result.err(), Some(LoadError::DoesNotFit { region: "text section", end: 100, memory_size: 50 }));
+    }
+
+    #[test]
+    fn empty_sections_may_be_outside_memory() {
+        let origin = Origin { text: 20_000_000, data: Some(0) };
+        let image = ::assemble("empty.asm", ".equ _X 1\n.data\nY .word 1\n.entry Y\n", origin).unwrap().image;
+        assert_eq!(image.sections[0].address, 20_000_000);
+        let vm = VM::from_image(&image, &VmConfig::default(), Streams::new(Cursor::new(""), vec![])).unwrap();
+        assert_eq!(vm.read_memory(0, 4), Some(&[1, 0, 0, 0][..]));
+    }
+
+    #[test]
+    fn stops_when_the_budget_is_exhausted() {
+        let mut costs = CostTable::new();
+        assert!(costs.set_cost_by_name("PUSH", 10));
+        assert!(!costs.set_cost_by_name("NOPE", 10));
+
+        // Adding to reg_1 costs 1, then pushing it would cost 10 more
+        let mut vm = load("test_files/27-call-stack.asm");
+        vm.set_budget(Some(10), costs.clone());
+        assert_eq!(vm.run(0), Ok(ExitStatus::BudgetExhausted));
+        assert_eq!(vm.pc(), INSTRUCTION_SIZE);
+        assert_eq!(vm.instructions_executed(), 1);
+        assert_eq!(vm.budget_spent(), 1);
+
+        let mut vm = load("test_files/27-call-stack.asm");
+        vm.set_budget(Some(1000), costs);
+        assert_eq!(vm.run(0), Ok(ExitStatus::Halted));
+        assert_eq!(vm.instructions_executed(), 15);
+        assert_eq!(vm.budget_spent(), 11 + 4 * 10);
+    }
+
+    #[test]
+    fn every_opcode_has_a_cost() {
+        let costs = CostTable::new();
+        for opcode in 0..100 {
+            if let Some(instruction) = InstructionType::from_bytecode(opcode) {
+                assert_eq!(costs.cost(&instruction), 1);
+            }
+        }
     }
 }
@@ -1,3 +1,4 @@
 + io 65
 OUT
 ASCO
+END
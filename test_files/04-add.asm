@@ -4,3 +4,4 @@
 + io reg_1
 + io io
 OUT
+END
@@ -4,3 +4,4 @@ OUT # 1
 + io -1
 && io io
 OUT # 0
+END
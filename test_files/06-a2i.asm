@@ -4,3 +4,4 @@ OUT
 + io 100 # Invalid
 A2I
 OUT # -1
+END
@@ -1,3 +1,4 @@
 + io 5
 I2A
 ASCO
+END
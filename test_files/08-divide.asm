@@ -6,3 +6,4 @@ OUT # 2
 + reg_1 -2 # 3
 / io reg_1
 OUT # 7 / 3 = 2
+END
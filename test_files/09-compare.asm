@@ -7,3 +7,4 @@ OUT # -1
 + io 10
 == io reg_1
 OUT # 1
+END
@@ -2,5 +2,5 @@
 >0 reg_1 Skip
 + io reg_1
 OUT # Would output 5 if hit
-Skip OUT # Will output 1 since the previous was skipped
+Skip OUT # Will output 0 since the previous was skipped
 END
@@ -4,3 +4,4 @@ JMP Skip
 + io 5
 Skip + io 5
 OUT
+END
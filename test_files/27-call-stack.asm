@@ -0,0 +1,17 @@
++ reg_1 21
+PUSH reg_1
+CALL Double
+POP io
+OUT # 42
+END
+
+# Doubles the value passed on the stack, leaving the result in its place
+Double ENTER 4
+PUSH reg_1 # Save a register so the caller's value is untouched
+LEAVE
+POP reg_3 # Return address
+POP reg_4 # Argument
++ reg_4 reg_4
+PUSH reg_4
+PUSH reg_3
+RET
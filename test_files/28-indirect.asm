@@ -0,0 +1,24 @@
+# Build a string below the stack and walk it with a pointer
+MOV reg_1 sp
++ reg_1 -16
++ reg_2 72 # 'H'
+STB reg_2 [reg_1]
++ reg_2 33 # 'i'
+STB reg_2 [reg_1+1]
++ reg_2 -72 # '!'
+STB reg_2 [reg_1+2]
+STB reg_0 [reg_1+3]
+
+Loop LDB io [reg_1]
+=0 io Done
+ASCO # Hi!
++ reg_1 1
+JMP Loop
+
+Done LDB io [reg_1-3]
+ASCO # H
++ reg_2 4000
+STW reg_2 [reg_1+4]
+LDW io [reg_1+4]
+OUT # 4033
+END
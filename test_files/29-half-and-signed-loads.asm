@@ -0,0 +1,20 @@
+Negative .word -1
+LDW io Negative
+OUT # -1
+LDH io Negative
+OUT # 65535
+LDHS io Negative
+OUT # -1
+LDB io Negative
+OUT # 255
+LDBS io Negative
+OUT # -1
+
++ io 70001 # io is -1 from the previous load
+STW io Negative
+LDW io Negative
+OUT # 70000
+STH reg_0 Negative
+LDW io Negative
+OUT # 65536
+END
@@ -0,0 +1,15 @@
+Greeting .byte 'H'
+Count    .half 3
+Total    .word -5
+Start    LDB io Greeting
+ASCO # H
+LDH io Count
+OUT # 3
+JMP Next
+Gap  .byte 'x'
+Next LDW io Total
+OUT # -5
+LDBS io Tail
+OUT # -2
+END
+Tail .byte 254
@@ -0,0 +1,18 @@
+.entry Main
+
+.data
+Answer .word 42
+Letter .byte 'Z'
+
+.text
+Helper LDB io Letter
+ASCO # Z
+RET
+
+Main LDW io Answer
+OUT # 42
+CALL Helper
+END
+
+.data
+Trailer .half 7
@@ -0,0 +1,17 @@
+.data
+Greeting .ascii "Hello, #1 "
+Name     .asciz "world"
+Buffer   .space 3
+         .align 4
+Number   .word 99
+
+.text
+LDA reg_1 Greeting
+Loop LDB io [reg_1]
+=0 io Done
+ASCO # Hello, #1 world
++ reg_1 1
+JMP Loop
+Done LDW io Number
+OUT # 99
+END
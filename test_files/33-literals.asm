@@ -0,0 +1,22 @@
+Space   .byte ' '
+Newline .byte '\n'
+Hex     .word 0x7F
+Mask    .word 0b1111_0000
+Big     .word 1_000_000
+
+.entry Main
+Main LDW io Hex
+OUT # 127
+LDB io Space
+ASCO # ' '
+LDW io Mask
+OUT # 240
+LDB io Space
+ASCO # ' '
+LDW io Big
+OUT # 1000000
+LDB io Newline
+ASCO # '\n'
++ io 0o10
+OUT # 18
+END
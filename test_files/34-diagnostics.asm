@@ -0,0 +1,8 @@
+# Every line below has a problem the assembler should report
+Start MOV 5 reg_1
+	+ reg_1
+Loop JMP Nowhere
+Loop END
+.byte 300
+JMP Strat
+Lonely
@@ -0,0 +1,3 @@
+# Never ends, so the test runner's budget stops it
++ reg_1 1
+Loop JMP Loop
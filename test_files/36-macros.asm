@@ -0,0 +1,25 @@
+# Prints the character with the ASCII code `code`, leaving io cleared afterwards
+.macro PrintChar code
++ io code
+ASCO
+MOV io reg_0
+.endm
+
+.macro Newline
+PrintChar 10
+.endm
+
+# Prints `count` stars, each expansion getting its own copy of the `Loop` label
+.macro Stars count
+MOV reg_1 reg_0
++ reg_1 count
+Loop PrintChar 42
++ reg_1 -1
+!0 reg_1 Loop
+.endm
+
+Stars 3
+Newline
+Stars 5
+Newline
+END
@@ -0,0 +1,11 @@
+# Shared routines are pulled in from other files, with paths relative to this one
++ reg_1 7
+CALL PrintLine
++ reg_1 35
+CALL PrintLine
+END
+
+.include "lib/print.asm"
+
+# Already included by print.asm, so this is skipped
+.include "lib/macros.asm"
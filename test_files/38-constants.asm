@@ -0,0 +1,42 @@
+# Constants may be used before they are defined, and may refer to each other
+.equ COUNT 3
+.equ SIZE COUNT*4
+.set NEWLINE 10
+
+.data
+Table  .word 7
+       .word 11
+       .word 13
+Length .word .-Table
+Buffer .space SIZE+(1<<2)+1
+       .align SIZE/3
+Last   .byte NEWLINE*6+7
+
+.text
+# Prints `value` plus one, then a newline
+.macro PrintNext value
+MOV io reg_0
++ io value+1
+OUT
+MOV io reg_0
++ io NEWLINE
+ASCO
+.endm
+
+LDW io Table+8
+OUT # 13
+LDW io Length
+OUT # 12
+LDA reg_1 Table
+LDW io [reg_1+SIZE/COUNT]
+OUT # 11
+LDA reg_1 Last
+LDA reg_2 Buffer
+- reg_1 reg_2
+MOV io reg_1
+OUT # 20, as Buffer is padded to a multiple of 4
+LDB io Last
+ASCO # C
+PrintNext (SIZE - 2)*COUNT # 31
+PrintNext ~0xF0&0xFF>>2 # 16
+END
@@ -0,0 +1,4 @@
+# Includes a file which includes this one, and a file which does not exist
+.include "cycle-b.asm"
+.include "missing.asm"
+END
@@ -0,0 +1,2 @@
+# Includes the file which included it
+.include "cycle-a.asm"
@@ -0,0 +1,7 @@
+# Macros shared between programs
+
+.macro Newline
+MOV io reg_0
++ io 10
+ASCO
+.endm
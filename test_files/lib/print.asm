@@ -0,0 +1,8 @@
+# Routines for printing, which need the shared macros
+.include "macros.asm"
+
+# Prints reg_1 followed by a newline
+PrintLine MOV io reg_1
+OUT
+Newline
+RET
@@ -0,0 +1,138 @@
+//! Runs every program in `test_files` and compares what it prints with the files stored next to it
+//!
+//! For a program `NAME.asm`:
+//!
+//! - `NAME.out` holds the expected stdout
+//! - `NAME.in` holds the stdin to feed the program, if it reads any
+//! - `NAME.err` holds the expected stderr, which is not checked when the file is missing
+//! - `NAME.code` holds the expected exit code, which is 0 when the file is missing
+//!
+//! Every program runs with a budget of `BUDGET` instructions so one which never ends fails instead of hanging.
+//!
+//! Run with `BLESS=1` to replace the expected output with what the programs print now.
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Most instructions a program may execute
+const BUDGET: &str = "1_000_000";
+
+struct Output {
+    stdout: String,
+    stderr: String,
+    code: i32
+}
+
+fn run(program: &Path, stdin: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_vm"))
+        .arg("run")
+        .arg(program)
+        .args(["--budget", BUDGET])
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    let output = child.wait_with_output().unwrap();
+    Output {
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        code: output.status.code().unwrap_or(-1)
+    }
+}
+
+fn read(path: &Path) -> Option<String> {
+    fs::read_to_string(path).ok()
+}
+
+/// Shows the lines which differ, marking expected lines with `-` and actual lines with `+`
+fn diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.split('\n').collect();
+    let actual: Vec<&str> = actual.split('\n').collect();
+
+    // Length of the longest common subsequence of the remaining lines
+    let mut common = vec![vec![0; actual.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            common[i][j] = if expected[i] == actual[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut output = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+            output.push_str(&format!("  {:?}\n", expected[i]));
+            i += 1;
+            j += 1;
+        } else if j == actual.len() || (i < expected.len() && common[i + 1][j] >= common[i][j + 1]) {
+            output.push_str(&format!("- {:?}\n", expected[i]));
+            i += 1;
+        } else {
+            output.push_str(&format!("+ {:?}\n", actual[j]));
+            j += 1;
+        }
+    }
+    output
+}
+
+fn programs() -> Vec<PathBuf> {
+    let mut programs: Vec<PathBuf> = fs::read_dir("test_files").unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "asm"))
+        .collect();
+    programs.sort();
+    programs
+}
+
+#[test]
+fn programs_match_their_expected_output() {
+    let bless = env::var_os("BLESS").is_some();
+    let mut failures = vec![];
+    for program in programs() {
+        let stdin = fs::read(program.with_extension("in")).unwrap_or_default();
+        let output = run(&program, &stdin);
+
+        let out_path = program.with_extension("out");
+        let err_path = program.with_extension("err");
+        if bless {
+            fs::write(&out_path, &output.stdout).unwrap();
+            if err_path.exists() {
+                fs::write(&err_path, &output.stderr).unwrap();
+            }
+            continue;
+        }
+
+        let mut problems = vec![];
+        match read(&out_path) {
+            Some(expected) if expected == output.stdout => {},
+            Some(expected) => problems.push(format!("stdout differs:\n{}", diff(&expected, &output.stdout))),
+            None => problems.push(format!("{} is missing, run with BLESS=1 to create it", out_path.display()))
+        };
+        if let Some(expected) = read(&err_path) {
+            if expected != output.stderr {
+                problems.push(format!("stderr differs:\n{}", diff(&expected, &output.stderr)));
+            }
+        }
+        let code = read(&program.with_extension("code"))
+            .map(|code| code.trim().parse::<i32>().unwrap())
+            .unwrap_or(0);
+        if code != output.code {
+            problems.push(format!("expected exit code {} but got {}\nstderr:\n{}", code, output.code, output.stderr));
+        }
+
+        if !problems.is_empty() {
+            failures.push(format!("{}:\n{}", program.display(), problems.join("\n")));
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}